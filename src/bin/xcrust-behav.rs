@@ -0,0 +1,92 @@
+use std::env;
+use std::path::{Path, PathBuf};
+use std::process;
+
+use clap::{crate_version, App, Arg, value_t};
+
+use xcrust::pos::{behav, Coordinates};
+use xcrust::pos::calibrate::{calibrate, Calibration};
+use xcrust::pos::mwl_ad::{pxyabw_header, PosReader, ProcessedPosWriter};
+use xcrust::pos::smooth::SmoothConfig;
+
+fn main() {
+    let matches = App::new("xcrust-behav")
+        .version(crate_version!())
+        .about("Work out position, head direction, speed and diode width \
+                from a .p file, writing a pxyabw file as behav does")
+        .arg(Arg::from_usage("<input-file> 'Diode position file (.p)'"))
+        .arg(Arg::from_usage("-o --output [output] \
+                              'Output file (default: <input-file stem>.pxyabw)'"))
+        .arg(Arg::from_usage("-k --calibration [calibration] \
+                              'Calibration file mapping pixels to arena centimeters \
+                              (default: leave positions in pixels)'"))
+        .arg(Arg::from_usage("-s --smooth \
+                              'Take position, head direction and speed from a Kalman \
+                              smoother rather than from single samples'"))
+        .arg(Arg::from_usage("--position-noise [position-noise] \
+                              'Standard deviation of the error in a measured position, \
+                              in pixels or, with --calibration, arena units (default: 1)'")
+             .requires("smooth"))
+        .arg(Arg::from_usage("--acceleration-noise [acceleration-noise] \
+                              'How freely the velocity changes, in units² per s³ of the \
+                              track, as for --position-noise (default: 1000)'")
+             .requires("smooth"))
+        .get_matches();
+
+    let input = value_t!(matches, "input-file", PathBuf).unwrap_or_else(|e| e.exit());
+    let output = matches
+        .value_of("output")
+        .map(PathBuf::from)
+        .unwrap_or_else(|| input.with_extension("pxyabw"));
+    if output == input {
+        eprintln!("xcrust-behav: refusing to overwrite the input file");
+        process::exit(1);
+    }
+
+    // The defaults are in pixels, so a calibrated track needs its own
+    let mut smooth_config = SmoothConfig::default();
+    if matches.is_present("position-noise") {
+        smooth_config.position_noise =
+            value_t!(matches, "position-noise", f32).unwrap_or_else(|e| e.exit());
+    }
+    if matches.is_present("acceleration-noise") {
+        smooth_config.acceleration_noise =
+            value_t!(matches, "acceleration-noise", f32).unwrap_or_else(|e| e.exit());
+    }
+
+    let fail = |e: xcrust::Error| -> ! {
+        eprintln!("xcrust-behav: {}", e);
+        process::exit(1)
+    };
+    let reader = PosReader::open(&input).unwrap_or_else(|e| fail(e));
+    let args: Vec<String> = env::args().collect();
+    let header = pxyabw_header(&args, &input.to_string_lossy(), &reader.header().as_metadata());
+    let mut coordinates = Coordinates::Pixels;
+    let mut positions = reader
+        .ticks()
+        .collect::<Result<Vec<_>, xcrust::Error>>()
+        .unwrap_or_else(|e| fail(e));
+    if let Some(path) = matches.value_of("calibration") {
+        let homography = Calibration::load(Path::new(path))
+            .and_then(|c| c.homography().map_err(|e| e.at_path(Path::new(path))))
+            .unwrap_or_else(|e| fail(e));
+        positions = calibrate(&positions, &homography);
+        coordinates = Coordinates::Arena;
+    }
+
+    let mut writer = ProcessedPosWriter::create(&output, &header).unwrap_or_else(|e| fail(e));
+    let processed = if matches.is_present("smooth") {
+        behav::process_smoothed(&positions, &SmoothConfig { coordinates, ..smooth_config })
+            .unwrap_or_else(|e| fail(e))
+    } else {
+        behav::process(&positions, coordinates)
+    };
+    for p in processed.iter() {
+        writer
+            .write_pos(p)
+            .map_err(|e| e.at_path(&output))
+            .unwrap_or_else(|e| fail(e));
+    }
+    writer.into_inner().map_err(|e| e.at_path(&output)).unwrap_or_else(|e| fail(e));
+    eprintln!("wrote {}", output.display());
+}
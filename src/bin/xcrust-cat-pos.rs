@@ -1,12 +1,37 @@
-use std::env;
-use std::path::{Path};
+use std::path::{PathBuf};
+use std::process;
 
-use xcrust::pos::mwl_ad::{read_p};
+use chrono::Duration;
+use clap::{crate_version, App, Arg, value_t};
+
+use xcrust::mwl_ad::duration_to_timestamp;
+use xcrust::pos::mwl_ad::{PosReader};
 
 fn main() {
-    let args : Vec<String> = env::args().collect();
-    let file = &args[1];
-    let ps = read_p( Path::new(&file) );
-    println!("ps[0]: {:?}", ps[0]);
-    println!("ps[0]: {:?}", ps[0]);
+    let matches = App::new("xcrust-cat-pos")
+        .version(crate_version!())
+        .arg(Arg::from_usage("<input-file> 'File to read positions from'"))
+        .arg(Arg::from_usage("-a --after [after] 'Lower bound on positions to cat'"))
+        .arg(Arg::from_usage("-b --before [before] 'Upper bound on positions to cat'"))
+        .get_matches();
+
+    let file = value_t!(matches, "input-file", PathBuf).unwrap_or_else(|e| e.exit());
+    let bound = |name| match value_t!(matches, name, f64) {
+        Ok(t) => Some(duration_to_timestamp(
+            &Duration::nanoseconds((t * 1_000_000_000.0) as i64))),
+        Err(clap::Error { kind: clap::ErrorKind::ArgumentNotFound, .. }) => None,
+        Err(e) => e.exit(),
+    };
+    let (after, before) = (bound("after"), bound("before"));
+
+    let fail = |e: xcrust::Error| -> ! {
+        eprintln!("xcrust-cat-pos: {}", e);
+        process::exit(1)
+    };
+    let reader = PosReader::open(&file)
+        .and_then(|r| r.time_range(after, before))
+        .unwrap_or_else(|e| fail(e));
+    for p in reader {
+        println!("{:?}", p.unwrap_or_else(|e| fail(e)));
+    }
 }
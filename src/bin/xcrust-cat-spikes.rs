@@ -1,7 +1,11 @@
 use std::io::prelude::*;
 
 use std::io::stdin;
-use xcrust::spike::mwl_ad::{read_spikes};
+use std::process;
+use xcrust::Error;
+use xcrust::mwl_ad::duration_to_timestamp;
+use xcrust::spike::Spike;
+use xcrust::spike::mwl_ad::{SpikeReader};
 use xcrust::spike::ascii_draw;
 use chrono::Duration;
 
@@ -43,9 +47,22 @@ enum OutputFormat {
 
 fn main() {
     let config = parse_config().unwrap_or_else(|_| panic!("TODO"));
-    let spikes = read_spikes( config.input_file.to_str().unwrap() );
+    let after = config.after.as_ref().map(duration_to_timestamp);
+    let before = config.before.as_ref().map(duration_to_timestamp);
+    let fail = |e: Error| -> ! {
+        eprintln!("xcrust-cat-spikes: {}", e);
+        process::exit(1)
+    };
+    let open = match config.input_format {
+        InputFormat::Ad => SpikeReader::open,
+    };
+    let spikes = open(&config.input_file)
+        .and_then(|r| r.time_range(after, before))
+        .unwrap_or_else(|e| fail(e))
+        .map(|s| s.unwrap_or_else(|e| fail(e)));
     match config.output_format {
-        OutputFormat::SpikeDebug => println!("spikes: {:?}", spikes),
+        OutputFormat::SpikeDebug =>
+            println!("spikes: {:?}", spikes.collect::<Vec<Spike<f32,f32>>>()),
         OutputFormat::SpikeJSON => panic!("not implemented"),
         OutputFormat::SpikeAscii => for s in spikes {
             let plot = ascii_draw::DEFAULT_PLOT;
@@ -0,0 +1,84 @@
+use std::env;
+use std::path::{Path, PathBuf};
+use std::process;
+
+use clap::{crate_version, App, Arg, value_t};
+
+use xcrust::lfp::mwl_ad::{eeg_header, EegWriter};
+use xcrust::mwl_ad::raw::RawContinuousReader;
+
+fn main() {
+    let matches = App::new("xcrust-eegextract")
+        .version(crate_version!())
+        .about("Extract continuous data from a raw CONTINUOUS-mode AD \
+                acquisition file into a .eeg file, as eegextract does")
+        .arg(Arg::from_usage("<input-file> 'Raw CONTINUOUS-mode file'"))
+        .arg(Arg::from_usage("-c --channels [channels] \
+                              'Comma-separated channels to extract (default: all)'"))
+        .arg(Arg::from_usage("-s --start [start] \
+                              'Timestamp of the first buffer, in ticks of 100us (default: 0)'"))
+        .arg(Arg::from_usage("-o --output [output] \
+                              'Output file (default: <input-file stem>.eeg)'"))
+        .get_matches();
+
+    let input = value_t!(matches, "input-file", PathBuf).unwrap_or_else(|e| e.exit());
+    let start = if matches.is_present("start") {
+        value_t!(matches, "start", u32).unwrap_or_else(|e| e.exit())
+    } else {
+        0
+    };
+    let output = matches
+        .value_of("output")
+        .map(PathBuf::from)
+        .unwrap_or_else(|| default_output(&input));
+    if output == input {
+        eprintln!("xcrust-eegextract: refusing to overwrite the input file");
+        process::exit(1);
+    }
+
+    let fail = |e: xcrust::Error| -> ! {
+        eprintln!("xcrust-eegextract: {}", e);
+        process::exit(1)
+    };
+    let reader = RawContinuousReader::open(&input)
+        .map(|r| r.start_timestamp(start))
+        .unwrap_or_else(|e| fail(e));
+    let n_channels = reader.acquisition().nchannels;
+    let channels: Vec<usize> = match matches.value_of("channels") {
+        Some(list) => list
+            .split(',')
+            .map(|c| c.trim().parse::<usize>().ok().filter(|c| *c < n_channels))
+            .collect::<Option<Vec<usize>>>()
+            .unwrap_or_else(|| {
+                eprintln!("xcrust-eegextract: channels must be numbers below {}", n_channels);
+                process::exit(1)
+            }),
+        None => (0..n_channels).collect(),
+    };
+
+    let args: Vec<String> = env::args().collect();
+    let header = eeg_header(&args, &channels, reader.buffer_len(),
+                            &input.to_string_lossy(), &reader.header().as_metadata());
+    let mut writer = EegWriter::create(&output, &header).unwrap_or_else(|e| fail(e));
+    for buffer in reader {
+        let buffer = buffer.map_err(|e| e.at_path(&input)).unwrap_or_else(|e| fail(e));
+        let mut eeg = buffer.split(n_channels);
+        eeg.channels = channels.iter().map(|c| eeg.channels[*c].clone()).collect();
+        writer
+            .write_eeg(&eeg)
+            .map_err(|e| e.at_path(&output))
+            .unwrap_or_else(|e| fail(e));
+    }
+    writer.into_inner().map_err(|e| e.at_path(&output)).unwrap_or_else(|e| fail(e));
+    eprintln!("wrote {}", output.display());
+}
+
+/// `data/run1.eeg.raw` becomes `data/run1.eeg`
+fn default_output(input: &Path) -> PathBuf {
+    let name = input.to_string_lossy();
+    let stem = name
+        .strip_suffix(".eeg.raw")
+        .or_else(|| name.strip_suffix(".raw"))
+        .unwrap_or(&name);
+    PathBuf::from(format!("{}.eeg", stem))
+}
@@ -0,0 +1,107 @@
+use std::env;
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::{Path, PathBuf};
+use std::process;
+
+use clap::{crate_version, App, Arg, value_t};
+
+use xcrust::mwl_ad::raw::RawSpikeReader;
+use xcrust::spike::mwl_ad::{tt_header, SpikeWriter};
+
+fn main() {
+    let matches = App::new("xcrust-extract")
+        .version(crate_version!())
+        .about("Extract the spikes of each probe from a raw AD acquisition file \
+                into .tt files, as adextract does")
+        .arg(Arg::from_usage("<input-file> 'Raw SPIKE-mode file (.spk.raw)'"))
+        .arg(Arg::from_usage("-p --probe [probe] 'Only extract this probe (default: all)'"))
+        .arg(Arg::from_usage("-l --spike-len [spike-len] \
+                              'Samples per channel to keep (default: the recorded spikelen)'"))
+        .arg(Arg::from_usage("-o --output [output] \
+                              'Output file, with %p standing for the probe number \
+                              (default: <input-file stem>.%p.tt)'"))
+        .get_matches();
+
+    let input = value_t!(matches, "input-file", PathBuf).unwrap_or_else(|e| e.exit());
+    let probe = if matches.is_present("probe") {
+        Some(value_t!(matches, "probe", usize).unwrap_or_else(|e| e.exit()))
+    } else {
+        None
+    };
+    let output = matches
+        .value_of("output")
+        .map(str::to_owned)
+        .unwrap_or_else(|| default_output(&input));
+
+    let fail = |e: xcrust::Error| -> ! {
+        eprintln!("xcrust-extract: {}", e);
+        process::exit(1)
+    };
+    let reader = RawSpikeReader::open(&input).unwrap_or_else(|e| fail(e));
+    let acquisition = reader.acquisition().clone();
+    let spike_len = if matches.is_present("spike-len") {
+        value_t!(matches, "spike-len", usize).unwrap_or_else(|e| e.exit())
+    } else {
+        acquisition.spikelen
+    };
+    if spike_len == 0 || spike_len > acquisition.spikelen {
+        eprintln!("xcrust-extract: spike length must be between 1 and {}",
+                  acquisition.spikelen);
+        process::exit(1);
+    }
+    let probes: Vec<usize> = match probe {
+        Some(p) => vec![p],
+        None => (0..acquisition.nelectrodes).collect(),
+    };
+    if probes.len() > 1 && !output.contains("%p") {
+        eprintln!("xcrust-extract: the output name needs a %p to extract several probes");
+        process::exit(1);
+    }
+
+    let args: Vec<String> = env::args().collect();
+    let source_name = input.to_string_lossy();
+    let source = reader.header().as_metadata();
+    let mut writers: Vec<Option<(PathBuf, SpikeWriter<BufWriter<File>>)>> =
+        (0..acquisition.nelectrodes).map(|_| None).collect();
+    for p in probes {
+        if p >= acquisition.nelectrodes {
+            eprintln!("xcrust-extract: the file only has probes 0 to {}",
+                      acquisition.nelectrodes.saturating_sub(1));
+            process::exit(1);
+        }
+        let path = PathBuf::from(output.replace("%p", &p.to_string()));
+        if path == input {
+            eprintln!("xcrust-extract: refusing to overwrite the input file");
+            process::exit(1);
+        }
+        let header = tt_header(&args, p, spike_len, &source_name, &source);
+        let writer = SpikeWriter::create(&path, &header).unwrap_or_else(|e| fail(e));
+        writers[p] = Some((path, writer));
+    }
+
+    let n_channels = acquisition.nelect_chan;
+    for spike in reader {
+        let spike = spike.map_err(|e| e.at_path(&input)).unwrap_or_else(|e| fail(e));
+        if let Some((path, writer)) = writers[spike.electrode].as_mut() {
+            writer
+                .write_record(spike.timestamp, spike.truncated(n_channels, spike_len))
+                .map_err(|e| e.at_path(path))
+                .unwrap_or_else(|e| fail(e));
+        }
+    }
+    for (path, writer) in writers.into_iter().flatten() {
+        writer.into_inner().map_err(|e| e.at_path(&path)).unwrap_or_else(|e| fail(e));
+        eprintln!("wrote {}", path.display());
+    }
+}
+
+/// `data/run1.spk.raw` becomes `data/run1.%p.tt`
+fn default_output(input: &Path) -> String {
+    let name = input.to_string_lossy();
+    let stem = name
+        .strip_suffix(".spk.raw")
+        .or_else(|| name.strip_suffix(".raw"))
+        .unwrap_or(&name);
+    format!("{}.%p.tt", stem)
+}
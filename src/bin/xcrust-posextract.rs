@@ -0,0 +1,62 @@
+use std::env;
+use std::path::PathBuf;
+use std::process;
+
+use clap::{crate_version, App, Arg, value_t};
+
+use xcrust::pos::extract::{CentroidConfig, Positions};
+use xcrust::pos::mwl_ad::{p_header, PosWriter, RawPosReader};
+
+fn main() {
+    let matches = App::new("xcrust-posextract")
+        .version(crate_version!())
+        .about("Reduce the diode images of a raw position tracker file to \
+                front and back diode positions in a .p file, as posextract does")
+        .arg(Arg::from_usage("<input-file> 'Raw position file'"))
+        .arg(Arg::from_usage("-o --output [output] \
+                              'Output file (default: <input-file stem>.p)'"))
+        .arg(Arg::from_usage("-m --min-pixels [min-pixels] \
+                              'Reject diode images with fewer pixels (default: 1)'"))
+        .arg(Arg::from_usage("-r --radius [radius] \
+                              'Distance in pixels from the median pixel beyond which \
+                              pixels are outliers (default: 10)'"))
+        .get_matches();
+
+    let input = value_t!(matches, "input-file", PathBuf).unwrap_or_else(|e| e.exit());
+    let output = matches
+        .value_of("output")
+        .map(PathBuf::from)
+        .unwrap_or_else(|| input.with_extension("p"));
+    if output == input {
+        eprintln!("xcrust-posextract: refusing to overwrite the input file");
+        process::exit(1);
+    }
+    let mut config = CentroidConfig::default();
+    if matches.is_present("min-pixels") {
+        config.min_pixels = value_t!(matches, "min-pixels", usize).unwrap_or_else(|e| e.exit());
+    }
+    if matches.is_present("radius") {
+        config.cluster_radius = value_t!(matches, "radius", f32).unwrap_or_else(|e| e.exit());
+    }
+
+    let fail = |e: xcrust::Error| -> ! {
+        eprintln!("xcrust-posextract: {}", e);
+        process::exit(1)
+    };
+    let reader = RawPosReader::open(&input).unwrap_or_else(|e| fail(e));
+
+    let args: Vec<String> = env::args().collect();
+    let header = p_header(&args, &input.to_string_lossy(), &reader.header().as_metadata());
+    let mut writer = PosWriter::create(&output, &header).unwrap_or_else(|e| fail(e));
+    let mut n_positions = 0;
+    for p in Positions::new(reader, config) {
+        let p = p.map_err(|e| e.at_path(&input)).unwrap_or_else(|e| fail(e));
+        writer
+            .write_record(&p)
+            .map_err(|e| e.at_path(&output))
+            .unwrap_or_else(|e| fail(e));
+        n_positions += 1;
+    }
+    writer.into_inner().map_err(|e| e.at_path(&output)).unwrap_or_else(|e| fail(e));
+    eprintln!("wrote {} positions to {}", n_positions, output.display());
+}
@@ -0,0 +1,64 @@
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::PathBuf;
+use std::process;
+
+use clap::{crate_version, App, Arg, value_t};
+
+use xcrust::mwl_ad::stream::{self, Warning};
+
+fn main() {
+    let matches = App::new("xcrust-repair")
+        .version(crate_version!())
+        .about("Copy an MWL record file, dropping a partial last record \
+                left by a recording that was killed mid-write")
+        .arg(Arg::from_usage("<input-file> 'Damaged file'"))
+        .arg(Arg::from_usage("-o --output [output] \
+                              'Repaired copy (default: <input-file>.repaired)'"))
+        .arg(Arg::from_usage("-t --timestamps \
+                              'Also drop records whose timestamps go backwards'"))
+        .get_matches();
+
+    let input = value_t!(matches, "input-file", PathBuf).unwrap_or_else(|e| e.exit());
+    let output = matches
+        .value_of("output")
+        .map(PathBuf::from)
+        .unwrap_or_else(|| {
+            let mut name = input.clone().into_os_string();
+            name.push(".repaired");
+            PathBuf::from(name)
+        });
+    if output == input {
+        eprintln!("xcrust-repair: refusing to overwrite the input file");
+        process::exit(1);
+    }
+
+    let fail = |e: xcrust::Error| -> ! {
+        eprintln!("xcrust-repair: {}", e);
+        process::exit(1)
+    };
+    let reader = File::open(&input)
+        .map_err(|e| xcrust::Error::from(e).at_path(&input))
+        .unwrap_or_else(|e| fail(e));
+    let mut writer = File::create(&output)
+        .map(BufWriter::new)
+        .map_err(|e| xcrust::Error::from(e).at_path(&output))
+        .unwrap_or_else(|e| fail(e));
+    let warnings = stream::repair(reader, &mut writer, matches.is_present("timestamps"))
+        .map_err(|e| e.at_path(&input))
+        .unwrap_or_else(|e| fail(e));
+
+    for w in warnings.iter() {
+        match w {
+            Warning::TrailingBytes { offset, count } =>
+                eprintln!("dropped {} trailing bytes at byte {}", count, offset),
+            Warning::TimestampRegression { offset, count, previous } =>
+                eprintln!("dropped {} records from byte {} with timestamps before {}",
+                          count, offset, previous),
+            Warning::ClockReset { offset, previous, timestamp, count } =>
+                eprintln!("clock reset from {} to {}; kept {} records held back from byte {}",
+                          previous, timestamp, count, offset),
+        }
+    }
+    eprintln!("wrote {}", output.display());
+}
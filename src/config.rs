@@ -0,0 +1,25 @@
+use std::fs;
+use std::path::Path;
+
+use serde::de::DeserializeOwned;
+
+use crate::Error;
+
+/// Read a config file, such as a position calibration or a track
+/// graph. Config files are TOML, or JSON when the file name ends in
+/// `.json`.
+pub fn load<T: DeserializeOwned>(path: &Path) -> Result<T, Error> {
+    let is_json = path.extension().is_some_and(|e| e.eq_ignore_ascii_case("json"));
+    fs::read_to_string(path)
+        .map_err(Error::from)
+        .and_then(|s| if is_json { from_json(&s) } else { from_toml(&s) })
+        .map_err(|e| e.at_path(path))
+}
+
+pub fn from_toml<T: DeserializeOwned>(s: &str) -> Result<T, Error> {
+    Ok(toml::from_str(s)?)
+}
+
+pub fn from_json<T: DeserializeOwned>(s: &str) -> Result<T, Error> {
+    Ok(serde_json::from_str(s)?)
+}
@@ -0,0 +1,266 @@
+use std::fmt;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use failure::Fail;
+
+use crate::lfp::Discontinuity;
+use crate::mwl_ad::header::HeaderError;
+use crate::mwl_ad::schema::SchemaError;
+
+/// The error returned by every reader in the crate. Each case records
+/// the file it came from (when reading from a path) and, where it is
+/// known, the byte offset of the problem, so that a pipeline over many
+/// files can report the bad ones and carry on with the rest.
+#[derive(Debug)]
+pub enum Error {
+    Io {
+        path: Option<PathBuf>,
+        offset: Option<u64>,
+        err: io::Error,
+    },
+    /// The header could not be parsed, or a required key is missing
+    /// or malformed. `HeaderError` names the key.
+    Header {
+        path: Option<PathBuf>,
+        err: HeaderError,
+    },
+    /// The `Fields` line is malformed, or doesn't describe the record
+    /// layout the reader expects
+    Schema {
+        path: Option<PathBuf>,
+        err: SchemaError,
+    },
+    /// The file ends partway through the record at `offset`
+    TruncatedRecord {
+        path: Option<PathBuf>,
+        offset: u64,
+        expected: usize,
+        found: usize,
+    },
+    /// The record at `offset` has an earlier timestamp than the one
+    /// before it
+    TimestampRegression {
+        path: Option<PathBuf>,
+        offset: u64,
+        previous: u32,
+        timestamp: u32,
+    },
+    /// Continuous data whose buffer timestamps aren't evenly spaced,
+    /// read with `GapPolicy::Error`
+    Discontinuity {
+        path: Option<PathBuf>,
+        discontinuity: Discontinuity,
+    },
+    /// A config file, such as a position calibration, could not be
+    /// parsed
+    Config {
+        path: Option<PathBuf>,
+        err: ConfigError,
+    },
+    /// A value in the file is out of range for what it describes
+    BadValue {
+        path: Option<PathBuf>,
+        offset: Option<u64>,
+        key: String,
+        value: String,
+        expected: String,
+    },
+}
+
+/// Why a config file could not be parsed, in the format it was read
+/// as
+#[derive(Debug)]
+pub enum ConfigError {
+    Toml(toml::de::Error),
+    Json(serde_json::Error),
+}
+
+impl Error {
+    pub fn path(&self) -> Option<&Path> {
+        match self {
+            Error::Io { path, .. }
+            | Error::Header { path, .. }
+            | Error::Schema { path, .. }
+            | Error::TruncatedRecord { path, .. }
+            | Error::TimestampRegression { path, .. }
+            | Error::Discontinuity { path, .. }
+            | Error::Config { path, .. }
+            | Error::BadValue { path, .. } => path.as_ref().map(PathBuf::as_path),
+        }
+    }
+
+    /// Byte offset of the problem from the start of the file
+    pub fn offset(&self) -> Option<u64> {
+        match self {
+            Error::Io { offset, .. } | Error::BadValue { offset, .. } => *offset,
+            Error::Header { err: HeaderError::ParseError { offset, .. }, .. } =>
+                Some(*offset as u64),
+            Error::TruncatedRecord { offset, .. }
+            | Error::TimestampRegression { offset, .. } => Some(*offset),
+            _ => None,
+        }
+    }
+
+    /// The header key involved, if any
+    pub fn key(&self) -> Option<&str> {
+        match self {
+            Error::Header { err: HeaderError::UnknownKey { key }, .. }
+            | Error::Header { err: HeaderError::BadValue { key, .. }, .. }
+            | Error::BadValue { key, .. } => Some(key),
+            Error::Schema { .. } => Some("Fields"),
+            _ => None,
+        }
+    }
+
+    /// A `BadValue` error for `key`, with the file and byte offset
+    /// left for `at_path` and `at_offset` to fill in
+    pub fn bad_value<V: Into<String>, E: Into<String>>(key: &str, value: V, expected: E) -> Error {
+        Error::BadValue {
+            path: None,
+            offset: None,
+            key: key.to_owned(),
+            value: value.into(),
+            expected: expected.into(),
+        }
+    }
+
+    /// Record the byte offset of an `Io` or `BadValue` error, unless
+    /// already known
+    pub fn at_offset(mut self, at: u64) -> Error {
+        if let Error::Io { offset, .. } | Error::BadValue { offset, .. } = &mut self {
+            if offset.is_none() {
+                *offset = Some(at);
+            }
+        }
+        self
+    }
+
+    /// Record the file the error came from, unless already known
+    pub fn at_path(mut self, file: &Path) -> Error {
+        match &mut self {
+            Error::Io { path, .. }
+            | Error::Header { path, .. }
+            | Error::Schema { path, .. }
+            | Error::TruncatedRecord { path, .. }
+            | Error::TimestampRegression { path, .. }
+            | Error::Discontinuity { path, .. }
+            | Error::Config { path, .. }
+            | Error::BadValue { path, .. } =>
+                if path.is_none() {
+                    *path = Some(file.to_path_buf());
+                },
+        }
+        self
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Some(path) = self.path() {
+            write!(f, "{}: ", path.display())?;
+        }
+        if let Some(offset) = self.offset() {
+            write!(f, "byte {}: ", offset)?;
+        }
+        match self {
+            Error::Io { err, .. } => write!(f, "i/o error: {}", err),
+            Error::Header { err, .. } => write!(f, "{}", err),
+            Error::Schema { err, .. } => write!(f, "{}", err),
+            Error::TruncatedRecord { expected, found, .. } =>
+                write!(f, "truncated record: {} of {} bytes", found, expected),
+            Error::TimestampRegression { previous, timestamp, .. } =>
+                write!(f, "timestamp {} follows later timestamp {}", timestamp, previous),
+            Error::Discontinuity { discontinuity: d, .. } =>
+                write!(f, "{:?} at buffer {}: timestamp {}, expected {}",
+                       d.kind, d.buffer, d.found, d.expected),
+            Error::Config { err, .. } => write!(f, "bad config: {}", err),
+            Error::BadValue { key, value, expected, .. } =>
+                write!(f, "{} has value {:?}, expected {}", key, value, expected),
+        }
+    }
+}
+
+impl Fail for Error {
+    fn cause(&self) -> Option<&dyn Fail> {
+        match self {
+            Error::Io { err, .. } => Some(err),
+            Error::Header { err, .. } => Some(err),
+            Error::Schema { err, .. } => Some(err),
+            Error::Config { err, .. } => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ConfigError::Toml(err) => write!(f, "{}", err),
+            ConfigError::Json(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl Fail for ConfigError {
+    fn cause(&self) -> Option<&dyn Fail> {
+        match self {
+            ConfigError::Toml(err) => Some(err),
+            ConfigError::Json(err) => Some(err),
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Error {
+        Error::Io { path: None, offset: None, err }
+    }
+}
+
+impl From<HeaderError> for Error {
+    fn from(err: HeaderError) -> Error {
+        Error::Header { path: None, err }
+    }
+}
+
+impl From<SchemaError> for Error {
+    fn from(err: SchemaError) -> Error {
+        Error::Schema { path: None, err }
+    }
+}
+
+impl From<toml::de::Error> for Error {
+    fn from(err: toml::de::Error) -> Error {
+        Error::Config { path: None, err: ConfigError::Toml(err) }
+    }
+}
+
+impl From<serde_json::Error> for Error {
+    fn from(err: serde_json::Error) -> Error {
+        Error::Config { path: None, err: ConfigError::Json(err) }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_reports_context() {
+        let e = Error::from(HeaderError::UnknownKey { key: "Probe".to_owned() })
+            .at_path(Path::new("a.tt"))
+            .at_path(Path::new("b.tt"));
+        assert_eq!(e.path(), Some(Path::new("a.tt")));
+        assert_eq!(e.key(), Some("Probe"));
+        assert_eq!(e.to_string(), "a.tt: missing header key: \"Probe\"");
+
+        let e = Error::TruncatedRecord { path: None, offset: 1300, expected: 260, found: 3 };
+        assert_eq!(e.offset(), Some(1300));
+        assert_eq!(e.to_string(), "byte 1300: truncated record: 3 of 260 bytes");
+
+        let e = Error::bad_value("Probe", "9", "a probe below 2").at_offset(40).at_offset(80);
+        assert_eq!(e.offset(), Some(40));
+        assert_eq!(e.key(), Some("Probe"));
+    }
+}
@@ -0,0 +1,208 @@
+use std::iter::FromIterator;
+use std::ops::Range;
+
+use crate::mwl_ad::TICKS_PER_SECOND;
+
+pub mod mwl_ad;
+
+/// A set of times, in timestamp ticks, as sorted, disjoint, non-empty
+/// half-open intervals. Intervals that overlap or touch are merged.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct IntervalSet {
+    intervals: Vec<Range<u32>>,
+}
+
+impl IntervalSet {
+    pub fn new() -> IntervalSet {
+        IntervalSet::default()
+    }
+
+    pub fn intervals(&self) -> &[Range<u32>] {
+        &self.intervals
+    }
+
+    /// The number of disjoint intervals
+    pub fn len(&self) -> usize {
+        self.intervals.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.intervals.is_empty()
+    }
+
+    /// From the start of the first interval to the end of the last
+    pub fn span(&self) -> Option<Range<u32>> {
+        match (self.intervals.first(), self.intervals.last()) {
+            (Some(first), Some(last)) => Some(first.start..last.end),
+            _ => None,
+        }
+    }
+
+    /// Total length of the intervals, in seconds
+    pub fn duration(&self) -> f64 {
+        self.intervals.iter().map(|r| (r.end - r.start) as f64).sum::<f64>() / TICKS_PER_SECOND
+    }
+
+    pub fn contains(&self, t: u32) -> bool {
+        self.position(t).is_ok()
+    }
+
+    /// Whether each of `times`, which must be sorted, is in the set.
+    /// This walks the times and intervals together, rather than
+    /// searching for each time.
+    pub fn contains_sorted(&self, times: &[u32]) -> Vec<bool> {
+        let mut intervals = self.intervals.iter().peekable();
+        times
+            .iter()
+            .map(|&t| {
+                while intervals.peek().is_some_and(|r| r.end <= t) {
+                    intervals.next();
+                }
+                intervals.peek().is_some_and(|r| r.start <= t)
+            })
+            .collect()
+    }
+
+    /// For each interval, the range of indices of the `times` (which
+    /// must be sorted) that fall in it, to slice spike times by epoch
+    pub fn index_ranges(&self, times: &[u32]) -> Vec<Range<usize>> {
+        self.intervals
+            .iter()
+            .map(|r| times.partition_point(|&t| t < r.start)..times.partition_point(|&t| t < r.end))
+            .collect()
+    }
+
+    pub fn union(&self, other: &IntervalSet) -> IntervalSet {
+        self.intervals.iter().chain(other.intervals.iter()).cloned().collect()
+    }
+
+    pub fn intersection(&self, other: &IntervalSet) -> IntervalSet {
+        let (mut i, mut j) = (0, 0);
+        let mut intervals = Vec::new();
+        while i < self.intervals.len() && j < other.intervals.len() {
+            let (a, b) = (&self.intervals[i], &other.intervals[j]);
+            let (start, end) = (a.start.max(b.start), a.end.min(b.end));
+            if start < end {
+                intervals.push(start..end);
+            }
+            if a.end < b.end {
+                i += 1;
+            } else {
+                j += 1;
+            }
+        }
+        IntervalSet { intervals }
+    }
+
+    /// The times in `self` but not in `other`
+    pub fn difference(&self, other: &IntervalSet) -> IntervalSet {
+        match self.span() {
+            Some(span) => self.intersection(&other.complement(span)),
+            None => IntervalSet::new(),
+        }
+    }
+
+    /// The times within `bounds` that aren't in the set
+    pub fn complement(&self, bounds: Range<u32>) -> IntervalSet {
+        let mut intervals = Vec::new();
+        let mut start = bounds.start;
+        for r in self.intervals.iter() {
+            if r.start > start {
+                intervals.push(start..r.start.min(bounds.end));
+            }
+            start = start.max(r.end);
+            if start >= bounds.end {
+                break;
+            }
+        }
+        if start < bounds.end {
+            intervals.push(start..bounds.end);
+        }
+        intervals.into_iter().filter(|r| r.start < r.end).collect()
+    }
+
+    /// Merge intervals separated by no more than `gap` ticks, as when
+    /// joining ripple windows or run bouts that briefly break off
+    pub fn merge_with_gap(&self, gap: u32) -> IntervalSet {
+        let mut intervals: Vec<Range<u32>> = Vec::with_capacity(self.intervals.len());
+        for r in self.intervals.iter() {
+            match intervals.last_mut() {
+                Some(last) if r.start - last.end <= gap => last.end = r.end,
+                _ => intervals.push(r.clone()),
+            }
+        }
+        IntervalSet { intervals }
+    }
+
+    // The index of the interval containing `t`, or else of the first
+    // interval after it
+    fn position(&self, t: u32) -> Result<usize, usize> {
+        let i = self.intervals.partition_point(|r| r.end <= t);
+        match self.intervals.get(i) {
+            Some(r) if r.start <= t => Ok(i),
+            _ => Err(i),
+        }
+    }
+}
+
+impl FromIterator<Range<u32>> for IntervalSet {
+    fn from_iter<I: IntoIterator<Item = Range<u32>>>(iter: I) -> IntervalSet {
+        let mut sorted: Vec<Range<u32>> = iter.into_iter().filter(|r| r.start < r.end).collect();
+        sorted.sort_by_key(|r| r.start);
+        let mut intervals: Vec<Range<u32>> = Vec::with_capacity(sorted.len());
+        for r in sorted {
+            match intervals.last_mut() {
+                Some(last) if r.start <= last.end => last.end = last.end.max(r.end),
+                _ => intervals.push(r),
+            }
+        }
+        IntervalSet { intervals }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn set(intervals: &[Range<u32>]) -> IntervalSet {
+        intervals.iter().cloned().collect()
+    }
+
+    #[test]
+    fn it_merges_and_queries_intervals() {
+        let set = set(&[30..40, 0..10, 5..12, 12..15, 20..20]);
+        assert_eq!(set.intervals(), &[0..15, 30..40]);
+        assert!(set.contains(0) && set.contains(14) && set.contains(30));
+        assert!(!set.contains(15) && !set.contains(25) && !set.contains(40));
+        assert_eq!(set.span(), Some(0..40));
+        assert_eq!(set.duration(), 25.0 / TICKS_PER_SECOND);
+        assert!(IntervalSet::new().is_empty());
+    }
+
+    #[test]
+    fn it_combines_sets() {
+        let a = set(&[0..10, 20..30, 40..50]);
+        let b = set(&[5..25, 45..60]);
+        assert_eq!(a.union(&b).intervals(), &[0..30, 40..60]);
+        assert_eq!(a.intersection(&b).intervals(), &[5..10, 20..25, 45..50]);
+        assert_eq!(a.difference(&b).intervals(), &[0..5, 25..30, 40..45]);
+        assert_eq!(b.difference(&a).intervals(), &[10..20, 50..60]);
+        assert_eq!(a.complement(5..45).intervals(), &[10..20, 30..40]);
+        assert_eq!(a.complement(0..100).intervals(), &[10..20, 30..40, 50..100]);
+        assert_eq!(IntervalSet::new().complement(3..7).span(), Some(3..7));
+        assert_eq!(a.merge_with_gap(10).span(), Some(0..50));
+        assert_eq!(a.merge_with_gap(10).len(), 1);
+        assert_eq!(a.merge_with_gap(9), a);
+    }
+
+    #[test]
+    fn it_finds_sorted_times() {
+        let a = set(&[10..20, 30..40]);
+        let times = [0, 10, 15, 20, 25, 30, 39, 40, 50];
+        assert_eq!(a.contains_sorted(&times),
+                   vec![false, true, true, false, false, true, true, false, false]);
+        assert!(a.contains_sorted(&times).iter().zip(times.iter()).all(|(&c, &t)| c == a.contains(t)));
+        assert_eq!(a.index_ranges(&times), vec![1..3, 5..7]);
+    }
+}
@@ -0,0 +1,147 @@
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::ops::Range;
+use std::path::Path;
+
+use super::IntervalSet;
+use crate::Error;
+use crate::mwl_ad::TICKS_PER_SECOND;
+use crate::mwl_ad::header::{self, MetadataBuf};
+
+/// A named stretch of a recording, such as `run1` or `sleep2`
+#[derive(Clone, Debug, PartialEq)]
+pub struct Epoch {
+    pub name: String,
+    /// In ticks of 100us
+    pub interval: Range<u32>,
+}
+
+/// The times covered by any of `epochs`
+pub fn epoch_set(epochs: &[Epoch]) -> IntervalSet {
+    epochs.iter().map(|e| e.interval.clone()).collect()
+}
+
+/// Read an epoch file: an MWL ascii file with one epoch per line, as
+/// a name followed by start and end times in seconds
+pub fn read_epochs(path: &Path) -> Result<Vec<Epoch>, Error> {
+    File::open(path)
+        .map_err(Error::from)
+        .and_then(parse_epochs)
+        .map_err(|e| e.at_path(path))
+}
+
+pub fn parse_epochs<R: Read>(inner: R) -> Result<Vec<Epoch>, Error> {
+    let mut inner = BufReader::new(inner);
+    let header_bytes = header::read_bytes(&mut inner)?;
+    let (metadata, _) = header::parse(&header_bytes)?;
+    if let Some(file_type) = header::lookup(&metadata, "File type") {
+        if file_type != "Ascii" {
+            return Err(Error::bad_value("File type", file_type, "Ascii"));
+        }
+    }
+
+    let mut text = String::new();
+    inner.read_to_string(&mut text)?;
+    let mut offset = header_bytes.len() as u64;
+    let mut epochs = Vec::new();
+    for line in text.split_inclusive('\n') {
+        let line_offset = offset;
+        offset += line.len() as u64;
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        match fields.as_slice() {
+            [] => continue,
+            [name, start_text, end_text] => {
+                let start = parse_time(start_text, line_offset)?;
+                let end = parse_time(end_text, line_offset)?;
+                if end < start {
+                    return Err(Error::bad_value("tend", *end_text, "a time no earlier than tstart")
+                               .at_offset(line_offset));
+                }
+                epochs.push(Epoch { name: name.to_string(), interval: start..end });
+            },
+            _ => return Err(Error::bad_value("epoch", line.trim_end(),
+                                             "a name, a start time and an end time")
+                            .at_offset(line_offset)),
+        }
+    }
+    Ok(epochs)
+}
+
+fn parse_time(s: &str, offset: u64) -> Result<u32, Error> {
+    match s.parse::<f64>() {
+        Ok(seconds) if seconds >= 0.0 && seconds * TICKS_PER_SECOND <= u32::MAX as f64 =>
+            Ok((seconds * TICKS_PER_SECOND).round() as u32),
+        _ => Err(Error::bad_value("time", s, "a time in seconds within the range of timestamps")
+                 .at_offset(offset)),
+    }
+}
+
+/// Header for an epoch file written by the running program
+pub fn epoch_header<S: AsRef<str>>(args: &[S]) -> MetadataBuf {
+    let mut h = MetadataBuf::for_program(args);
+    h.push_pair("File type", "Ascii");
+    h.push_pair("Extraction type", "epochs");
+    h.push_pair("Fields", "epoch\ttstart\ttend");
+    h
+}
+
+pub fn write_epochs(path: &Path, header: &MetadataBuf, epochs: &[Epoch]) -> Result<(), Error> {
+    File::create(path)
+        .map_err(Error::from)
+        .and_then(|f| {
+            let mut w = BufWriter::new(f);
+            format_epochs(&mut w, header, epochs)?;
+            w.flush()?;
+            Ok(())
+        })
+        .map_err(|e| e.at_path(path))
+}
+
+pub fn format_epochs<W: Write>(w: &mut W, header: &MetadataBuf, epochs: &[Epoch]) -> Result<(), Error> {
+    header::write(&header.as_metadata(), w)?;
+    for e in epochs {
+        if e.name.is_empty() || e.name.contains(char::is_whitespace) {
+            return Err(Error::bad_value("epoch", e.name.as_str(), "a name without spaces"));
+        }
+        writeln!(w, "{}\t{:.4}\t{:.4}", e.name,
+                 e.interval.start as f64 / TICKS_PER_SECOND,
+                 e.interval.end as f64 / TICKS_PER_SECOND)?;
+    }
+    Ok(())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_round_trips_epoch_files() {
+        let epochs = vec![
+            Epoch { name: "sleep1".to_owned(), interval: 0..12_000_000 },
+            Epoch { name: "run1".to_owned(), interval: 12_500_000..24_012_345 },
+        ];
+        let mut bytes = Vec::new();
+        format_epochs(&mut bytes, &epoch_header(&["test"]), &epochs).unwrap();
+        assert!(String::from_utf8_lossy(&bytes).contains("run1\t1250.0000\t2401.2345\n"));
+        assert_eq!(parse_epochs(bytes.as_slice()).unwrap(), epochs);
+        assert!((epoch_set(&epochs).duration() - 2351.2345).abs() < 1e-9);
+
+        let bad = vec![Epoch { name: "run 2".to_owned(), interval: 0..1 }];
+        assert!(format_epochs(&mut Vec::new(), &epoch_header(&["test"]), &bad).is_err());
+    }
+
+    #[test]
+    fn it_reports_bad_lines() {
+        let mut bytes = header::to_bytes(&epoch_header(&["test"]).as_metadata()).unwrap();
+        let data_offset = bytes.len() as u64;
+        bytes.extend_from_slice(b"run1 10 20\n\nrun2 30\n");
+        match parse_epochs(bytes.as_slice()) {
+            Err(Error::BadValue { offset, key, .. }) => {
+                assert_eq!(key, "epoch");
+                assert_eq!(offset, Some(data_offset + 12));
+            },
+            r => panic!("expected a bad value, got {:?}", r),
+        }
+    }
+}
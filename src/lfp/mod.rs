@@ -0,0 +1,345 @@
+use std::ops::Range;
+
+use crate::Error;
+use crate::mwl_ad::TICKS_PER_SECOND;
+
+pub mod mwl_ad;
+
+/// A stretch of continuously sampled signal (an LFP or EEG trace)
+/// from several channels, starting at `time`. As with `Spike`, the
+/// voltage and time parameters are abstract, and are interpreted by
+/// the container: a raw AD buffer is an `Eeg<i16, u32>` of AD values
+/// and timestamp ticks.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Eeg<V, T> {
+    /// One trace per channel, all of the same length
+    pub channels: Vec<Vec<V>>,
+    /// Time of the first sample of each trace
+    pub time: T,
+}
+
+impl<V, T> Eeg<V, T> {
+    pub fn n_channels(&self) -> usize {
+        self.channels.len()
+    }
+
+    /// Samples per channel
+    pub fn len(&self) -> usize {
+        self.channels.first().map_or(0, Vec::len)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// How a buffer's timestamp can break from the regular spacing of
+/// buffers, `buffer_len / rate` apart
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DiscontinuityKind {
+    /// The buffer starts later than expected: samples are missing
+    Gap,
+    /// The buffer starts before the previous one ended
+    Overlap,
+    /// The buffer starts no later than the previous one started, as
+    /// happens when the AD clock is reset
+    Reset,
+}
+
+/// A buffer whose timestamp doesn't follow on from the buffer
+/// before it
+#[derive(Clone, Debug, PartialEq)]
+pub struct Discontinuity {
+    pub kind: DiscontinuityKind,
+    /// Index of the buffer, counting from the first buffer read
+    pub buffer: usize,
+    /// Index of the buffer's first sample
+    pub sample: usize,
+    /// The timestamp that would have followed on from the previous
+    /// buffer
+    pub expected: u32,
+    pub found: u32,
+}
+
+impl Discontinuity {
+    /// The stretch of time, in ticks of 100us, that is missing (for a
+    /// gap) or covered twice (otherwise)
+    pub fn interval(&self) -> Range<u32> {
+        self.expected.min(self.found)..self.expected.max(self.found)
+    }
+
+    /// The number of samples that fit in `interval` at `rate`
+    pub fn n_samples(&self, rate: f64) -> usize {
+        let ticks = self.interval().end - self.interval().start;
+        (ticks as f64 / TICKS_PER_SECOND * rate).round() as usize
+    }
+}
+
+/// Timestamps are whole ticks but a buffer needn't last a whole
+/// number of ticks, so spacing is allowed to be off by this much
+pub const CONTINUITY_TOLERANCE: u32 = 1;
+
+/// Compare the timestamp of a buffer with that of the buffer before
+/// it, which held `previous_len` samples per channel at `rate`,
+/// returning the kind of break, if any, and the expected timestamp
+pub(crate) fn check_continuity(
+    previous: u32,
+    previous_len: usize,
+    rate: f64,
+    timestamp: u32,
+) -> Option<(DiscontinuityKind, u32)> {
+    let duration = previous_len as f64 / rate * TICKS_PER_SECOND;
+    let expected = (previous as f64 + duration).round().min(u32::MAX as f64) as u32;
+    let kind = if timestamp <= previous {
+        DiscontinuityKind::Reset
+    } else if timestamp > expected.saturating_add(CONTINUITY_TOLERANCE) {
+        DiscontinuityKind::Gap
+    } else if timestamp < expected.saturating_sub(CONTINUITY_TOLERANCE) {
+        DiscontinuityKind::Overlap
+    } else {
+        return None;
+    };
+    Some((kind, expected))
+}
+
+/// What to do with the discontinuities of a `ContinuousSignal`
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum GapPolicy {
+    /// Fill gaps with NaN samples. Overlaps and resets can't be
+    /// filled, and split the signal instead.
+    NanFill,
+    /// Fill gaps with zeros, splitting at overlaps and resets
+    ZeroFill,
+    /// Split the signal into evenly sampled segments
+    Split,
+    /// Fail with `Error::Discontinuity` at the first discontinuity
+    Error,
+}
+
+/// A continuous signal in volts, put together from the buffers of a
+/// .eeg file. Buffers needn't be back to back: each keeps its own
+/// timestamp, from which the times of its samples are reconstructed.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ContinuousSignal {
+    /// One trace per channel, in volts
+    pub channels: Vec<Vec<f32>>,
+    /// Samples per second on each channel
+    pub rate: f64,
+    /// Time of the first sample, in seconds
+    pub start_time: f64,
+    /// Amplifier gain of each channel
+    pub gains: Vec<f32>,
+    /// Index of the first sample and timestamp (in ticks of 100us)
+    /// of each buffer, in order
+    pub buffers: Vec<(usize, u32)>,
+}
+
+impl ContinuousSignal {
+    /// An empty signal of `gains.len()` channels
+    pub fn new(rate: f64, gains: Vec<f32>) -> ContinuousSignal {
+        ContinuousSignal {
+            channels: vec![Vec::new(); gains.len()],
+            rate,
+            start_time: 0.0,
+            gains,
+            buffers: Vec::new(),
+        }
+    }
+
+    /// Append a buffer of samples in volts, with its timestamp. Fails
+    /// unless the buffer has one trace per channel of the signal, all
+    /// of the same length.
+    pub fn push(&mut self, eeg: &Eeg<f32, u32>) -> Result<(), Error> {
+        if eeg.channels.len() != self.n_channels() {
+            return Err(Error::bad_value("channels", format!("{} channels", eeg.channels.len()),
+                                        format!("{} channels", self.n_channels())));
+        }
+        let lens: Vec<usize> = eeg.channels.iter().map(Vec::len).collect();
+        if lens.windows(2).any(|w| w[0] != w[1]) {
+            return Err(Error::bad_value("channels", format!("traces of {:?} samples", lens),
+                                        "the same number of samples on each channel"));
+        }
+        if self.buffers.is_empty() {
+            self.start_time = eeg.time as f64 / TICKS_PER_SECOND;
+        }
+        self.buffers.push((self.len(), eeg.time));
+        for (trace, samples) in self.channels.iter_mut().zip(eeg.channels.iter()) {
+            trace.extend_from_slice(samples);
+        }
+        Ok(())
+    }
+
+    pub fn n_channels(&self) -> usize {
+        self.channels.len()
+    }
+
+    /// Samples per channel
+    pub fn len(&self) -> usize {
+        self.channels.first().map_or(0, Vec::len)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Time of sample `i`, in seconds, counted on from the timestamp
+    /// of the buffer it came from
+    pub fn time(&self, i: usize) -> f64 {
+        let b = self.buffers.partition_point(|(first, _)| *first <= i);
+        match b.checked_sub(1).map(|b| self.buffers[b]) {
+            Some((first, timestamp)) =>
+                (timestamp as f64 + (i - first) as f64 * TICKS_PER_SECOND / self.rate)
+                    / TICKS_PER_SECOND,
+            None => self.start_time + i as f64 / self.rate,
+        }
+    }
+
+    /// Times of all samples, in seconds
+    pub fn times(&self) -> Vec<f64> {
+        (0..self.len()).map(|i| self.time(i)).collect()
+    }
+
+    /// Samples per channel in buffer `b`
+    fn buffer_len(&self, b: usize) -> usize {
+        let end = self.buffers.get(b + 1).map_or(self.len(), |(first, _)| *first);
+        end - self.buffers[b].0
+    }
+
+    fn buffer(&self, b: usize) -> Eeg<f32, u32> {
+        let (first, time) = self.buffers[b];
+        let end = first + self.buffer_len(b);
+        Eeg { channels: self.channels.iter().map(|c| c[first..end].to_vec()).collect(), time }
+    }
+
+    /// Every buffer whose timestamp doesn't follow on from the buffer
+    /// before it
+    pub fn discontinuities(&self) -> Vec<Discontinuity> {
+        (1..self.buffers.len())
+            .filter_map(|b| {
+                let (_, previous) = self.buffers[b - 1];
+                let (sample, found) = self.buffers[b];
+                check_continuity(previous, self.buffer_len(b - 1), self.rate, found)
+                    .map(|(kind, expected)| Discontinuity { kind, buffer: b, sample, expected, found })
+            })
+            .collect()
+    }
+
+    /// Deal with discontinuities according to `policy`, returning the
+    /// signal as one or more evenly sampled segments
+    pub fn segments(self, policy: GapPolicy) -> Result<Vec<ContinuousSignal>, Error> {
+        let discontinuities = self.discontinuities();
+        if policy == GapPolicy::Error {
+            if let Some(discontinuity) = discontinuities.into_iter().next() {
+                return Err(Error::Discontinuity { path: None, discontinuity });
+            }
+            return Ok(vec![self]);
+        }
+
+        let mut segments = Vec::new();
+        let mut segment = ContinuousSignal::new(self.rate, self.gains.clone());
+        let mut discontinuities = discontinuities.into_iter().peekable();
+        for b in 0..self.buffers.len() {
+            if let Some(d) = discontinuities.next_if(|d| d.buffer == b) {
+                let fill = match policy {
+                    GapPolicy::NanFill => f32::NAN,
+                    _ => 0.0,
+                };
+                match (d.kind, policy) {
+                    (DiscontinuityKind::Gap, GapPolicy::NanFill)
+                    | (DiscontinuityKind::Gap, GapPolicy::ZeroFill) => {
+                        let n = d.n_samples(self.rate);
+                        segment.push(&Eeg {
+                            channels: vec![vec![fill; n]; self.n_channels()],
+                            time: d.expected,
+                        })?;
+                    },
+                    _ => segments.push(std::mem::replace(
+                        &mut segment,
+                        ContinuousSignal::new(self.rate, self.gains.clone()),
+                    )),
+                }
+            }
+            segment.push(&self.buffer(b))?;
+        }
+        segments.push(segment);
+        Ok(segments)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_reconstructs_sample_times() {
+        let mut s = ContinuousSignal::new(1000.0, vec![1.0, 2.0]);
+        s.push(&Eeg { channels: vec![vec![0.0; 4], vec![1.0; 4]], time: 100 }).unwrap();
+        // A buffer that starts late leaves a gap in time
+        s.push(&Eeg { channels: vec![vec![0.0; 4], vec![1.0; 4]], time: 200 }).unwrap();
+        assert_eq!(s.len(), 8);
+        assert_eq!(s.start_time, 0.01);
+        assert_eq!(s.time(3), 0.013);
+        assert_eq!(s.time(4), 0.02);
+        assert_eq!(s.times()[7], 0.023);
+    }
+
+    // Buffers of 4 samples at 1 kHz, 40 ticks long
+    fn signal(timestamps: &[u32]) -> ContinuousSignal {
+        let mut s = ContinuousSignal::new(1000.0, vec![1.0]);
+        for (i, t) in timestamps.iter().enumerate() {
+            s.push(&Eeg { channels: vec![vec![i as f32; 4]], time: *t }).unwrap();
+        }
+        s
+    }
+
+    #[test]
+    fn it_finds_discontinuities() {
+        let s = signal(&[100, 140, 181, 260, 280, 50]);
+        let d = s.discontinuities();
+        let kinds: Vec<(DiscontinuityKind, usize)> = d.iter().map(|d| (d.kind, d.buffer)).collect();
+        assert_eq!(kinds, vec![(DiscontinuityKind::Gap, 3),
+                               (DiscontinuityKind::Overlap, 4),
+                               (DiscontinuityKind::Reset, 5)]);
+        assert_eq!(d[0].interval(), 221..260);
+        assert_eq!(d[0].sample, 12);
+        assert_eq!(d[0].n_samples(1000.0), 4);
+        assert_eq!(d[1].interval(), 280..300);
+    }
+
+    #[test]
+    fn it_applies_gap_policies() {
+        let s = signal(&[100, 140, 220, 260, 100]);
+        match s.clone().segments(GapPolicy::Error) {
+            Err(Error::Discontinuity { discontinuity, .. }) =>
+                assert_eq!(discontinuity.kind, DiscontinuityKind::Gap),
+            r => panic!("expected a discontinuity, got {:?}", r),
+        }
+
+        let split = s.clone().segments(GapPolicy::Split).unwrap();
+        let lens: Vec<usize> = split.iter().map(ContinuousSignal::len).collect();
+        assert_eq!(lens, vec![8, 8, 4]);
+        assert_eq!(split[1].start_time, 0.022);
+
+        let filled = s.segments(GapPolicy::NanFill).unwrap();
+        assert_eq!(filled.len(), 2);
+        assert_eq!(filled[0].len(), 20);
+        assert!(filled[0].channels[0][8..12].iter().all(|v| v.is_nan()));
+        assert_eq!(filled[0].channels[0][12], 2.0);
+        assert_eq!(filled[0].time(12), 0.022);
+        assert!(filled[0].discontinuities().is_empty());
+    }
+
+    #[test]
+    fn it_rejects_buffers_of_the_wrong_shape() {
+        let mut s = ContinuousSignal::new(1000.0, vec![1.0, 2.0]);
+        for channels in [vec![vec![0.0; 4]], vec![vec![0.0; 4], vec![1.0; 3]]] {
+            match s.push(&Eeg { channels, time: 100 }) {
+                Err(Error::BadValue { key, .. }) => assert_eq!(key, "channels"),
+                r => panic!("expected a bad value, got {:?}", r),
+            }
+        }
+        assert!(s.is_empty());
+        assert!(s.buffers.is_empty());
+    }
+}
@@ -0,0 +1,415 @@
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Seek, Write};
+use std::path::Path;
+
+use super::{check_continuity, ContinuousSignal, Discontinuity, Eeg, GapPolicy};
+use crate::Error;
+use crate::mwl_ad::{to_volts, FormatType};
+use crate::mwl_ad::acquisition::AdAcquisitionHeader;
+use crate::mwl_ad::header::{self, Metadata, MetadataBuf};
+use crate::mwl_ad::schema::{Field, RecordSchema};
+use crate::mwl_ad::stream::{self, RecordStream, Recovery, Warning};
+
+/// The .eeg record layout: a u32 timestamp followed by `buffer_len`
+/// samples from each of `n_channels` channels, interleaved
+pub fn eeg_schema(n_channels: usize, buffer_len: usize) -> RecordSchema {
+    RecordSchema::new(vec![
+        Field::new("timestamp", FormatType::ULongT, 4, 1),
+        Field::new("data", FormatType::ShortT, 2, n_channels * buffer_len),
+    ])
+}
+
+/// Read a whole .eeg file into memory
+pub fn read_eeg(file_path: &Path) -> Result<ContinuousSignal, Error> {
+    let reader = EegReader::open(file_path)?;
+    let mut signal = ContinuousSignal::new(reader.rate(), reader.gains().to_vec());
+    for eeg in reader {
+        signal.push(&eeg?).map_err(|e| e.at_path(file_path))?;
+    }
+    Ok(signal)
+}
+
+/// Read a whole .eeg file into memory, dealing with gaps and other
+/// discontinuities according to `policy`
+pub fn read_eeg_segments(file_path: &Path, policy: GapPolicy)
+                         -> Result<Vec<ContinuousSignal>, Error> {
+    read_eeg(file_path)?
+        .segments(policy)
+        .map_err(|e| e.at_path(file_path))
+}
+
+/// Reads the buffers of a continuous data (.eeg) file one at a time,
+/// converting samples to volts with the gains of their channels.
+///
+/// Buffers whose timestamps don't follow on from the buffer before
+/// are read as they are, and recorded as `discontinuities`; use
+/// `ContinuousSignal::segments` to deal with them.
+pub struct EegReader<R> {
+    header: MetadataBuf,
+    layout: EegLayout,
+    gains: Vec<f32>,
+    rate: f64,
+    records: RecordStream<BufReader<R>>,
+    previous: Option<u32>,
+    n_buffers: usize,
+    discontinuities: Vec<Discontinuity>,
+}
+
+impl EegReader<File> {
+    pub fn open(path: &Path) -> Result<EegReader<File>, Error> {
+        File::open(path)
+            .map_err(Error::from)
+            .and_then(EegReader::new)
+            .map(|mut r| {
+                r.records = r.records.with_path(path);
+                r
+            })
+            .map_err(|e| e.at_path(path))
+    }
+}
+
+impl<R: Read> EegReader<R> {
+    /// Parse the header of `inner`; buffers are read on iteration
+    pub fn new(inner: R) -> Result<EegReader<R>, Error> {
+        let mut layout = None;
+        let (header, mut records) = stream::open_with(inner, |metadata| {
+            let l = EegLayout::from_metadata(metadata)?;
+            let schema = l.schema();
+            layout = Some(l);
+            Ok(schema)
+        })?;
+        let layout = layout.unwrap();
+        // Clock resets are discontinuities like any other
+        records.set_check_order(false);
+        let acquisition = AdAcquisitionHeader::from_metadata(&header.as_metadata())?;
+        let gains = layout.channels
+            .iter()
+            .map(|c| acquisition.channels.get(*c).map(|s| s.ampgain))
+            .collect::<Option<Vec<f32>>>()
+            .ok_or_else(|| Error::bad_value("Channels", format!("{:?}", layout.channels),
+                                            format!("channels below {}", acquisition.nchannels)))?;
+        // The acquisition rate is summed over all channels
+        let rate = acquisition.rate / acquisition.nchannels as f64;
+        Ok(EegReader {
+            header,
+            layout,
+            gains,
+            rate,
+            records,
+            previous: None,
+            n_buffers: 0,
+            discontinuities: Vec::new(),
+        })
+    }
+
+    pub fn header(&self) -> &MetadataBuf {
+        &self.header
+    }
+
+    /// The acquisition channels in the file, in order
+    pub fn channels(&self) -> &[usize] {
+        &self.layout.channels
+    }
+
+    /// Amplifier gain of each channel in the file
+    pub fn gains(&self) -> &[f32] {
+        &self.gains
+    }
+
+    /// Samples per second on each channel
+    pub fn rate(&self) -> f64 {
+        self.rate
+    }
+
+    /// Samples per channel in each buffer
+    pub fn buffer_len(&self) -> usize {
+        self.layout.buffer_len
+    }
+
+    /// Gaps, overlaps and clock resets between the buffers read so far
+    pub fn discontinuities(&self) -> &[Discontinuity] {
+        &self.discontinuities
+    }
+
+    /// Choose how to handle a partial trailing buffer
+    /// (`Recovery::Strict` by default)
+    pub fn recovery(mut self, recovery: Recovery) -> EegReader<R> {
+        self.records.set_recovery(recovery);
+        self
+    }
+
+    /// Problems worked around so far in `Recovery::Salvage` mode
+    pub fn warnings(&self) -> &[Warning] {
+        self.records.warnings()
+    }
+}
+
+impl<R: Read + Seek> EegReader<R> {
+    /// Restrict the reader to buffers with timestamps (in ticks of
+    /// 100us) in `[after, before)`, found by binary search. This
+    /// assumes the clock wasn't reset during the recording.
+    pub fn time_range(mut self, after: Option<u32>, before: Option<u32>)
+                      -> Result<EegReader<R>, Error> {
+        self.records.seek_time_range(after, before)?;
+        self.previous = None;
+        Ok(self)
+    }
+}
+
+impl<R: Read> Iterator for EegReader<R> {
+    type Item = Result<Eeg<f32, u32>, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let gains = &self.gains;
+        let buffer_len = self.layout.buffer_len;
+        let eeg = self.records.next_record().map(|record| {
+            let record = record?;
+            let mut channels = vec![Vec::with_capacity(buffer_len); gains.len()];
+            let samples = record[4..].chunks_exact(2).map(|b| i16::from_le_bytes([b[0], b[1]]));
+            for (i, v) in samples.enumerate() {
+                let c = i % gains.len();
+                channels[c].push(to_volts(v, gains[c]));
+            }
+            Ok(Eeg { channels, time: stream::record_timestamp(record) })
+        });
+        if let Some(Ok(eeg)) = &eeg {
+            if let Some(previous) = self.previous {
+                if let Some((kind, expected)) =
+                    check_continuity(previous, buffer_len, self.rate, eeg.time) {
+                    self.discontinuities.push(Discontinuity {
+                        kind,
+                        buffer: self.n_buffers,
+                        sample: self.n_buffers * buffer_len,
+                        expected,
+                        found: eeg.time,
+                    });
+                }
+            }
+            self.previous = Some(eeg.time);
+            self.n_buffers += 1;
+        }
+        eeg
+    }
+}
+
+/// Header for a .eeg file holding acquisition channels `channels`,
+/// in buffers of `buffer_len` samples per channel, extracted from
+/// `source`, whose header is kept as a provenance section
+pub fn eeg_header<S: AsRef<str>>(
+    args: &[S],
+    channels: &[usize],
+    buffer_len: usize,
+    source_name: &str,
+    source: &Metadata,
+) -> MetadataBuf {
+    let channel_list: Vec<String> = channels.iter().map(|c| c.to_string()).collect();
+    let mut h = MetadataBuf::for_program(args);
+    h.push_pair("File type", "Binary");
+    h.push_pair("Extraction type", "continuous data");
+    h.push_pair("Channels", channel_list.join(" "));
+    h.push_pair("Fields", eeg_schema(channels.len(), buffer_len));
+    h.push_comment("");
+    h.push_section(source_name, source);
+    h
+}
+
+/// Writes buffers of AD values to a continuous data (.eeg) file
+pub struct EegWriter<W: Write> {
+    inner: W,
+    layout: EegLayout,
+    record: Vec<u8>,
+}
+
+impl EegWriter<BufWriter<File>> {
+    pub fn create(path: &Path, header: &MetadataBuf) -> Result<EegWriter<BufWriter<File>>, Error> {
+        File::create(path)
+            .map_err(Error::from)
+            .and_then(|f| EegWriter::new(BufWriter::new(f), header))
+            .map_err(|e| e.at_path(path))
+    }
+}
+
+impl<W: Write> EegWriter<W> {
+    /// Write `header`, which must declare a .eeg record layout and
+    /// carry the acquisition settings of its channels
+    /// (see `eeg_header`)
+    pub fn new(mut inner: W, header: &MetadataBuf) -> Result<EegWriter<W>, Error> {
+        let metadata = header.as_metadata();
+        let layout = EegLayout::from_metadata(&metadata)?;
+        let schema = layout.schema();
+        stream::write_header(&mut inner, &metadata, &schema)?;
+        Ok(EegWriter { inner, layout, record: Vec::with_capacity(schema.record_size()) })
+    }
+
+    /// Write a buffer of raw AD values, one trace per channel
+    pub fn write_eeg(&mut self, eeg: &Eeg<i16, u32>) -> Result<(), Error> {
+        let n_channels = self.layout.channels.len();
+        let buffer_len = self.layout.buffer_len;
+        if eeg.n_channels() != n_channels
+            || eeg.channels.iter().any(|c| c.len() != buffer_len) {
+            return Err(Error::bad_value("data",
+                                        format!("{} channels", eeg.n_channels()),
+                                        format!("{} channels of {} samples", n_channels, buffer_len)));
+        }
+        let mut samples = Vec::with_capacity(n_channels * buffer_len);
+        for i in 0..buffer_len {
+            samples.extend(eeg.channels.iter().map(|c| c[i]));
+        }
+        self.write_record(eeg.time, &samples)
+    }
+
+    /// Write a buffer from its raw timestamp and interleaved AD values
+    pub fn write_record(&mut self, timestamp: u32, samples: &[i16]) -> Result<(), Error> {
+        let n_values = self.layout.channels.len() * self.layout.buffer_len;
+        if samples.len() != n_values {
+            return Err(Error::bad_value("data", format!("{} samples", samples.len()),
+                                        format!("{} samples", n_values)));
+        }
+        self.record.clear();
+        self.record.extend_from_slice(&timestamp.to_le_bytes());
+        for v in samples {
+            self.record.extend_from_slice(&v.to_le_bytes());
+        }
+        self.inner.write_all(&self.record)?;
+        Ok(())
+    }
+
+    /// Flush and return the underlying writer
+    pub fn into_inner(mut self) -> Result<W, Error> {
+        self.inner.flush()?;
+        Ok(self.inner)
+    }
+}
+
+/// The acquisition channels and buffer length of a .eeg file, which
+/// together fix its record layout
+#[derive(Clone, Debug)]
+struct EegLayout {
+    channels: Vec<usize>,
+    buffer_len: usize,
+}
+
+impl EegLayout {
+    /// Take the channels from the `Channels` line, or all channels of
+    /// the acquisition header if there is none, and the buffer length
+    /// from the `Fields` line
+    fn from_metadata(metadata: &Metadata) -> Result<EegLayout, Error> {
+        let channels = match header::tree(metadata).lookup("Channels") {
+            Some(list) => list
+                .split_whitespace()
+                .map(|c| c.parse::<usize>())
+                .collect::<Result<Vec<usize>, _>>()
+                .map_err(|_| Error::bad_value("Channels", list,
+                                              "a list of channel numbers"))?,
+            None => (0..AdAcquisitionHeader::from_metadata(metadata)?.nchannels).collect(),
+        };
+        let schema = RecordSchema::from_metadata(metadata)?;
+        let count = schema.field("data").map_or(0, |f| f.count);
+        if channels.is_empty() || count == 0 || count % channels.len() != 0 {
+            return Err(Error::bad_value("Fields", schema.to_string(),
+                                        format!("data of {} interleaved channels", channels.len())));
+        }
+        Ok(EegLayout { buffer_len: count / channels.len(), channels })
+    }
+
+    fn schema(&self) -> RecordSchema {
+        eeg_schema(self.channels.len(), self.buffer_len)
+    }
+}
+
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mwl_ad::raw::RawContinuousReader;
+    use crate::mwl_ad::raw::tests::continuous_file;
+    use crate::lfp::DiscontinuityKind;
+
+    #[test]
+    fn it_writes_selected_channels() {
+        let raw = continuous_file(2);
+        let reader = RawContinuousReader::new(raw.as_slice()).unwrap();
+        let header = eeg_header(&["xcrust-eegextract"], &[1], reader.buffer_len(),
+                                "x.eeg.raw", &reader.header().as_metadata());
+        let mut writer = EegWriter::new(Vec::new(), &header).unwrap();
+        for buffer in reader {
+            let mut eeg = buffer.unwrap().split(2);
+            eeg.channels.remove(0);
+            writer.write_eeg(&eeg).unwrap();
+        }
+        let bytes = writer.into_inner().unwrap();
+
+        let (m, data) = header::parse(&bytes).unwrap();
+        assert_eq!(header::tree(&m).lookup("Channels"), Some("1"));
+        assert_eq!(RecordSchema::from_metadata(&m).unwrap(), eeg_schema(1, 4));
+        let mut expected = Vec::new();
+        for (t, first) in &[(0u32, 1i16), (20, 9)] {
+            expected.extend_from_slice(&t.to_le_bytes());
+            for i in 0..4 {
+                expected.extend_from_slice(&(first + 2 * i).to_le_bytes());
+            }
+        }
+        assert_eq!(data, expected.as_slice());
+    }
+
+    #[test]
+    fn it_reads_written_buffers_in_volts() {
+        let raw = continuous_file(3);
+        let reader = RawContinuousReader::new(raw.as_slice()).unwrap().start_timestamp(100);
+        let header = eeg_header(&["xcrust-eegextract"], &[0, 1], reader.buffer_len(),
+                                "x.eeg.raw", &reader.header().as_metadata());
+        let mut writer = EegWriter::new(Vec::new(), &header).unwrap();
+        for buffer in reader {
+            writer.write_eeg(&buffer.unwrap().split(2)).unwrap();
+        }
+        let bytes = writer.into_inner().unwrap();
+
+        let reader = EegReader::new(bytes.as_slice()).unwrap();
+        assert_eq!(reader.rate(), 2000.0);
+        assert_eq!(reader.gains(), &[1000.0, 2000.0]);
+        let mut signal = ContinuousSignal::new(reader.rate(), reader.gains().to_vec());
+        for eeg in reader {
+            signal.push(&eeg.unwrap()).unwrap();
+        }
+        assert_eq!(signal.len(), 12);
+        assert_eq!(signal.channels[1][5], to_volts(11, 2000.0));
+        assert_eq!(signal.start_time, 0.01);
+        assert_eq!(signal.time(5), 0.0125);
+        assert_eq!(signal.buffers, vec![(0, 100), (4, 120), (8, 140)]);
+    }
+
+    #[test]
+    fn it_reports_discontinuities_while_reading() {
+        let raw = continuous_file(0);
+        let reader = RawContinuousReader::new(raw.as_slice()).unwrap();
+        let header = eeg_header(&["xcrust-eegextract"], &[0], 4,
+                                "x.eeg.raw", &reader.header().as_metadata());
+        let mut writer = EegWriter::new(Vec::new(), &header).unwrap();
+        for t in &[100, 120, 160, 10] {
+            writer.write_record(*t, &[0; 4]).unwrap();
+        }
+        let bytes = writer.into_inner().unwrap();
+
+        let mut reader = EegReader::new(bytes.as_slice()).unwrap();
+        assert_eq!(reader.by_ref().count(), 4);
+        let d: Vec<(DiscontinuityKind, usize, u32)> = reader
+            .discontinuities()
+            .iter()
+            .map(|d| (d.kind, d.sample, d.expected))
+            .collect();
+        assert_eq!(d, vec![(DiscontinuityKind::Gap, 8, 140),
+                           (DiscontinuityKind::Reset, 12, 180)]);
+    }
+
+    #[test]
+    fn it_rejects_mismatched_buffers() {
+        let raw = continuous_file(0);
+        let reader = RawContinuousReader::new(raw.as_slice()).unwrap();
+        let header = eeg_header(&["xcrust-eegextract"], &[0, 1], 4,
+                                "x.eeg.raw", &reader.header().as_metadata());
+        let mut writer = EegWriter::new(Vec::new(), &header).unwrap();
+        let eeg = Eeg { channels: vec![vec![0; 3]; 2], time: 0 };
+        assert!(writer.write_eeg(&eeg).is_err());
+    }
+}
@@ -1,8 +1,14 @@
-#[macro_use] extern crate num;
+extern crate num;
 #[macro_use] extern crate num_derive;
-#[macro_use] extern crate failure;
+extern crate failure;
 
 
+pub mod config;
+pub mod error;
+pub mod interval;
+pub mod lfp;
 pub mod pos;
 pub mod spike;
 pub mod mwl_ad;
+
+pub use error::{ConfigError, Error};
@@ -0,0 +1,178 @@
+use std::str::FromStr;
+
+use super::header::{self, HeaderError, Metadata, Section};
+
+/// Acquisition mode of the AD system
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum AdMode {
+    Spike,
+    Continuous,
+}
+
+/// Amplifier and display settings for one AD channel
+#[derive(Clone, Debug, PartialEq)]
+pub struct ChannelSettings {
+    pub ampgain: f32,
+    pub adgain: i32,
+    pub filter: i32,
+    pub threshold: i32,
+    pub color: i32,
+    pub offset: i32,
+    pub contscale: i32,
+}
+
+/// The header written by the AD acquisition system at the top of
+/// every raw recording. Extracted files carry a copy of it as an
+/// embedded section (see `header::tree`).
+#[derive(Clone, Debug, PartialEq)]
+pub struct AdAcquisitionHeader {
+    pub mode: AdMode,
+    pub adversion: String,
+    /// Sampling rate, in Hz, summed over all channels
+    pub rate: f64,
+    pub nelectrodes: usize,
+    pub nchannels: usize,
+    pub nelect_chan: usize,
+    pub spikelen: usize,
+    pub spikesep: usize,
+    pub dma_bufsize: usize,
+    /// Settings of channels `0..nchannels`, in order
+    pub channels: Vec<ChannelSettings>,
+}
+
+impl AdAcquisitionHeader {
+    /// Read the acquisition header from the first section of
+    /// `metadata` (outermost or embedded) that has an `adversion` key
+    pub fn from_metadata(metadata: &Metadata) -> Result<AdAcquisitionHeader, HeaderError> {
+        let tree = header::tree(metadata);
+        let section = tree
+            .find_key("adversion")
+            .ok_or_else(|| HeaderError::UnknownKey { key: "adversion".to_owned() })?;
+        AdAcquisitionHeader::from_section(section)
+    }
+
+    /// Read the acquisition header from the own lines of `section`
+    pub fn from_section(section: &Section) -> Result<AdAcquisitionHeader, HeaderError> {
+        let mode = match section.require("mode")? {
+            "SPIKE" => AdMode::Spike,
+            "CONTINUOUS" => AdMode::Continuous,
+            other => return Err(HeaderError::BadValue {
+                key: "mode".to_owned(),
+                value: other.to_owned(),
+                expected: "SPIKE or CONTINUOUS".to_owned(),
+            }),
+        };
+        let nchannels = value(section, "nchannels")?;
+        let channels = (0..nchannels)
+            .map(|i| channel_settings(section, i))
+            .collect::<Result<Vec<ChannelSettings>, HeaderError>>()?;
+        Ok(AdAcquisitionHeader {
+            mode,
+            adversion: section.require("adversion")?.to_owned(),
+            rate: value(section, "rate")?,
+            nelectrodes: value(section, "nelectrodes")?,
+            nchannels,
+            nelect_chan: value(section, "nelect_chan")?,
+            spikelen: value(section, "spikelen")?,
+            spikesep: value(section, "spikesep")?,
+            dma_bufsize: value(section, "dma_bufsize")?,
+            channels,
+        })
+    }
+
+    /// Settings of the `nelect_chan` channels recorded by `probe`
+    pub fn probe_channels(&self, probe: usize) -> Result<&[ChannelSettings], HeaderError> {
+        let range = probe
+            .checked_mul(self.nelect_chan)
+            .and_then(|first| Some(first..first.checked_add(self.nelect_chan)?));
+        match range {
+            Some(range) if range.end <= self.channels.len() => Ok(&self.channels[range]),
+            _ => Err(HeaderError::BadValue {
+                key: "Probe".to_owned(),
+                value: probe.to_string(),
+                expected: format!("a probe below {}",
+                                  self.channels.len() / self.nelect_chan.max(1)),
+            }),
+        }
+    }
+}
+
+fn channel_settings(section: &Section, channel: usize) -> Result<ChannelSettings, HeaderError> {
+    let key = |name| format!("channel {} {}", channel, name);
+    Ok(ChannelSettings {
+        ampgain: value(section, &key("ampgain"))?,
+        adgain: value(section, &key("adgain"))?,
+        filter: value(section, &key("filter"))?,
+        threshold: value(section, &key("threshold"))?,
+        color: value(section, &key("color"))?,
+        offset: value(section, &key("offset"))?,
+        contscale: value(section, &key("contscale"))?,
+    })
+}
+
+/// Look up `key` and parse it as a `T`
+pub fn value<T: FromStr>(section: &Section, key: &str) -> Result<T, HeaderError> {
+    let v = section.require(key)?;
+    v.parse::<T>().map_err(|_| HeaderError::BadValue {
+        key: key.to_owned(),
+        value: v.to_owned(),
+        expected: format!("a value of type {}", std::any::type_name::<T>()),
+    })
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mwl_ad::header::MetadataBuf;
+
+    fn acquisition_header(nchannels: &str) -> MetadataBuf {
+        let mut m = MetadataBuf::new()
+            .pair("mode", "SPIKE")
+            .pair("adversion", "1.36b")
+            .pair("rate", "250000.000000")
+            .pair("nelectrodes", 2)
+            .pair("nchannels", nchannels)
+            .pair("nelect_chan", 4)
+            .pair("dma_bufsize", 24576)
+            .pair("spikelen", 32)
+            .pair("spikesep", 26);
+        for c in 0..8 {
+            for (k, v) in &[("ampgain", 24994), ("adgain", 0), ("filter", 200),
+                            ("threshold", 425), ("color", 15 - c), ("offset", 65),
+                            ("contscale", 0)] {
+                m.push_pair(format!("channel {} {}", c, k), v);
+            }
+        }
+        m
+    }
+
+    #[test]
+    fn it_reads_embedded_acquisition_header() {
+        let mut m = MetadataBuf::new().pair("Probe", 1).pair("rate", "bogus");
+        m.push_section("x.spk.raw", &acquisition_header("8").as_metadata());
+        let h = AdAcquisitionHeader::from_metadata(&m.as_metadata()).unwrap();
+        assert_eq!(h.mode, AdMode::Spike);
+        assert_eq!(h.rate, 250_000.0);
+        assert_eq!(h.channels.len(), 8);
+        let probe1 = h.probe_channels(1).unwrap();
+        assert_eq!(probe1.len(), 4);
+        assert_eq!(probe1[0].color, 11);
+        assert!(h.probe_channels(2).is_err());
+        assert!(h.probe_channels(usize::MAX).is_err());
+    }
+
+    #[test]
+    fn it_reports_malformed_values() {
+        let m = acquisition_header("eight");
+        assert_eq!(AdAcquisitionHeader::from_metadata(&m.as_metadata()),
+                   Err(HeaderError::BadValue {
+                       key: "nchannels".to_owned(),
+                       value: "eight".to_owned(),
+                       expected: "a value of type usize".to_owned(),
+                   }));
+        let m = MetadataBuf::new().pair("adversion", "1.36b").pair("mode", "SPIKE");
+        assert_eq!(AdAcquisitionHeader::from_metadata(&m.as_metadata()),
+                   Err(HeaderError::UnknownKey { key: "nchannels".to_owned() }));
+    }
+}
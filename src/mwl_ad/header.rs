@@ -1,4 +1,9 @@
+use std::fmt;
+use std::io;
+use std::io::{BufRead, Write};
 use std::str;
+use chrono::{DateTime, Local};
+use failure::Fail;
 use nom::{ IResult };
 use nom;
 use nom::bytes::complete as noms;
@@ -18,33 +23,77 @@ use nom::sequence;
 #[derive (Clone, Debug, PartialEq)]
 pub enum HeaderError {
     UnknownKey { key: String },
-    ParseError { err: String },
+    /// `offset` is the byte offset of the line that failed to parse
+    ParseError { err: String, offset: usize },
+    BadValue { key: String, value: String, expected: String },
 }
 
+impl fmt::Display for HeaderError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            HeaderError::UnknownKey { key } => write!(f, "missing header key: {:?}", key),
+            HeaderError::ParseError { err, .. } => write!(f, "{}", err),
+            HeaderError::BadValue { key, value, expected } =>
+                write!(f, "header key {:?} has value {:?}, expected {}", key, value, expected),
+        }
+    }
+}
+
+impl Fail for HeaderError {}
+
 /// Abstract metadata collection derived from file header
+#[derive (Clone, Debug, PartialEq)]
 pub struct Metadata <'a> {
     pub header: Vec<HeaderLine<'a>>
 }
 
 /// Parse Metadata and get a pointer to the file's binary data
-pub fn parse(file_contents: &[u8]) -> Result<(Metadata, &[u8]), HeaderError> {
+pub fn parse(file_contents: &[u8]) -> Result<(Metadata<'_>, &[u8]), HeaderError> {
     match parse_header(file_contents) {
         Ok ((file_data, lines)) =>
             Ok ((Metadata { header: lines }, file_data)),
-        Err (e) =>
+        Err (nom::Err::Error((rest, kind))) | Err (nom::Err::Failure((rest, kind))) =>
+            Err (HeaderError::ParseError {
+                err: format!("header parse error: {:?}", kind),
+                offset: file_contents.len() - rest.len(),
+            }),
+        Err (nom::Err::Incomplete(_)) =>
             Err (HeaderError::ParseError {
-                err: format!("header parse error: {:?}", e)
+                err: "header parse error: incomplete header".to_owned(),
+                offset: file_contents.len(),
             }),
     }
 }
 
+/// Read the raw bytes of a header from the start of a stream, up to
+/// and including the `%%ENDHEADER` line, leaving `r` positioned at
+/// the first byte of binary data
+pub fn read_bytes<R: BufRead>(r: &mut R) -> io::Result<Vec<u8>> {
+    let mut buffer = Vec::new();
+    r.read_until(b'\n', &mut buffer)?;
+    if buffer != b"%%BEGINHEADER\n" {
+        return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                  "stream does not start with %%BEGINHEADER"));
+    }
+    loop {
+        let line_start = buffer.len();
+        if r.read_until(b'\n', &mut buffer)? == 0 {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof,
+                                      "stream ended before %%ENDHEADER"));
+        }
+        if &buffer[line_start..] == b"%%ENDHEADER\n" {
+            return Ok(buffer);
+        }
+    }
+}
+
 /// Find the value for the first occurrance of `key` in the metadata
-pub fn lookup<'a, 'b>(metadata: &Metadata<'a>, key: &'b str) -> Option<&'a str> {
+pub fn lookup<'a>(metadata: &Metadata<'a>, key: &str) -> Option<&'a str> {
     metadata
         .header
         .iter()
         .filter_map(|hl| match hl {
-            HeaderLine::HeaderPair { key: k , value: v }
+            HeaderLine::HeaderPair { key: k , value: v, .. }
             if k == &key => Some (v.to_owned()),
             _ => None,
         })
@@ -54,27 +103,315 @@ pub fn lookup<'a, 'b>(metadata: &Metadata<'a>, key: &'b str) -> Option<&'a str>
 
 /// Find the value of the first occurrance of `key` in metadata,
 /// returning an error if `key` is missing
-pub fn require<'a, 'b>(metadata: &Metadata<'a>, key: &'b str) -> Result<&'a str, HeaderError> {
+pub fn require<'a>(metadata: &Metadata<'a>, key: &str) -> Result<&'a str, HeaderError> {
     lookup(metadata, key)
-        .map(|v| Ok(v))
+        .map(Ok)
         .unwrap_or( Err (HeaderError::UnknownKey { key: key.to_owned() }) )
 }
 
 
 /// Find all values for `key` in metadata
-pub fn lookup_multiple<'a, 'b>(metadata: Metadata<'a>, key: &'b str) -> Vec<&'a str> {
+pub fn lookup_multiple<'a>(metadata: Metadata<'a>, key: &str) -> Vec<&'a str> {
     metadata
         .header
         .into_iter()
         .filter_map(|hl| match hl {
-            HeaderLine::HeaderPair { key: k, value: v} if k == key => Some (v),
+            HeaderLine::HeaderPair { key: k, value: v, .. } if k == key => Some (v),
             _ => None
         })
         .collect()
 }
 
 
-pub fn parse_header(s : &[u8]) -> IResult<&[u8], Vec<HeaderLine>> {
+/// One level of the provenance tree of a header. Tools like adextract
+/// copy the header of their input file into their output, between
+/// `Beginning of header from input file '...'` and
+/// `End of header from input file '...'` comments. Each copied header
+/// becomes a child `Section`, so keys of the outer and embedded
+/// headers can be looked up separately.
+#[derive (Clone, Debug, PartialEq)]
+pub struct Section<'a> {
+    /// The input file the section was copied from,
+    /// `None` for the outermost header
+    pub source: Option<&'a str>,
+    /// The section's own lines, excluding those of nested sections
+    pub lines: Vec<HeaderLine<'a>>,
+    pub children: Vec<Section<'a>>,
+}
+
+const SECTION_BEGIN: &str = "Beginning of header from input file ";
+const SECTION_END: &str = "End of header from input file ";
+
+/// Build the tree of provenance sections in `metadata`. An unmatched
+/// `End of header` comment is kept as a plain comment, and a section
+/// that is never closed ends with the header.
+pub fn tree<'a>(metadata: &Metadata<'a>) -> Section<'a> {
+    let new_section = |source| Section { source, lines: Vec::new(), children: Vec::new() };
+    let mut stack = vec![new_section(None)];
+    for line in metadata.header.iter() {
+        match *line {
+            HeaderLine::HeaderComment { comment } if comment.starts_with(SECTION_BEGIN) =>
+                stack.push(new_section(Some(section_source(&comment[SECTION_BEGIN.len()..])))),
+            HeaderLine::HeaderComment { comment }
+            if comment.starts_with(SECTION_END) && stack.len() > 1 => {
+                let done = stack.pop().unwrap();
+                stack.last_mut().unwrap().children.push(done);
+            },
+            _ => stack.last_mut().unwrap().lines.push(*line),
+        }
+    }
+    while stack.len() > 1 {
+        let done = stack.pop().unwrap();
+        stack.last_mut().unwrap().children.push(done);
+    }
+    stack.pop().unwrap()
+}
+
+// "'data/original.spk.raw'" -> "data/original.spk.raw"
+fn section_source(quoted: &str) -> &str {
+    let s = quoted.trim();
+    s.strip_prefix('\'')
+        .and_then(|s| s.strip_suffix('\''))
+        .unwrap_or(s)
+}
+
+impl<'a> Section<'a> {
+    /// The section's own lines as a flat `Metadata`,
+    /// for use with `lookup`, `require` and friends
+    pub fn metadata(&self) -> Metadata<'a> {
+        Metadata { header: self.lines.clone() }
+    }
+
+    /// Find `key` among this section's own lines only
+    pub fn lookup(&self, key: &str) -> Option<&'a str> {
+        lookup(&self.metadata(), key)
+    }
+
+    pub fn require(&self, key: &str) -> Result<&'a str, HeaderError> {
+        require(&self.metadata(), key)
+    }
+
+    /// The direct child section copied from input file `source`
+    pub fn child(&self, source: &str) -> Option<&Section<'a>> {
+        self.children.iter().find(|c| c.source == Some(source))
+    }
+
+    /// This section and all nested sections, depth-first
+    pub fn sections(&self) -> Vec<&Section<'a>> {
+        let mut sections = vec![self];
+        for c in self.children.iter() {
+            sections.extend(c.sections());
+        }
+        sections
+    }
+
+    /// The first section, depth-first starting with this one,
+    /// that defines `key` itself
+    pub fn find_key(&self, key: &str) -> Option<&Section<'a>> {
+        self.sections().into_iter().find(|s| s.lookup(key).is_some())
+    }
+}
+
+
+/// Write `metadata` as a `%%BEGINHEADER ... %%ENDHEADER` block.
+/// Pairs keep the separator and trailing whitespace they were parsed
+/// with, so a header read from a file is written back byte for byte;
+/// pairs built with `MetadataBuf` are written as `% key: \tvalue`.
+pub fn write<W: Write>(metadata: &Metadata, w: &mut W) -> io::Result<()> {
+    w.write_all(b"%%BEGINHEADER\n")?;
+    for line in metadata.header.iter() {
+        write_line(line, w)?;
+        w.write_all(b"\n")?;
+    }
+    if metadata.header.is_empty() {
+        w.write_all(b"\n")?;
+    }
+    w.write_all(b"%%ENDHEADER\n")
+}
+
+/// Serialize `metadata` into a new byte buffer (see `write`)
+pub fn to_bytes(metadata: &Metadata) -> io::Result<Vec<u8>> {
+    let mut buffer = Vec::new();
+    write(metadata, &mut buffer)?;
+    Ok(buffer)
+}
+
+// Lines that would not parse back to themselves are rejected,
+// rather than silently producing a different header
+fn write_line<W: Write>(line: &HeaderLine, w: &mut W) -> io::Result<()> {
+    let invalid = |msg: String| io::Error::new(io::ErrorKind::InvalidInput, msg);
+    match *line {
+        HeaderLine::HeaderPair { key, separator, value, trailing } => {
+            if key.trim().is_empty() || key.contains(':') || key.contains('\n') {
+                return Err(invalid(format!("invalid header key: {:?}", key)));
+            }
+            if value.contains('\n') {
+                return Err(invalid(format!("newline in value for header key {:?}", key)));
+            }
+            let (before, after) = separator.split_once(':').unwrap_or((separator, ""));
+            let blank = |s: &str| s.chars().all(|c| c.is_whitespace() && c != '\n');
+            if !separator.contains(':') || !blank(before) || !blank(after) || !blank(trailing)
+                || (value.trim().is_empty() && after.is_empty()) {
+                return Err(invalid(format!("invalid separator for header key {:?}: {:?}",
+                                           key, separator)));
+            }
+            write!(w, "% {}{}{}{}", key.trim(), separator, value.trim(), trailing)
+        },
+        HeaderLine::HeaderComment { comment: "" } => w.write_all(b"%"),
+        HeaderLine::HeaderComment { comment } => {
+            let looks_like_pair = comment
+                .find(':')
+                .is_some_and(|i| i > 0 && i + 1 < comment.len());
+            if comment.contains('\n') || looks_like_pair {
+                return Err(invalid(format!("header comment would not round-trip: {:?}",
+                                           comment)));
+            }
+            write!(w, "% {}", comment)
+        },
+    }
+}
+
+
+/// Owned counterpart of `HeaderLine`
+#[derive (Clone, Debug, PartialEq)]
+pub enum HeaderLineBuf {
+    HeaderPair { key: String, separator: String, value: String, trailing: String },
+    HeaderComment { comment: String },
+}
+
+impl HeaderLineBuf {
+    pub fn as_line(&self) -> HeaderLine<'_> {
+        match self {
+            HeaderLineBuf::HeaderPair { key, separator, value, trailing } =>
+                HeaderLine::HeaderPair { key, separator, value, trailing },
+            HeaderLineBuf::HeaderComment { comment } =>
+                HeaderLine::HeaderComment { comment },
+        }
+    }
+}
+
+impl<'a> From<&HeaderLine<'a>> for HeaderLineBuf {
+    fn from(line: &HeaderLine<'a>) -> HeaderLineBuf {
+        match *line {
+            HeaderLine::HeaderPair { key, separator, value, trailing } =>
+                HeaderLineBuf::HeaderPair {
+                    key: key.to_owned(),
+                    separator: separator.to_owned(),
+                    value: value.to_owned(),
+                    trailing: trailing.to_owned(),
+                },
+            HeaderLine::HeaderComment { comment } =>
+                HeaderLineBuf::HeaderComment { comment: comment.to_owned() },
+        }
+    }
+}
+
+/// Owned, growable `Metadata`, for tools that write out derived files.
+/// Lines are kept in insertion order.
+///
+/// ```
+/// use xcrust::mwl_ad::header::{self, MetadataBuf};
+/// let m = MetadataBuf::new()
+///     .pair("Program", "xcrust-extract")
+///     .comment("")
+///     .pair("File type", "Binary");
+/// assert_eq!(header::lookup(&m.as_metadata(), "File type"), Some("Binary"));
+/// ```
+#[derive (Clone, Debug, Default, PartialEq)]
+pub struct MetadataBuf {
+    pub header: Vec<HeaderLineBuf>,
+}
+
+impl MetadataBuf {
+    pub fn new() -> MetadataBuf {
+        MetadataBuf { header: Vec::new() }
+    }
+
+    /// Append a `key: value` pair
+    pub fn pair<K: Into<String>, V: ToString>(mut self, key: K, value: V) -> MetadataBuf {
+        self.push_pair(key, value);
+        self
+    }
+
+    /// Append a comment line
+    pub fn comment<C: Into<String>>(mut self, comment: C) -> MetadataBuf {
+        self.push_comment(comment);
+        self
+    }
+
+    pub fn push_pair<K: Into<String>, V: ToString>(&mut self, key: K, value: V) {
+        self.header.push(HeaderLineBuf::HeaderPair {
+            key: key.into(),
+            separator: PAIR_SEPARATOR.to_owned(),
+            value: value.to_string(),
+            trailing: String::new(),
+        });
+    }
+
+    pub fn push_comment<C: Into<String>>(&mut self, comment: C) {
+        self.header.push(HeaderLineBuf::HeaderComment { comment: comment.into() });
+    }
+
+    /// Replace the value of the first occurrance of `key`,
+    /// or append the pair if `key` is not present yet
+    pub fn set<K: Into<String>, V: ToString>(&mut self, key: K, value: V) {
+        let key = key.into();
+        let existing = self.header.iter_mut().find_map(|hl| match hl {
+            HeaderLineBuf::HeaderPair { key: k, value: v, .. } if *k == key => Some(v),
+            _ => None,
+        });
+        match existing {
+            Some(v) => *v = value.to_string(),
+            None => self.push_pair(key, value),
+        }
+    }
+
+    /// Append the `Program`, `Argc`, `Argv[n]` and `Date` keys that
+    /// mwsoft tools write at the top of every derived file.
+    /// `args` is the full command line, including the program name.
+    pub fn push_invocation<S: AsRef<str>>(&mut self, args: &[S], date: DateTime<Local>) {
+        if let Some(program) = args.first() {
+            self.push_pair("Program", program.as_ref());
+        }
+        self.push_pair("Argc", args.len());
+        for (i, arg) in args.iter().enumerate().skip(1) {
+            self.push_pair(format!("Argv[{}]", i), arg.as_ref());
+        }
+        self.push_pair("Date", date.format("%a %b %e %H:%M:%S %Y"));
+    }
+
+    /// Start the header of a file written by the running program,
+    /// with its invocation stamped with the current time
+    pub fn for_program<S: AsRef<str>>(args: &[S]) -> MetadataBuf {
+        let mut m = MetadataBuf::new();
+        m.push_invocation(args, Local::now());
+        m.push_pair("xcrust version", env!("CARGO_PKG_VERSION"));
+        m
+    }
+
+    /// Append a copy of an input file's header, wrapped in the
+    /// `Beginning of header`/`End of header` comments that `tree`
+    /// recognizes as a provenance section
+    pub fn push_section(&mut self, source: &str, metadata: &Metadata) {
+        self.push_comment(format!("{}'{}'", SECTION_BEGIN, source));
+        self.header.extend(metadata.header.iter().map(HeaderLineBuf::from));
+        self.push_comment(format!("{}'{}'", SECTION_END, source));
+    }
+
+    /// Borrow as a `Metadata`, to use `lookup`, `require` and `write`
+    pub fn as_metadata(&self) -> Metadata<'_> {
+        Metadata { header: self.header.iter().map(HeaderLineBuf::as_line).collect() }
+    }
+}
+
+impl<'a> From<&Metadata<'a>> for MetadataBuf {
+    fn from(metadata: &Metadata<'a>) -> MetadataBuf {
+        MetadataBuf { header: metadata.header.iter().map(HeaderLineBuf::from).collect() }
+    }
+}
+
+
+pub fn parse_header(s : &[u8]) -> IResult<&[u8], Vec<HeaderLine<'_>>> {
     delimited( noms::tag("%%BEGINHEADER\n"),
                separated_list( noms::tag("\n"), header_line ),
                noms::tag("\n%%ENDHEADER\n")
@@ -84,12 +421,19 @@ pub fn parse_header(s : &[u8]) -> IResult<&[u8], Vec<HeaderLine>> {
 
 #[derive (Clone, Copy, Debug, PartialEq)]
 pub enum HeaderLine <'a> {
-    HeaderPair { key: & 'a str, value: & 'a str },
+    /// `separator` is the text between the key and the value, such as
+    /// `": \t"` or `" :\t"`, and `trailing` the whitespace after the
+    /// value, both kept so that the line can be written back as read
+    HeaderPair { key: & 'a str, separator: & 'a str, value: & 'a str, trailing: & 'a str },
     HeaderComment { comment: & 'a str },
 }
 
+/// The separator of pairs built with `MetadataBuf`, as adextract
+/// writes them
+pub const PAIR_SEPARATOR: &str = ": \t";
+
 
-fn header_line(line: &[u8]) -> IResult<&[u8], HeaderLine> {
+fn header_line(line: &[u8]) -> IResult<&[u8], HeaderLine<'_>> {
     branch::alt(
         (sequence::preceded( noms::tag(b"% "), header_pair ) ,
          header_comment)
@@ -97,17 +441,32 @@ fn header_line(line: &[u8]) -> IResult<&[u8], HeaderLine> {
 }
 
 // Parses like this:
-// "key : value" -> HeaderPair { key: "key", value: "value" }
-fn header_pair(line: &[u8]) -> IResult<&[u8], HeaderLine> {
-    combinator::map(
-        sequence::separated_pair(
-            noms::take_while1(|ch| ch != b':' && ch != b'\n'),
-            noms::tag(":"),
-            noms::take_while1(|ch| ch != b'\n')
+// "key : value" -> HeaderPair { key: "key", separator: " : ", value: "value", trailing: "" }
+fn header_pair(line: &[u8]) -> IResult<&[u8], HeaderLine<'_>> {
+    combinator::map_res(
+        combinator::recognize(
+            sequence::separated_pair(
+                noms::take_while1(|ch| ch != b':' && ch != b'\n'),
+                noms::tag(":"),
+                noms::take_while1(|ch| ch != b'\n')
+            )
         ),
-        |(k,v) : (&[u8], &[u8])| HeaderLine::HeaderPair {
-            key: str::from_utf8(k).unwrap().trim(),
-            value: str::from_utf8(v).unwrap().trim()
+        |pair : &[u8]| -> Result<HeaderLine, str::Utf8Error> {
+            let pair = str::from_utf8(pair)?;
+            let colon = pair.find(':').unwrap_or(0);
+            let key = pair[..colon].trim();
+            let rest = pair[colon + 1..].trim_start();
+            let value = rest.trim_end();
+            // Slice the separator and trailing whitespace out of the
+            // line, between the trimmed key and value
+            let key_end = pair[..colon].trim_end().len();
+            let value_start = pair.len() - rest.len();
+            Ok (HeaderLine::HeaderPair {
+                key,
+                separator: &pair[key_end..value_start],
+                value,
+                trailing: &rest[value.len()..],
+            })
         }
     )(line)
 }
@@ -117,11 +476,11 @@ fn header_pair(line: &[u8]) -> IResult<&[u8], HeaderLine> {
 // "%\n"          -> HeaderComment {comment: ""}
 // The ':' signifying Pair (as opposed to Comment) is handled
 // upstream of this parser, so we don't need to handle it here
-fn header_comment(line: &[u8]) -> IResult<&[u8], HeaderLine> {
+fn header_comment(line: &[u8]) -> IResult<&[u8], HeaderLine<'_>> {
     branch::alt(
-        (combinator::map(
+        (combinator::map_res(
             sequence::preceded(noms::tag(b"% "), noms::take_while(|ch| ch != b'\n')),
-            |s| HeaderLine::HeaderComment { comment: str::from_utf8(s).unwrap() }),
+            |s| str::from_utf8(s).map(|comment| HeaderLine::HeaderComment { comment })),
          combinator::value(
              HeaderLine::HeaderComment { comment: "" },
              sequence::preceded(
@@ -135,15 +494,18 @@ fn header_comment(line: &[u8]) -> IResult<&[u8], HeaderLine> {
                                   
 
 #[cfg(test)]
-mod tests {
+pub(crate) mod tests {
     use super::*;
+    use chrono::TimeZone;
 
     #[test]
     fn it_parses_header_pair() {
         assert_eq!(header_pair(b"key : value"),
                    Ok((str::as_bytes(""),
                        HeaderLine::HeaderPair{ key: "key",
-                                               value: "value" })
+                                               separator: " : ",
+                                               value: "value",
+                                               trailing: "" })
                    )
         );
     }
@@ -158,7 +520,8 @@ mod tests {
     fn it_stops_after_header_pair() {
         assert_eq!(header_line(b"% key: value\nleftover"),
                    Ok((str::as_bytes("\nleftover"),
-                       HeaderLine::HeaderPair{key: "key", value: "value"}))
+                       HeaderLine::HeaderPair{key: "key", separator: ": ", value: "value",
+                                              trailing: ""}))
         );
     }
 
@@ -175,7 +538,8 @@ mod tests {
         let r = parse_header(str::as_bytes(HEADER_FIXTURE_SMALL));
         assert_eq!(
             r.clone().map(|vs| vs.1[0]),
-            Ok (HeaderLine::HeaderPair { key: "Program", value: "./adextract"})
+            Ok (HeaderLine::HeaderPair { key: "Program", separator: ": \t",
+                                         value: "./adextract", trailing: "" })
         );
         assert_eq!(
             r.map(|vs| vs.1[1]),
@@ -189,9 +553,118 @@ mod tests {
         assert_eq!(lookup(&m, "Program"), Some("./adextract"));
         assert_eq!(require(&m, "Argc"), Ok("8"));
     }
-}
 
-const HEADER_FIXTURE_SMALL : &'static str = r#"%%BEGINHEADER
+    #[test]
+    fn it_round_trips_header() {
+        let (m, rest) = parse(str::as_bytes(HEADER_FIXTURE)).unwrap();
+        assert_eq!(rest, b"");
+        let bytes = to_bytes(&m).unwrap();
+        let (m2, rest2) = parse(&bytes).unwrap();
+        assert_eq!(rest2, b"");
+        assert_eq!(m2, m);
+        assert_eq!(to_bytes(&m2).unwrap(), bytes);
+        assert_eq!(bytes, HEADER_FIXTURE.as_bytes());
+        let copied = MetadataBuf::from(&m);
+        assert_eq!(to_bytes(&copied.as_metadata()).unwrap(), HEADER_FIXTURE.as_bytes());
+    }
+
+    #[test]
+    fn it_writes_empty_header() {
+        let m = MetadataBuf::new();
+        let bytes = to_bytes(&m.as_metadata()).unwrap();
+        assert_eq!(parse(&bytes).map(|(m2, _)| m2), Ok (m.as_metadata()));
+    }
+
+    #[test]
+    fn it_rejects_unwritable_lines() {
+        let bad_key = MetadataBuf::new().pair("a:b", "c");
+        assert!(to_bytes(&bad_key.as_metadata()).is_err());
+        let bad_comment = MetadataBuf::new().comment("looks: like a pair");
+        assert!(to_bytes(&bad_comment.as_metadata()).is_err());
+        let bad_separator = Metadata { header: vec![HeaderLine::HeaderPair {
+            key: "a", separator: " = ", value: "b", trailing: "",
+        }] };
+        assert!(to_bytes(&bad_separator).is_err());
+    }
+
+    #[test]
+    fn it_builds_invocation_header() {
+        let date = Local.with_ymd_and_hms(2012, 10, 22, 16, 49, 4).unwrap();
+        let mut m = MetadataBuf::new();
+        m.push_invocation(&["xcrust-extract", "in.spk.raw", "-o", "out.tt"], date);
+        m.set("Argc", 5);
+        let md = m.as_metadata();
+        assert_eq!(lookup(&md, "Program"), Some("xcrust-extract"));
+        assert_eq!(lookup(&md, "Argv[3]"), Some("out.tt"));
+        assert_eq!(lookup(&md, "Argc"), Some("5"));
+        assert_eq!(lookup(&md, "Date"), Some("Mon Oct 22 16:49:04 2012"));
+
+        let bytes = to_bytes(&md).unwrap();
+        let (parsed, _) = parse(&bytes).unwrap();
+        assert_eq!(MetadataBuf::from(&parsed), m);
+    }
+
+    #[test]
+    fn it_builds_section_tree() {
+        let (m, _) = parse(str::as_bytes(HEADER_FIXTURE)).unwrap();
+        let t = tree(&m);
+        assert_eq!(t.source, None);
+        assert_eq!(t.lookup("Probe"), Some("0"));
+        assert_eq!(t.lookup("rate"), None);
+        let inner = t.child("data/original.spk.raw").unwrap();
+        assert_eq!(inner.lookup("rate"), Some("250000.000000"));
+        assert_eq!(inner.lookup("Probe"), None);
+        assert_eq!(t.find_key("nchannels").map(|s| s.source), Some(Some("data/original.spk.raw")));
+    }
+
+    #[test]
+    fn it_nests_pushed_sections() {
+        let (m, _) = parse(str::as_bytes(HEADER_FIXTURE)).unwrap();
+        let mut out = MetadataBuf::new().pair("Program", "xcrust-extract");
+        out.push_section("data/original.tt", &m);
+        let md = out.as_metadata();
+        let t = tree(&md);
+        assert_eq!(t.lookup("Program"), Some("xcrust-extract"));
+        let middle = t.child("data/original.tt").unwrap();
+        assert_eq!(middle.lookup("Program"), Some("./adextract"));
+        let inner = middle.child("data/original.spk.raw").unwrap();
+        assert_eq!(inner.lookup("mode"), Some("SPIKE"));
+    }
+
+    #[test]
+    fn it_keeps_unmatched_section_ends() {
+        let m = MetadataBuf::new()
+            .comment("End of header from input file 'x'")
+            .comment("Beginning of header from input file 'y'")
+            .pair("k", "v");
+        let md = m.as_metadata();
+        let t = tree(&md);
+        assert_eq!(t.lines.len(), 1);
+        assert_eq!(t.child("y").and_then(|s| s.lookup("k")), Some("v"));
+    }
+
+    #[test]
+    fn it_reads_header_from_stream() {
+        let mut bytes = HEADER_FIXTURE_SMALL.as_bytes().to_vec();
+        bytes.extend_from_slice(&[1, 2, 3]);
+        let mut r = io::Cursor::new(bytes);
+        let header_bytes = read_bytes(&mut r).unwrap();
+        assert_eq!(header_bytes, HEADER_FIXTURE_SMALL.as_bytes());
+        assert_eq!(r.position() as usize, header_bytes.len());
+        assert!(read_bytes(&mut io::Cursor::new(b"%%BEGINHEADER\n% a: b\n")).is_err());
+    }
+
+    #[test]
+    fn it_reports_bad_header_lines() {
+        let bytes = b"%%BEGINHEADER\n% Probe: 0\n% Bad: \xff\xfe\n%%ENDHEADER\n";
+        match parse(bytes) {
+            Err (HeaderError::ParseError { offset, .. }) => assert_eq!(offset, 24),
+            r => panic!("expected a parse error, got {:?}", r.map(|(m, _)| m)),
+        }
+    }
+
+
+    const HEADER_FIXTURE_SMALL : &str = r#"%%BEGINHEADER
 % Program: 	./adextract
 % Some comment
 %
@@ -200,7 +673,8 @@ const HEADER_FIXTURE_SMALL : &'static str = r#"%%BEGINHEADER
 %%ENDHEADER
 "#;
     
-const HEADER_FIXTURE : &'static str = r#"%%BEGINHEADER
+    /// A real adextract .tt header, shared with the reader tests
+    pub(crate) const HEADER_FIXTURE : &str = r#"%%BEGINHEADER
 % Program: 	./adextract
 % Program Version: 	1.18
 % Argc: 	8
@@ -295,3 +769,4 @@ const HEADER_FIXTURE : &'static str = r#"%%BEGINHEADER
 %
 %%ENDHEADER
 "#;
+}
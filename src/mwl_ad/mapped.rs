@@ -0,0 +1,179 @@
+use std::fs::File;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+
+use memmap::Mmap;
+
+use crate::Error;
+use super::header::{self, MetadataBuf};
+use super::schema::RecordSchema;
+use super::stream;
+use super::table::ColumnType;
+
+/// A memory-mapped MWL binary file. The header is parsed once, and
+/// records are handed out as slices of the mapping, so repeated
+/// passes over a large file don't copy or decode anything up front.
+///
+/// As with any memory map, the file must not be truncated or
+/// modified by another process while it is mapped.
+pub struct MappedFile {
+    path: PathBuf,
+    map: Mmap,
+    header: MetadataBuf,
+    schema: RecordSchema,
+    data_offset: usize,
+}
+
+impl MappedFile {
+    pub fn open(path: &Path) -> Result<MappedFile, Error> {
+        MappedFile::map(path).map_err(|e| e.at_path(path))
+    }
+
+    fn map(path: &Path) -> Result<MappedFile, Error> {
+        let file = File::open(path)?;
+        let map = unsafe { Mmap::map(&file)? };
+        let (header, schema, data_offset) = {
+            let (metadata, data) = header::parse(&map)?;
+            let schema = RecordSchema::from_metadata(&metadata)?;
+            (MetadataBuf::from(&metadata), schema, map.len() - data.len())
+        };
+        Ok(MappedFile { path: path.to_owned(), map, header, schema, data_offset })
+    }
+
+    /// Like `open`, but fail unless records have the layout `expected`
+    pub fn open_with_layout(path: &Path, expected: &RecordSchema) -> Result<MappedFile, Error> {
+        let mapped = MappedFile::open(path)?;
+        mapped.schema
+            .check_layout(expected)
+            .map_err(|e| Error::from(e).at_path(path))?;
+        Ok(mapped)
+    }
+
+    pub fn header(&self) -> &MetadataBuf {
+        &self.header
+    }
+
+    pub fn schema(&self) -> &RecordSchema {
+        &self.schema
+    }
+
+    /// The binary data following the header
+    pub fn data(&self) -> &[u8] {
+        &self.map[self.data_offset..]
+    }
+
+    /// Byte offset of the first record from the start of the file
+    pub fn data_offset(&self) -> usize {
+        self.data_offset
+    }
+
+    /// Number of complete records
+    pub fn len(&self) -> usize {
+        self.schema.record_count(self.data().len())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The `i`th record, or `None` past the last complete record
+    pub fn record(&self, i: usize) -> Option<Record<'_>> {
+        if i >= self.len() {
+            return None;
+        }
+        let size = self.schema.record_size();
+        Some(Record {
+            bytes: &self.data()[i * size..(i + 1) * size],
+            schema: &self.schema,
+        })
+    }
+
+    pub fn records(&self) -> impl Iterator<Item = Record<'_>> {
+        (0..self.len()).filter_map(move |i| self.record(i))
+    }
+
+    /// Index of the first record with a timestamp at or after `t`,
+    /// assuming records start with non-decreasing u32 timestamps.
+    /// Fails if records are too small to hold a timestamp.
+    pub fn seek_timestamp(&self, t: u32) -> Result<usize, Error> {
+        stream::check_timestamped(&self.schema).map_err(|e| e.at_path(&self.path))?;
+        let size = self.schema.record_size();
+        let data = self.data();
+        let (mut low, mut high) = (0, self.len());
+        while low < high {
+            let mid = low + (high - low) / 2;
+            if stream::record_timestamp(&data[mid * size..(mid + 1) * size]) < t {
+                low = mid + 1;
+            } else {
+                high = mid;
+            }
+        }
+        Ok(low)
+    }
+
+    /// Indices of the records with timestamps in `[after, before)`
+    pub fn time_range(&self, after: Option<u32>, before: Option<u32>) -> Result<Range<usize>, Error> {
+        let start = after.map_or(Ok(0), |t| self.seek_timestamp(t))?;
+        let end = before.map_or(Ok(self.len()), |t| self.seek_timestamp(t))?;
+        Ok(start..end.max(start))
+    }
+}
+
+/// One record of a `MappedFile`, borrowed from the mapping
+#[derive(Clone, Copy, Debug)]
+pub struct Record<'a> {
+    bytes: &'a [u8],
+    schema: &'a RecordSchema,
+}
+
+impl<'a> Record<'a> {
+    pub fn bytes(&self) -> &'a [u8] {
+        self.bytes
+    }
+
+    /// The raw bytes of field `name`
+    pub fn field(&self, name: &str) -> Option<&'a [u8]> {
+        let offset = self.schema.offset(name)?;
+        let len = self.schema.field(name)?.byte_len();
+        Some(&self.bytes[offset..offset + len])
+    }
+
+    /// Decode element `i` of field `name`. Returns `None` if there is
+    /// no such field or element, or if the field is not of type `T`.
+    pub fn get<T: ColumnType>(&self, name: &str, i: usize) -> Option<T> {
+        let field = self.schema.field(name)?;
+        if !T::decodes(field) || i >= field.count {
+            return None;
+        }
+        let bytes = self.field(name)?;
+        Some(T::decode_le(&bytes[i * T::SIZE..(i + 1) * T::SIZE]))
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+    use std::process;
+
+    #[test]
+    fn it_rejects_time_seeks_without_timestamps() {
+        let path = env::temp_dir().join(format!("xcrust-{}-mapped-short.dat", process::id()));
+        let mut bytes = b"%%BEGINHEADER\n% File type: \tBinary\n% Fields: \tx,2,2,1\n%%ENDHEADER\n".to_vec();
+        bytes.extend_from_slice(&[1, 0, 2, 0, 3, 0]);
+        fs::write(&path, bytes).unwrap();
+        let mapped = MappedFile::open(&path).unwrap();
+        assert_eq!(mapped.len(), 3);
+        assert_eq!(mapped.time_range(None, None).unwrap(), 0..3);
+        match mapped.time_range(Some(1), None) {
+            Err(Error::BadValue { path: Some(p), key, .. }) => {
+                assert_eq!(p, path);
+                assert_eq!(key, "Fields");
+            },
+            r => panic!("expected a bad value, got {:?}", r),
+        }
+        fs::remove_file(path).unwrap();
+    }
+}
@@ -1,6 +1,16 @@
+use std::fmt;
+use chrono::Duration;
+use failure::Fail;
+
+pub mod acquisition;
 pub mod header;
+pub mod mapped;
+pub mod raw;
+pub mod schema;
+pub mod stream;
+pub mod table;
 
-#[derive(Debug, FromPrimitive, PartialEq, ToPrimitive)]
+#[derive(Clone, Copy, Debug, FromPrimitive, PartialEq, ToPrimitive)]
 pub enum FormatType {
     InvalidT = 0,
     CharT    = 1, // u8
@@ -14,12 +24,44 @@ pub enum FormatType {
     UnknownT = -1,
 }
 
-#[derive(Debug,Fail, PartialEq)]
+#[derive(Debug, PartialEq)]
 pub enum DecodingError {
-    #[fail(display = "invalid format code: {}", code)]
     UnknownFormatType { code : i32 },
 }
 
+impl fmt::Display for DecodingError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DecodingError::UnknownFormatType { code } => write!(f, "invalid format code: {}", code),
+        }
+    }
+}
+
+impl Fail for DecodingError {}
+
+impl FormatType {
+    /// The numeric code used for this type in `Fields` header lines
+    pub fn code(&self) -> i32 {
+        num::ToPrimitive::to_i32(self).unwrap_or(-1)
+    }
+}
+
+/// MWL timestamps count ticks of 100us
+pub const TICKS_PER_SECOND: f64 = 10_000.0;
+
+/// Convert a raw AD sample to volts at the electrode tip, given the
+/// channel's amplifier gain (`ampgain` in the acquisition header)
+pub fn to_volts(v: i16, gain: f32) -> f32 {
+    (v as f32) / 32_768.0 * 5.0 / gain
+}
+
+/// Convert a time since the start of acquisition to an MWL timestamp,
+/// saturating at the bounds of u32
+pub fn duration_to_timestamp(d: &Duration) -> u32 {
+    let ticks = d.num_microseconds().unwrap_or(i64::MAX) / 100;
+    ticks.max(0).min(u32::MAX as i64) as u32
+}
+
 pub fn decode_type(i: i32) -> Result<FormatType,DecodingError> {
     num::FromPrimitive::from_i32(i)
         .map(Ok)
@@ -40,4 +82,11 @@ mod tests {
         assert_eq!(decode_type(9), Err (DecodingError::UnknownFormatType {code: 9} ));
     }
 
+    #[test]
+    fn duration_to_timestamp_saturates() {
+        assert_eq!(duration_to_timestamp(&Duration::milliseconds(1500)), 15_000);
+        assert_eq!(duration_to_timestamp(&Duration::seconds(-1)), 0);
+        assert_eq!(duration_to_timestamp(&Duration::days(10_000)), u32::MAX);
+    }
+
 }
@@ -0,0 +1,368 @@
+use std::fs::File;
+use std::io::{BufReader, Read};
+use std::path::Path;
+
+use crate::Error;
+use crate::lfp::Eeg;
+use super::TICKS_PER_SECOND;
+use super::acquisition::{self, AdAcquisitionHeader, AdMode};
+use super::header::{self, MetadataBuf};
+use super::stream::{RecordStream, Recovery, Warning};
+
+/// A spike as recorded by the AD system in SPIKE mode, before
+/// extraction
+#[derive(Clone, Debug, PartialEq)]
+pub struct RawSpike {
+    /// The electrode (probe) that triggered the spike
+    pub electrode: usize,
+    /// In ticks of 100us
+    pub timestamp: u32,
+    /// `spikelen` samples from each of the `nelect_chan` channels of
+    /// the electrode, interleaved
+    pub samples: Vec<i16>,
+}
+
+impl RawSpike {
+    /// The first `spike_len` samples of each of `n_channels` channels,
+    /// still interleaved
+    pub fn truncated(&self, n_channels: usize, spike_len: usize) -> &[i16] {
+        &self.samples[..(n_channels * spike_len).min(self.samples.len())]
+    }
+}
+
+/// Reads the spikes of a raw SPIKE-mode acquisition file
+/// (`.spk.raw`). Each record holds the electrode number, some
+/// padding, the timestamp, and the samples of every channel of that
+/// electrode: `int electrode_num; long timestamp; int data[128]`
+/// in the header's words, `spike_size` bytes in all.
+///
+/// Spikes of different electrodes are interleaved in the file, so
+/// timestamps aren't checked for order.
+pub struct RawSpikeReader<R> {
+    header: MetadataBuf,
+    acquisition: AdAcquisitionHeader,
+    timestamp_offset: usize,
+    records: RecordStream<BufReader<R>>,
+}
+
+impl RawSpikeReader<File> {
+    pub fn open(path: &Path) -> Result<RawSpikeReader<File>, Error> {
+        File::open(path)
+            .map_err(Error::from)
+            .and_then(RawSpikeReader::new)
+            .map(|mut r| {
+                r.records = r.records.with_path(path);
+                r
+            })
+            .map_err(|e| e.at_path(path))
+    }
+}
+
+impl<R: Read> RawSpikeReader<R> {
+    /// Parse the header of `inner`; spikes are read on iteration
+    pub fn new(inner: R) -> Result<RawSpikeReader<R>, Error> {
+        let mut inner = BufReader::new(inner);
+        let bytes = header::read_bytes(&mut inner)?;
+        let (metadata, _) = header::parse(&bytes)?;
+        let acquisition = AdAcquisitionHeader::from_metadata(&metadata)?;
+        if acquisition.mode != AdMode::Spike {
+            return Err(Error::bad_value("mode", "CONTINUOUS", "SPIKE"));
+        }
+        let spike_size: usize = acquisition::value(&header::tree(&metadata), "spike_size")?;
+        let data_len = 2 * acquisition.nelect_chan * acquisition.spikelen;
+        // The electrode number and the timestamp come before the data
+        if spike_size < data_len + 6 {
+            return Err(Error::bad_value("spike_size", spike_size.to_string(),
+                                        format!("at least {} bytes", data_len + 6)));
+        }
+        let mut records = RecordStream::new(inner, spike_size, bytes.len() as u64);
+        records.set_check_order(false);
+        Ok(RawSpikeReader {
+            header: MetadataBuf::from(&metadata),
+            acquisition,
+            timestamp_offset: spike_size - data_len - 4,
+            records,
+        })
+    }
+
+    pub fn header(&self) -> &MetadataBuf {
+        &self.header
+    }
+
+    pub fn acquisition(&self) -> &AdAcquisitionHeader {
+        &self.acquisition
+    }
+
+    /// Choose how to handle a partial trailing record
+    /// (`Recovery::Strict` by default)
+    pub fn recovery(mut self, recovery: Recovery) -> RawSpikeReader<R> {
+        self.records.set_recovery(recovery);
+        self
+    }
+
+    /// Problems worked around so far in `Recovery::Salvage` mode
+    pub fn warnings(&self) -> &[Warning] {
+        self.records.warnings()
+    }
+}
+
+impl<R: Read> Iterator for RawSpikeReader<R> {
+    type Item = Result<RawSpike, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let offset = self.records.record_offset(self.records.next_index());
+        let n_electrodes = self.acquisition.nelectrodes;
+        let t = self.timestamp_offset;
+        self.records.next_record().map(|record| {
+            let record = record?;
+            let electrode = i16::from_le_bytes([record[0], record[1]]);
+            if electrode < 0 || electrode as usize >= n_electrodes {
+                return Err(Error::bad_value("electrode_num", electrode.to_string(),
+                                            format!("an electrode below {}", n_electrodes))
+                           .at_offset(offset));
+            }
+            let timestamp = u32::from_le_bytes([record[t], record[t + 1],
+                                                record[t + 2], record[t + 3]]);
+            let samples = record[t + 4..]
+                .chunks_exact(2)
+                .map(|b| i16::from_le_bytes([b[0], b[1]]))
+                .collect();
+            Ok(RawSpike { electrode: electrode as usize, timestamp, samples })
+        })
+    }
+}
+
+/// One DMA buffer recorded by the AD system in CONTINUOUS mode
+#[derive(Clone, Debug, PartialEq)]
+pub struct RawBuffer {
+    /// Timestamp of the first sample, in ticks of 100us
+    pub timestamp: u32,
+    /// Samples of all `nchannels` channels, interleaved
+    pub samples: Vec<i16>,
+}
+
+impl RawBuffer {
+    /// Split the samples into one trace per channel
+    pub fn split(&self, n_channels: usize) -> Eeg<i16, u32> {
+        let mut channels = vec![Vec::with_capacity(self.samples.len() / n_channels); n_channels];
+        for (i, v) in self.samples.iter().enumerate() {
+            channels[i % n_channels].push(*v);
+        }
+        Eeg { channels, time: self.timestamp }
+    }
+}
+
+/// Reads the buffers of a raw CONTINUOUS-mode acquisition file. The
+/// file holds nothing but back-to-back DMA buffers of `dma_bufsize`
+/// bytes, so the timestamp of each buffer is worked out from its
+/// position in the file and the sampling `rate`, counting from
+/// `start_timestamp` (0, the start of acquisition, by default).
+pub struct RawContinuousReader<R> {
+    header: MetadataBuf,
+    acquisition: AdAcquisitionHeader,
+    start_timestamp: u32,
+    records: RecordStream<BufReader<R>>,
+}
+
+impl RawContinuousReader<File> {
+    pub fn open(path: &Path) -> Result<RawContinuousReader<File>, Error> {
+        File::open(path)
+            .map_err(Error::from)
+            .and_then(RawContinuousReader::new)
+            .map(|mut r| {
+                r.records = r.records.with_path(path);
+                r
+            })
+            .map_err(|e| e.at_path(path))
+    }
+}
+
+impl<R: Read> RawContinuousReader<R> {
+    /// Parse the header of `inner`; buffers are read on iteration
+    pub fn new(inner: R) -> Result<RawContinuousReader<R>, Error> {
+        let mut inner = BufReader::new(inner);
+        let bytes = header::read_bytes(&mut inner)?;
+        let (metadata, _) = header::parse(&bytes)?;
+        let acquisition = AdAcquisitionHeader::from_metadata(&metadata)?;
+        if acquisition.mode != AdMode::Continuous {
+            return Err(Error::bad_value("mode", "SPIKE", "CONTINUOUS"));
+        }
+        let frame_len = 2 * acquisition.nchannels;
+        if frame_len == 0 || acquisition.dma_bufsize == 0
+            || acquisition.dma_bufsize % frame_len != 0 {
+            return Err(Error::bad_value("dma_bufsize", acquisition.dma_bufsize.to_string(),
+                                        format!("a multiple of {} bytes", frame_len)));
+        }
+        if acquisition.rate.is_nan() || acquisition.rate <= 0.0 {
+            return Err(Error::bad_value("rate", acquisition.rate.to_string(),
+                                        "a positive rate"));
+        }
+        let mut records = RecordStream::new(inner, acquisition.dma_bufsize, bytes.len() as u64);
+        records.set_check_order(false);
+        Ok(RawContinuousReader {
+            header: MetadataBuf::from(&metadata),
+            acquisition,
+            start_timestamp: 0,
+            records,
+        })
+    }
+
+    pub fn header(&self) -> &MetadataBuf {
+        &self.header
+    }
+
+    pub fn acquisition(&self) -> &AdAcquisitionHeader {
+        &self.acquisition
+    }
+
+    /// Samples per channel in each buffer
+    pub fn buffer_len(&self) -> usize {
+        self.acquisition.dma_bufsize / 2 / self.acquisition.nchannels
+    }
+
+    /// Count buffer timestamps from `start` rather than from 0
+    pub fn start_timestamp(mut self, start: u32) -> RawContinuousReader<R> {
+        self.start_timestamp = start;
+        self
+    }
+
+    /// Timestamp of the `i`th buffer of the file
+    pub fn buffer_timestamp(&self, i: u64) -> u32 {
+        // `rate` is summed over channels, as is the buffer size
+        let samples = i as f64 * (self.acquisition.dma_bufsize / 2) as f64;
+        let ticks = (samples / self.acquisition.rate * TICKS_PER_SECOND).round();
+        (self.start_timestamp as f64 + ticks).min(u32::MAX as f64) as u32
+    }
+
+    /// Choose how to handle a partial trailing buffer
+    /// (`Recovery::Strict` by default)
+    pub fn recovery(mut self, recovery: Recovery) -> RawContinuousReader<R> {
+        self.records.set_recovery(recovery);
+        self
+    }
+
+    /// Problems worked around so far in `Recovery::Salvage` mode
+    pub fn warnings(&self) -> &[Warning] {
+        self.records.warnings()
+    }
+}
+
+impl<R: Read> Iterator for RawContinuousReader<R> {
+    type Item = Result<RawBuffer, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let timestamp = self.buffer_timestamp(self.records.next_index());
+        self.records.next_record().map(|record| {
+            let samples = record?
+                .chunks_exact(2)
+                .map(|b| i16::from_le_bytes([b[0], b[1]]))
+                .collect();
+            Ok(RawBuffer { timestamp, samples })
+        })
+    }
+}
+
+
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::mwl_ad::header::tests::HEADER_FIXTURE;
+
+    /// The acquisition header embedded in `HEADER_FIXTURE`, as it
+    /// appears at the top of the raw file
+    pub(crate) fn raw_header() -> MetadataBuf {
+        let (m, _) = header::parse(HEADER_FIXTURE.as_bytes()).unwrap();
+        let t = header::tree(&m);
+        MetadataBuf::from(&t.child("data/original.spk.raw").unwrap().metadata())
+    }
+
+    /// A raw file with one spike per `(electrode, timestamp)`, each
+    /// with sample values `t, t+1, ...`
+    pub(crate) fn raw_file(spikes: &[(i16, u32)]) -> Vec<u8> {
+        let mut bytes = header::to_bytes(&raw_header().as_metadata()).unwrap();
+        for (e, t) in spikes {
+            bytes.extend_from_slice(&e.to_le_bytes());
+            bytes.extend_from_slice(&[0, 0]);
+            bytes.extend_from_slice(&t.to_le_bytes());
+            for i in 0..128 {
+                bytes.extend_from_slice(&((*t as i16).wrapping_add(i)).to_le_bytes());
+            }
+        }
+        bytes
+    }
+
+    /// A CONTINUOUS-mode raw file of 2 channels, with buffers of 4
+    /// samples per channel at 2 kHz per channel, holding `n_buffers`
+    /// buffers of sample values 0, 1, ...
+    pub(crate) fn continuous_file(n_buffers: usize) -> Vec<u8> {
+        let mut m = MetadataBuf::new()
+            .pair("mode", "CONTINUOUS")
+            .pair("adversion", "1.36b")
+            .pair("rate", "4000.000000")
+            .pair("nelectrodes", 0)
+            .pair("nchannels", 2)
+            .pair("nelect_chan", 2)
+            .pair("dma_bufsize", 16)
+            .pair("spikelen", 32)
+            .pair("spikesep", 26);
+        for c in 0..2 {
+            for (k, v) in &[("ampgain", 1000 * (c + 1)), ("adgain", 0), ("filter", 0),
+                            ("threshold", 0), ("color", 0), ("offset", 0),
+                            ("contscale", 0)] {
+                m.push_pair(format!("channel {} {}", c, k), v);
+            }
+        }
+        let mut bytes = header::to_bytes(&m.as_metadata()).unwrap();
+        for i in 0..(n_buffers as i16 * 8) {
+            bytes.extend_from_slice(&i.to_le_bytes());
+        }
+        bytes
+    }
+
+    #[test]
+    fn it_reads_raw_buffers() {
+        let bytes = continuous_file(3);
+        let reader = RawContinuousReader::new(bytes.as_slice()).unwrap().start_timestamp(10);
+        assert_eq!(reader.buffer_len(), 4);
+        let buffers: Vec<RawBuffer> = reader.map(Result::unwrap).collect();
+        // Each buffer holds 2ms of samples
+        let timestamps: Vec<u32> = buffers.iter().map(|b| b.timestamp).collect();
+        assert_eq!(timestamps, vec![10, 30, 50]);
+        let eeg = buffers[1].split(2);
+        assert_eq!(eeg.time, 30);
+        assert_eq!(eeg.channels, vec![vec![8, 10, 12, 14], vec![9, 11, 13, 15]]);
+
+        let spike_mode = raw_file(&[]);
+        assert!(RawContinuousReader::new(spike_mode.as_slice()).is_err());
+    }
+
+    #[test]
+    fn it_reads_raw_spikes() {
+        let bytes = raw_file(&[(1, 300), (0, 200)]);
+        let reader = RawSpikeReader::new(bytes.as_slice()).unwrap();
+        assert_eq!(reader.acquisition().nelectrodes, 2);
+        let spikes: Vec<RawSpike> = reader.map(Result::unwrap).collect();
+        assert_eq!(spikes.len(), 2);
+        assert_eq!((spikes[0].electrode, spikes[0].timestamp), (1, 300));
+        assert_eq!((spikes[1].electrode, spikes[1].timestamp), (0, 200));
+        assert_eq!(spikes[1].samples.len(), 128);
+        assert_eq!(spikes[1].samples[5], 205);
+        assert_eq!(spikes[1].truncated(4, 8), &spikes[1].samples[..32]);
+    }
+
+    #[test]
+    fn it_rejects_unknown_electrodes() {
+        let bytes = raw_file(&[(0, 100), (2, 200)]);
+        let header_len = bytes.len() - 2 * 264;
+        let mut reader = RawSpikeReader::new(bytes.as_slice()).unwrap();
+        assert!(reader.next().unwrap().is_ok());
+        match reader.next() {
+            Some(Err(Error::BadValue { key, offset, .. })) => {
+                assert_eq!(key, "electrode_num");
+                assert_eq!(offset, Some(header_len as u64 + 264));
+            },
+            r => panic!("expected a bad electrode, got {:?}", r),
+        }
+    }
+}
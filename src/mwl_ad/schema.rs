@@ -0,0 +1,228 @@
+use std::fmt;
+
+use failure::Fail;
+
+use super::{decode_type, FormatType};
+use super::header::{self, Metadata};
+
+/// One column of an MWL binary record, as described by one entry
+/// of the header's `Fields` line: `name,type,size,count`.
+/// `size` is the size in bytes of a single element, and `count` the
+/// number of elements of that column in each record.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Field {
+    pub name: String,
+    pub format: FormatType,
+    pub size: usize,
+    pub count: usize,
+}
+
+impl Field {
+    pub fn new(name: &str, format: FormatType, size: usize, count: usize) -> Field {
+        Field { name: name.to_owned(), format, size, count }
+    }
+
+    /// Number of bytes this field occupies in each record
+    pub fn byte_len(&self) -> usize {
+        self.size * self.count
+    }
+}
+
+/// The layout of every record in an MWL binary file
+#[derive(Clone, Debug, PartialEq)]
+pub struct RecordSchema {
+    pub fields: Vec<Field>,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum SchemaError {
+    MissingFields,
+    MalformedField { entry: String },
+    UnknownFormat { field: String, code: i32 },
+    UnsupportedField { field: String },
+    LayoutMismatch { expected: String, found: String },
+}
+
+impl fmt::Display for SchemaError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SchemaError::MissingFields => write!(f, "header has no Fields line"),
+            SchemaError::MalformedField { entry } =>
+                write!(f, "malformed entry in Fields line: {:?}", entry),
+            SchemaError::UnknownFormat { field, code } =>
+                write!(f, "field {} has unknown format code {}", field, code),
+            SchemaError::UnsupportedField { field } =>
+                write!(f, "field {} has a type and size that can't be decoded", field),
+            SchemaError::LayoutMismatch { expected, found } =>
+                write!(f, "record layout mismatch: expected \"{}\", file has \"{}\"", expected, found),
+        }
+    }
+}
+
+impl Fail for SchemaError {}
+
+impl RecordSchema {
+    pub fn new(fields: Vec<Field>) -> RecordSchema {
+        RecordSchema { fields }
+    }
+
+    /// Parse the value of a `Fields` header line,
+    /// e.g. `"timestamp,8,4,1\twaveform,2,2,128"`
+    pub fn parse(fields_line: &str) -> Result<RecordSchema, SchemaError> {
+        fields_line
+            .split_whitespace()
+            .map(parse_field)
+            .collect::<Result<Vec<Field>, SchemaError>>()
+            .map(RecordSchema::new)
+    }
+
+    /// Read the schema from the `Fields` line of a file header
+    pub fn from_metadata(metadata: &Metadata) -> Result<RecordSchema, SchemaError> {
+        header::lookup(metadata, "Fields")
+            .ok_or(SchemaError::MissingFields)
+            .and_then(RecordSchema::parse)
+    }
+
+    /// Size in bytes of a single record
+    pub fn record_size(&self) -> usize {
+        self.fields.iter().map(Field::byte_len).sum()
+    }
+
+    /// Number of complete records in `n_bytes` of file data
+    pub fn record_count(&self, n_bytes: usize) -> usize {
+        match self.record_size() {
+            0 => 0,
+            size => n_bytes / size,
+        }
+    }
+
+    /// Number of bytes left over after the last complete record
+    pub fn trailing_bytes(&self, n_bytes: usize) -> usize {
+        match self.record_size() {
+            0 => n_bytes,
+            size => n_bytes % size,
+        }
+    }
+
+    pub fn field(&self, name: &str) -> Option<&Field> {
+        self.fields.iter().find(|f| f.name == name)
+    }
+
+    /// Byte offset of field `name` from the start of a record
+    pub fn offset(&self, name: &str) -> Option<usize> {
+        let mut offset = 0;
+        for f in self.fields.iter() {
+            if f.name == name {
+                return Some(offset);
+            }
+            offset += f.byte_len();
+        }
+        None
+    }
+
+    /// Check that records described by `self` can be decoded with a
+    /// parser written for `expected`. Field names are not compared,
+    /// only the sequence of element types and sizes, so
+    /// `pos,2,2,4` matches four separate `short` fields.
+    pub fn check_layout(&self, expected: &RecordSchema) -> Result<(), SchemaError> {
+        if self.elements().eq(expected.elements()) {
+            Ok(())
+        } else {
+            Err(SchemaError::LayoutMismatch {
+                expected: expected.to_string(),
+                found: self.to_string(),
+            })
+        }
+    }
+
+    /// Like `check_layout`, but also require the fields to have the
+    /// names in `expected`, for readers that look fields up by name
+    pub fn check_fields(&self, expected: &RecordSchema) -> Result<(), SchemaError> {
+        if self == expected {
+            Ok(())
+        } else {
+            Err(SchemaError::LayoutMismatch {
+                expected: expected.to_string(),
+                found: self.to_string(),
+            })
+        }
+    }
+
+    fn elements(&self) -> impl Iterator<Item = (FormatType, usize)> + '_ {
+        self.fields
+            .iter()
+            .flat_map(|f| (0..f.count).map(move |_| (f.format, f.size)))
+    }
+}
+
+/// Formats as the value of a `Fields` header line
+impl fmt::Display for RecordSchema {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let entries: Vec<String> = self.fields
+            .iter()
+            .map(|field| format!("{},{},{},{}",
+                                 field.name,
+                                 field.format.code(),
+                                 field.size,
+                                 field.count))
+            .collect();
+        write!(f, "{}", entries.join("\t"))
+    }
+}
+
+fn parse_field(entry: &str) -> Result<Field, SchemaError> {
+    let malformed = || SchemaError::MalformedField { entry: entry.to_owned() };
+    let parts: Vec<&str> = entry.split(',').collect();
+    if parts.len() != 4 || parts[0].is_empty() {
+        return Err(malformed());
+    }
+    let number = |s: &str| s.trim().parse::<i32>().map_err(|_| malformed());
+    let code = number(parts[1])?;
+    let size = number(parts[2])?;
+    let count = number(parts[3])?;
+    if size < 0 || count < 0 {
+        return Err(malformed());
+    }
+    let format = decode_type(code).map_err(|_| SchemaError::UnknownFormat {
+        field: parts[0].to_owned(),
+        code,
+    })?;
+    Ok(Field::new(parts[0], format, size as usize, count as usize))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_parses_tt_fields() {
+        let s = RecordSchema::parse("timestamp,8,4,1\twaveform,2,2,128\t").unwrap();
+        assert_eq!(s.fields, vec![Field::new("timestamp", FormatType::ULongT, 4, 1),
+                                  Field::new("waveform", FormatType::ShortT, 2, 128)]);
+        assert_eq!(s.record_size(), 260);
+        assert_eq!(s.offset("waveform"), Some(4));
+        assert_eq!(s.record_count(521), 2);
+        assert_eq!(s.trailing_bytes(521), 1);
+        assert_eq!(s.to_string(), "timestamp,8,4,1\twaveform,2,2,128");
+    }
+
+    #[test]
+    fn it_rejects_bad_fields() {
+        assert_eq!(RecordSchema::parse("timestamp,8,4"),
+                   Err(SchemaError::MalformedField { entry: "timestamp,8,4".to_owned() }));
+        assert_eq!(RecordSchema::parse("x,12,4,1"),
+                   Err(SchemaError::UnknownFormat { field: "x".to_owned(), code: 12 }));
+    }
+
+    #[test]
+    fn it_compares_layouts_by_element() {
+        let split = RecordSchema::parse("t,8,4,1 xf,2,2,1 yf,2,2,1 xb,2,2,1 yb,2,2,1").unwrap();
+        let joined = RecordSchema::parse("timestamp,8,4,1 pos,2,2,4").unwrap();
+        assert_eq!(split.check_layout(&joined), Ok(()));
+        let wrong = RecordSchema::parse("timestamp,8,4,1 pos,4,4,4").unwrap();
+        assert!(wrong.check_layout(&joined).is_err());
+        assert!(split.check_fields(&joined).is_err());
+        assert_eq!(joined.check_fields(&joined.clone()), Ok(()));
+    }
+}
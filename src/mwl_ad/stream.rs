@@ -0,0 +1,663 @@
+use std::collections::VecDeque;
+use std::io;
+use std::io::{BufReader, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use crate::Error;
+use crate::interval::IntervalSet;
+use super::header::{self, Metadata, MetadataBuf};
+use super::schema::RecordSchema;
+
+/// Parse the header at the start of `inner`, checking that the
+/// records that follow have the layout `expected`, and return a
+/// stream of those records
+pub fn open<R: Read>(
+    inner: R,
+    expected: &RecordSchema,
+) -> Result<(MetadataBuf, RecordStream<BufReader<R>>), Error> {
+    open_with(inner, |_| Ok(expected.clone()))
+}
+
+/// Like `open`, for formats whose record layout depends on the
+/// header: `layout` is given the parsed header and returns the
+/// layout the records must have
+pub fn open_with<R: Read, F>(
+    inner: R,
+    layout: F,
+) -> Result<(MetadataBuf, RecordStream<BufReader<R>>), Error>
+where
+    F: FnOnce(&Metadata) -> Result<RecordSchema, Error>,
+{
+    let mut inner = BufReader::new(inner);
+    let bytes = header::read_bytes(&mut inner)?;
+    let (metadata, _) = header::parse(&bytes)?;
+    let expected = layout(&metadata)?;
+    RecordSchema::from_metadata(&metadata)?.check_layout(&expected)?;
+    check_timestamped(&expected)?;
+    let records = RecordStream::new(inner, expected.record_size(), bytes.len() as u64);
+    Ok((MetadataBuf::from(&metadata), records))
+}
+
+/// How readers handle damaged files
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Recovery {
+    /// Fail on a partial trailing record or a timestamp regression
+    Strict,
+    /// Drop partial trailing records and records whose timestamps
+    /// regress, recording a `Warning` for each. Regressed records are
+    /// held back rather than dropped while they are in order among
+    /// themselves: once `RESYNC_RECORDS` of them are, the clock is
+    /// taken to have been reset, and they are kept along with the
+    /// records after them.
+    Salvage,
+}
+
+/// A problem worked around while reading in `Recovery::Salvage` mode
+#[derive(Clone, Debug, PartialEq)]
+pub enum Warning {
+    /// `count` bytes at `offset` don't make up a whole record
+    TrailingBytes { offset: u64, count: usize },
+    /// `count` consecutive records starting at `offset` had timestamps
+    /// earlier than `previous`, the last timestamp kept
+    TimestampRegression { offset: u64, count: u64, previous: u32 },
+    /// The clock went back from `previous` to `timestamp` and stayed
+    /// there: the `count` records held back from the one at `offset`
+    /// until the reset was confirmed are kept, as are those after them
+    ClockReset { offset: u64, previous: u32, timestamp: u32, count: u64 },
+}
+
+/// How many consecutive regressed records, in order among themselves,
+/// `Recovery::Salvage` takes as a clock reset rather than noise
+pub const RESYNC_RECORDS: u64 = 8;
+
+// The size of the timestamp at the start of each record
+const TIMESTAMP_SIZE: usize = 4;
+
+/// Fixed-size records read one at a time from a stream, reusing a
+/// single record-sized buffer.
+///
+/// The time-seeking methods assume that every record starts with a
+/// u32 timestamp, and that timestamps never decrease, as is the case
+/// for all MWL record files. `seek_time_range` restricts the stream to
+/// a range of timestamps, and `seek_intervals` to a set of intervals,
+/// such as the laps or run epochs of a session; the two combine, so
+/// that only records in both are read. Either way the first record is
+/// found by binary search, without reading the ones before it, and
+/// reading stops once no later record can be in range. The readers of
+/// MWL record files restrict themselves to times through these.
+pub struct RecordStream<R> {
+    inner: R,
+    path: Option<PathBuf>,
+    record: Vec<u8>,
+    data_offset: u64,
+    next_index: u64,
+    start_timestamp: Option<u32>,
+    end_timestamp: Option<u32>,
+    intervals: Option<IntervalSet>,
+    recovery: Recovery,
+    check_order: bool,
+    last_timestamp: Option<u32>,
+    // A run of regressed records in order, with their offsets, held
+    // back until it is long enough to be a clock reset
+    held_back: Vec<(u64, Vec<u8>)>,
+    // Records of a confirmed clock reset, yet to be returned
+    replay: VecDeque<Vec<u8>>,
+    warnings: Vec<Warning>,
+}
+
+impl<R: Read> RecordStream<R> {
+    /// `inner` must be positioned at the first record,
+    /// `data_offset` bytes from the start of the file
+    pub fn new(inner: R, record_size: usize, data_offset: u64) -> RecordStream<R> {
+        RecordStream {
+            inner,
+            path: None,
+            record: vec![0; record_size],
+            data_offset,
+            next_index: 0,
+            start_timestamp: None,
+            end_timestamp: None,
+            intervals: None,
+            recovery: Recovery::Strict,
+            check_order: record_size >= TIMESTAMP_SIZE,
+            last_timestamp: None,
+            held_back: Vec::new(),
+            replay: VecDeque::new(),
+            warnings: Vec::new(),
+        }
+    }
+
+    /// Name the file being read, for error messages
+    pub fn with_path(mut self, path: &Path) -> RecordStream<R> {
+        self.path = Some(path.to_path_buf());
+        self
+    }
+
+    pub fn set_recovery(&mut self, recovery: Recovery) {
+        self.recovery = recovery;
+    }
+
+    /// Whether to check that timestamps never decrease (the default
+    /// for records big enough to hold one). Turn this off for files
+    /// whose records don't start with one.
+    pub fn set_check_order(&mut self, check_order: bool) {
+        self.check_order = check_order && self.record.len() >= TIMESTAMP_SIZE;
+    }
+
+    /// Problems worked around so far in `Recovery::Salvage` mode
+    pub fn warnings(&self) -> &[Warning] {
+        &self.warnings
+    }
+
+    /// The next complete record, or `None` at the end of the stream
+    /// or of the time range. What happens to a partial trailing
+    /// record or a timestamp regression depends on the `Recovery` mode.
+    pub fn next_record(&mut self) -> Option<Result<&[u8], Error>> {
+        loop {
+            if let Some(record) = self.replay.pop_front() {
+                self.record = record;
+                match self.select(record_timestamp(&self.record)) {
+                    Some(true) => return Some(Ok(&self.record)),
+                    Some(false) => continue,
+                    None => return None,
+                }
+            }
+
+            let offset = self.record_offset(self.next_index);
+            let filled = match self.fill_record() {
+                Ok(filled) => filled,
+                Err(e) => return Some(Err(self.io_error(e, offset))),
+            };
+            if filled == 0 {
+                self.drop_held_back();
+                return None;
+            }
+            // Step past a bad record, so that it is reported only once
+            self.next_index += 1;
+            if filled < self.record.len() {
+                return match self.recovery {
+                    Recovery::Strict => Some(Err(Error::TruncatedRecord {
+                        path: self.path.clone(),
+                        offset,
+                        expected: self.record.len(),
+                        found: filled,
+                    })),
+                    Recovery::Salvage => {
+                        self.drop_held_back();
+                        self.warnings.push(Warning::TrailingBytes { offset, count: filled });
+                        None
+                    },
+                };
+            }
+
+            let needs_timestamp = self.check_order
+                || self.end_timestamp.is_some()
+                || self.intervals.is_some();
+            if !needs_timestamp || self.record.len() < TIMESTAMP_SIZE {
+                return Some(Ok(&self.record));
+            }
+            let timestamp = record_timestamp(&self.record);
+            match self.last_timestamp {
+                Some(previous) if self.check_order && timestamp < previous => {
+                    match self.recovery {
+                        Recovery::Strict => return Some(Err(Error::TimestampRegression {
+                            path: self.path.clone(),
+                            offset,
+                            previous,
+                            timestamp,
+                        })),
+                        Recovery::Salvage => {
+                            self.hold_back(offset, timestamp, previous);
+                            continue;
+                        },
+                    }
+                },
+                _ => {
+                    self.drop_held_back();
+                    self.last_timestamp = Some(timestamp);
+                },
+            }
+            match self.select(timestamp) {
+                Some(true) => return Some(Ok(&self.record)),
+                Some(false) => continue,
+                None => return None,
+            }
+        }
+    }
+
+    // Whether a record with `timestamp` is in the time range and
+    // intervals, or `None` if no later record can be
+    fn select(&self, timestamp: u32) -> Option<bool> {
+        if let Some(end) = self.end_timestamp {
+            if timestamp >= end {
+                return None;
+            }
+        }
+        if let Some(intervals) = &self.intervals {
+            if !intervals.contains(timestamp) {
+                // Ordered timestamps can't come back into the set
+                // once past its last interval
+                let past_end = intervals.span().is_none_or(|span| timestamp >= span.end);
+                if self.check_order && past_end {
+                    return None;
+                }
+                return Some(false);
+            }
+        }
+        Some(true)
+    }
+
+    // Read as much of a record as the stream holds,
+    // returning the number of bytes read
+    fn fill_record(&mut self) -> io::Result<usize> {
+        let mut filled = 0;
+        while filled < self.record.len() {
+            match self.inner.read(&mut self.record[filled..]) {
+                Ok(0) => break,
+                Ok(n) => filled += n,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => (),
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(filled)
+    }
+
+    // Hold back the regressed record just read as part of a possible
+    // clock reset, and queue the run to be returned once it is long
+    // enough to be one
+    fn hold_back(&mut self, offset: u64, timestamp: u32, previous: u32) {
+        let in_order = self.held_back
+            .last()
+            .is_none_or(|(_, record)| record_timestamp(record) <= timestamp);
+        if !in_order {
+            self.drop_held_back();
+        }
+        self.held_back.push((offset, self.record.clone()));
+        if self.held_back.len() as u64 >= RESYNC_RECORDS {
+            let (first_offset, first) = &self.held_back[0];
+            self.warnings.push(Warning::ClockReset {
+                offset: *first_offset,
+                previous,
+                timestamp: record_timestamp(first),
+                count: self.held_back.len() as u64,
+            });
+            self.last_timestamp = Some(timestamp);
+            self.replay.extend(self.held_back.drain(..).map(|(_, record)| record));
+        }
+    }
+
+    // Drop the records held back, which turned out not to be a clock
+    // reset
+    fn drop_held_back(&mut self) {
+        if let Some(previous) = self.last_timestamp {
+            for (offset, _) in std::mem::take(&mut self.held_back) {
+                self.warn_regression(offset, previous);
+            }
+        }
+    }
+
+    // Extend the previous warning if it covers the record before this one
+    fn warn_regression(&mut self, offset: u64, previous: u32) {
+        let record_size = self.record.len() as u64;
+        if let Some(Warning::TimestampRegression { offset: start, count, .. }) =
+            self.warnings.last_mut() {
+            if *start + *count * record_size == offset {
+                *count += 1;
+                return;
+            }
+        }
+        self.warnings.push(Warning::TimestampRegression { offset, count: 1, previous });
+    }
+
+    fn io_error(&self, err: io::Error, offset: u64) -> Error {
+        Error::Io { path: self.path.clone(), offset: Some(offset), err }
+    }
+
+    /// Stop at the first record with a timestamp at or after `end`
+    pub fn set_end_timestamp(&mut self, end: Option<u32>) {
+        self.end_timestamp = end;
+    }
+
+    /// Skip records whose timestamps aren't in `intervals`
+    pub fn set_intervals(&mut self, intervals: Option<IntervalSet>) {
+        self.intervals = intervals;
+    }
+
+    /// Index of the record the next call to `next_record` reads
+    pub fn next_index(&self) -> u64 {
+        self.next_index
+    }
+
+    /// Byte offset of record `i` from the start of the file
+    pub fn record_offset(&self, i: u64) -> u64 {
+        self.data_offset + i * self.record.len() as u64
+    }
+
+    pub fn get_ref(&self) -> &R {
+        &self.inner
+    }
+
+    pub fn get_mut(&mut self) -> &mut R {
+        &mut self.inner
+    }
+}
+
+impl<R: Read + Seek> RecordStream<R> {
+    /// Number of complete records in the file
+    pub fn len(&mut self) -> Result<u64, Error> {
+        let here = self.record_offset(self.next_index);
+        let end = self.inner
+            .seek(SeekFrom::End(0))
+            .and_then(|end| self.inner.seek(SeekFrom::Start(here)).map(|_| end))
+            .map_err(|e| self.io_error(e, here))?;
+        Ok(end.saturating_sub(self.data_offset) / self.record.len().max(1) as u64)
+    }
+
+    pub fn is_empty(&mut self) -> Result<bool, Error> {
+        self.len().map(|n| n == 0)
+    }
+
+    /// Position the stream so that the next record read is record `i`
+    pub fn seek_record(&mut self, i: u64) -> Result<(), Error> {
+        let offset = self.record_offset(i);
+        self.inner.seek(SeekFrom::Start(offset)).map_err(|e| self.io_error(e, offset))?;
+        self.next_index = i;
+        self.last_timestamp = None;
+        self.held_back.clear();
+        self.replay.clear();
+        Ok(())
+    }
+
+    /// Position the stream at the first record with a timestamp at or
+    /// after `t`, found by binary search, and return its index
+    pub fn seek_timestamp(&mut self, t: u32) -> Result<u64, Error> {
+        if self.record.len() < TIMESTAMP_SIZE {
+            let err = too_small(self.record.len());
+            return Err(match &self.path {
+                Some(path) => err.at_path(path),
+                None => err,
+            });
+        }
+        let (mut low, mut high) = (0, self.len()?);
+        let mut timestamp = [0u8; 4];
+        while low < high {
+            let mid = low + (high - low) / 2;
+            let offset = self.record_offset(mid);
+            self.inner
+                .seek(SeekFrom::Start(offset))
+                .and_then(|_| self.inner.read_exact(&mut timestamp))
+                .map_err(|e| self.io_error(e, offset))?;
+            if u32::from_le_bytes(timestamp) < t {
+                low = mid + 1;
+            } else {
+                high = mid;
+            }
+        }
+        self.seek_record(low)?;
+        Ok(low)
+    }
+
+    /// Restrict the stream to records with timestamps in
+    /// `[after, before)`; either bound may be left open. This replaces
+    /// any earlier time range, but keeps the intervals of
+    /// `seek_intervals`, so that only records in both are read.
+    pub fn seek_time_range(&mut self, after: Option<u32>, before: Option<u32>) -> Result<(), Error> {
+        self.start_timestamp = after;
+        self.set_end_timestamp(before);
+        self.seek_start()
+    }
+
+    /// Restrict the stream to records with timestamps in `intervals`.
+    /// This replaces any earlier intervals, but keeps the time range of
+    /// `seek_time_range`, so that only records in both are read.
+    pub fn seek_intervals(&mut self, intervals: IntervalSet) -> Result<(), Error> {
+        self.set_intervals(Some(intervals));
+        self.seek_start()
+    }
+
+    // Seek to the first record that can be in both the time range and
+    // the intervals
+    fn seek_start(&mut self) -> Result<(), Error> {
+        let intervals_start = self.intervals.as_ref().and_then(|i| i.span()).map(|span| span.start);
+        match self.start_timestamp.max(intervals_start) {
+            Some(t) => self.seek_timestamp(t).map(|_| ()),
+            None => self.seek_record(0),
+        }
+    }
+}
+
+/// Write `header` at the start of a new record file, checking that
+/// its `Fields` line describes records of the layout `expected`
+pub fn write_header<W: Write>(
+    w: &mut W,
+    header: &Metadata,
+    expected: &RecordSchema,
+) -> Result<(), Error> {
+    RecordSchema::from_metadata(header)?.check_layout(expected)?;
+    header::write(header, w)?;
+    Ok(())
+}
+
+/// Copy an MWL record file of any layout, keeping its header
+/// byte-for-byte but dropping a partial trailing record. With
+/// `drop_regressions`, records whose timestamps regress are dropped
+/// too, which assumes records start with a u32 timestamp.
+/// Returns what was dropped.
+pub fn repair<R: Read, W: Write>(
+    input: R,
+    output: &mut W,
+    drop_regressions: bool,
+) -> Result<Vec<Warning>, Error> {
+    let mut input = BufReader::new(input);
+    let header_bytes = header::read_bytes(&mut input)?;
+    let (metadata, _) = header::parse(&header_bytes)?;
+    let schema = RecordSchema::from_metadata(&metadata)?;
+    if drop_regressions {
+        check_timestamped(&schema)?;
+    }
+    output.write_all(&header_bytes)?;
+
+    let mut records = RecordStream::new(input, schema.record_size(), header_bytes.len() as u64);
+    records.set_recovery(Recovery::Salvage);
+    records.set_check_order(drop_regressions);
+    while let Some(record) = records.next_record() {
+        output.write_all(record?)?;
+    }
+    Ok(records.warnings)
+}
+
+// Records must be big enough to start with a u32 timestamp
+pub(crate) fn check_timestamped(schema: &RecordSchema) -> Result<(), Error> {
+    match schema.record_size() {
+        n if n < TIMESTAMP_SIZE => Err(too_small(n)),
+        _ => Ok(()),
+    }
+}
+
+fn too_small(record_size: usize) -> Error {
+    Error::bad_value("Fields", format!("{}-byte records", record_size),
+                     "records that start with a u32 timestamp")
+}
+
+/// The u32 timestamp at the start of an MWL record, which must be at
+/// least 4 bytes long
+pub fn record_timestamp(record: &[u8]) -> u32 {
+    u32::from_le_bytes([record[0], record[1], record[2], record[3]])
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    // Records of a u32 timestamp and a u16 payload
+    fn stream(timestamps: &[u32]) -> RecordStream<Cursor<Vec<u8>>> {
+        let mut bytes = b"header".to_vec();
+        for t in timestamps {
+            bytes.extend_from_slice(&t.to_le_bytes());
+            bytes.extend_from_slice(&[0xaa, 0xbb]);
+        }
+        let mut cursor = Cursor::new(bytes);
+        cursor.set_position(6);
+        RecordStream::new(cursor, 6, 6)
+    }
+
+    fn remaining(s: &mut RecordStream<Cursor<Vec<u8>>>) -> Vec<u32> {
+        let mut ts = Vec::new();
+        while let Some(r) = s.next_record() {
+            ts.push(record_timestamp(r.unwrap()));
+        }
+        ts
+    }
+
+    // Like `remaining`, but stop at the first error
+    fn remaining_ok(s: &mut RecordStream<Cursor<Vec<u8>>>) -> Vec<u32> {
+        let mut ts = Vec::new();
+        while let Some(Ok(r)) = s.next_record() {
+            ts.push(record_timestamp(r));
+        }
+        ts
+    }
+
+    #[test]
+    fn it_seeks_by_timestamp() {
+        let mut s = stream(&[10, 20, 20, 30, 40]);
+        assert_eq!(s.len().unwrap(), 5);
+        assert_eq!(s.seek_timestamp(20).unwrap(), 1);
+        assert_eq!(s.seek_timestamp(21).unwrap(), 3);
+        assert_eq!(s.seek_timestamp(0).unwrap(), 0);
+        assert_eq!(s.seek_timestamp(50).unwrap(), 5);
+        assert_eq!(remaining(&mut s), Vec::<u32>::new());
+    }
+
+    #[test]
+    fn it_reads_time_ranges() {
+        let mut s = stream(&[10, 20, 20, 30, 40]);
+        s.seek_time_range(Some(15), Some(40)).unwrap();
+        assert_eq!(remaining(&mut s), vec![20, 20, 30]);
+        s.seek_time_range(None, Some(20)).unwrap();
+        assert_eq!(remaining(&mut s), vec![10]);
+        s.seek_time_range(Some(30), None).unwrap();
+        assert_eq!(remaining(&mut s), vec![30, 40]);
+    }
+
+    #[test]
+    fn it_filters_by_intervals() {
+        // Reading stops past the last interval, before the regression
+        let mut s = stream(&[10, 20, 20, 30, 36, 40, 5]);
+        s.seek_intervals(vec![15..25, 35..38].into_iter().collect()).unwrap();
+        assert_eq!(remaining_ok(&mut s), vec![20, 20, 36]);
+        s.seek_intervals(IntervalSet::new()).unwrap();
+        assert_eq!(remaining_ok(&mut s), Vec::<u32>::new());
+    }
+
+    #[test]
+    fn it_reads_intervals_within_a_time_range() {
+        let intervals: IntervalSet = vec![15..25, 30..40].into_iter().collect();
+        let mut s = stream(&[10, 20, 20, 30, 36, 40]);
+        s.seek_time_range(Some(20), Some(36)).unwrap();
+        s.seek_intervals(intervals.clone()).unwrap();
+        assert_eq!(remaining(&mut s), vec![20, 20, 30]);
+
+        let mut s = stream(&[10, 20, 20, 30, 36, 40]);
+        s.seek_intervals(intervals).unwrap();
+        s.seek_time_range(Some(25), None).unwrap();
+        assert_eq!(remaining(&mut s), vec![30, 36]);
+    }
+
+    #[test]
+    fn it_salvages_damaged_streams() {
+        let mut s = stream(&[10, 20, 5, 6, 30, 7, 40]);
+        s.get_mut().get_mut().extend_from_slice(&[1, 2, 3]);
+        s.set_recovery(Recovery::Salvage);
+        assert_eq!(remaining(&mut s), vec![10, 20, 30, 40]);
+        assert_eq!(s.warnings(), &[
+            Warning::TimestampRegression { offset: 18, count: 2, previous: 20 },
+            Warning::TimestampRegression { offset: 36, count: 1, previous: 30 },
+            Warning::TrailingBytes { offset: 48, count: 3 },
+        ]);
+
+        let mut s = stream(&[10, 20, 5]);
+        assert_eq!(remaining_ok(&mut s), vec![10, 20]);
+    }
+
+    #[test]
+    fn it_repairs_files() {
+        let mut bytes = header::to_bytes(
+            &MetadataBuf::new().pair("Fields", "timestamp,8,4,1\tx,2,2,1").as_metadata()
+        ).unwrap();
+        let header_len = bytes.len();
+        for t in &[1u32, 3, 2, 4] {
+            bytes.extend_from_slice(&t.to_le_bytes());
+            bytes.extend_from_slice(&[0, 0]);
+        }
+        bytes.push(9);
+
+        let mut repaired = Vec::new();
+        let warnings = repair(bytes.as_slice(), &mut repaired, false).unwrap();
+        assert_eq!(warnings, vec![Warning::TrailingBytes { offset: header_len as u64 + 24, count: 1 }]);
+        assert_eq!(repaired, &bytes[..bytes.len() - 1]);
+
+        let mut repaired = Vec::new();
+        let warnings = repair(bytes.as_slice(), &mut repaired, true).unwrap();
+        assert_eq!(warnings.len(), 2);
+        assert_eq!(repaired.len(), header_len + 18);
+    }
+
+    #[test]
+    fn it_resynchronizes_after_a_clock_reset() {
+        let mut timestamps = vec![100, 200, 50, 300];
+        timestamps.extend(1..=10);
+        let mut s = stream(&timestamps);
+        s.set_recovery(Recovery::Salvage);
+        let mut expected = vec![100, 200, 300];
+        expected.extend(1..=10);
+        assert_eq!(remaining(&mut s), expected);
+        assert_eq!(s.warnings(), &[
+            Warning::TimestampRegression { offset: 18, count: 1, previous: 200 },
+            Warning::ClockReset { offset: 30, previous: 300, timestamp: 1, count: 8 },
+        ]);
+
+        // A run cut short by the clock catching up is dropped after all
+        let mut s = stream(&[100, 200, 1, 2, 3, 250, 300]);
+        s.set_recovery(Recovery::Salvage);
+        assert_eq!(remaining(&mut s), vec![100, 200, 250, 300]);
+        assert_eq!(s.warnings(), &[
+            Warning::TimestampRegression { offset: 18, count: 3, previous: 200 },
+        ]);
+    }
+
+    #[test]
+    fn it_repairs_records_too_small_for_timestamps() {
+        let mut bytes = header::to_bytes(
+            &MetadataBuf::new().pair("Fields", "x,2,2,1").as_metadata()
+        ).unwrap();
+        let header_len = bytes.len();
+        bytes.extend_from_slice(&[1, 2, 3, 4, 5]);
+
+        let mut repaired = Vec::new();
+        let warnings = repair(bytes.as_slice(), &mut repaired, false).unwrap();
+        assert_eq!(warnings, vec![Warning::TrailingBytes { offset: header_len as u64 + 4, count: 1 }]);
+        assert_eq!(repaired, &bytes[..bytes.len() - 1]);
+
+        match repair(bytes.as_slice(), &mut Vec::new(), true) {
+            Err(Error::BadValue { key, .. }) => assert_eq!(key, "Fields"),
+            r => panic!("expected a bad value, got {:?}", r),
+        }
+        let short = RecordSchema::parse("x,2,2,1").unwrap();
+        assert!(open(bytes.as_slice(), &short).is_err());
+    }
+
+    #[test]
+    fn it_reports_truncated_records() {
+        let mut s = stream(&[10]);
+        s.get_mut().get_mut().extend_from_slice(&[1, 2, 3]);
+        assert!(s.next_record().unwrap().is_ok());
+        match s.next_record() {
+            Some(Err(Error::TruncatedRecord { offset: 12, expected: 6, found: 3, .. })) => (),
+            r => panic!("expected a truncated record, got {:?}", r),
+        }
+        assert!(s.next_record().is_none());
+    }
+}
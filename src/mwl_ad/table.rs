@@ -0,0 +1,287 @@
+use std::convert::TryInto;
+use std::fmt;
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+
+use failure::Fail;
+
+use crate::Error;
+use super::FormatType;
+use super::header::{self, Metadata, MetadataBuf};
+use super::schema::{Field, RecordSchema, SchemaError};
+
+/// All values of one field, in record order. For fields with a
+/// count greater than one, the elements of each record are stored
+/// contiguously.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Column {
+    Char(Vec<u8>),
+    Short(Vec<i16>),
+    Int(Vec<i32>),
+    Float(Vec<f32>),
+    Double(Vec<f64>),
+    ULong(Vec<u32>),
+    ULong64(Vec<u64>),
+}
+
+/// Rust types that MWL columns can be decoded into
+pub trait ColumnType: Sized {
+    const NAME: &'static str;
+    /// The `Fields` type and element size this type decodes
+    const FORMAT: FormatType;
+    const SIZE: usize;
+    fn from_column(column: &Column) -> Option<&[Self]>;
+    /// Decode one element from exactly `SIZE` little-endian bytes
+    fn decode_le(bytes: &[u8]) -> Self;
+
+    fn decodes(field: &Field) -> bool {
+        field.format == Self::FORMAT && field.size == Self::SIZE
+    }
+}
+
+macro_rules! column_type {
+    ($t:ty, $variant:ident, $format:ident) => {
+        impl ColumnType for $t {
+            const NAME: &'static str = stringify!($t);
+            const FORMAT: FormatType = FormatType::$format;
+            const SIZE: usize = std::mem::size_of::<$t>();
+            fn from_column(column: &Column) -> Option<&[$t]> {
+                match column {
+                    Column::$variant(vs) => Some(vs),
+                    _ => None,
+                }
+            }
+            fn decode_le(bytes: &[u8]) -> $t {
+                <$t>::from_le_bytes(bytes.try_into().unwrap())
+            }
+        }
+    };
+}
+
+column_type!(u8, Char, CharT);
+column_type!(i16, Short, ShortT);
+column_type!(i32, Int, IntT);
+column_type!(f32, Float, FloatT);
+column_type!(f64, Double, DoubleT);
+column_type!(u32, ULong, ULongT);
+column_type!(u64, ULong64, ULongT);
+
+impl Column {
+    /// Name of the Rust element type, for use with `column::<T>`
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Column::Char(_) => u8::NAME,
+            Column::Short(_) => i16::NAME,
+            Column::Int(_) => i32::NAME,
+            Column::Float(_) => f32::NAME,
+            Column::Double(_) => f64::NAME,
+            Column::ULong(_) => u32::NAME,
+            Column::ULong64(_) => u64::NAME,
+        }
+    }
+
+    fn with_capacity(field: &Field, n: usize) -> Result<Column, SchemaError> {
+        match (field.format, field.size) {
+            (FormatType::CharT, 1) => Ok(Column::Char(Vec::with_capacity(n))),
+            (FormatType::ShortT, 2) => Ok(Column::Short(Vec::with_capacity(n))),
+            (FormatType::IntT, 4) => Ok(Column::Int(Vec::with_capacity(n))),
+            (FormatType::FloatT, 4) => Ok(Column::Float(Vec::with_capacity(n))),
+            (FormatType::DoubleT, 8) => Ok(Column::Double(Vec::with_capacity(n))),
+            (FormatType::ULongT, 4) => Ok(Column::ULong(Vec::with_capacity(n))),
+            (FormatType::ULongT, 8) => Ok(Column::ULong64(Vec::with_capacity(n))),
+            _ => Err(SchemaError::UnsupportedField { field: field.name.clone() }),
+        }
+    }
+
+    // `bytes` holds exactly one element
+    fn push_le(&mut self, bytes: &[u8]) {
+        match self {
+            Column::Char(vs) => vs.push(u8::decode_le(bytes)),
+            Column::Short(vs) => vs.push(i16::decode_le(bytes)),
+            Column::Int(vs) => vs.push(i32::decode_le(bytes)),
+            Column::Float(vs) => vs.push(f32::decode_le(bytes)),
+            Column::Double(vs) => vs.push(f64::decode_le(bytes)),
+            Column::ULong(vs) => vs.push(u32::decode_le(bytes)),
+            Column::ULong64(vs) => vs.push(u64::decode_le(bytes)),
+        }
+    }
+}
+
+/// Errors from looking up a column of a `RecordTable`
+#[derive(Debug, PartialEq)]
+pub enum TableError {
+    UnknownColumn { name: String },
+    WrongType { name: String, requested: &'static str, found: &'static str },
+    RecordOutOfRange { index: usize, len: usize },
+}
+
+impl fmt::Display for TableError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TableError::UnknownColumn { name } => write!(f, "no column named {:?}", name),
+            TableError::WrongType { name, requested, found } =>
+                write!(f, "column {:?} holds {}, not {}", name, found, requested),
+            TableError::RecordOutOfRange { index, len } =>
+                write!(f, "no record {} in a table of {}", index, len),
+        }
+    }
+}
+
+impl Fail for TableError {}
+
+/// The contents of an MWL binary file of any record type, decoded
+/// into one typed column per field of the file's `Fields` line
+#[derive(Clone, Debug, PartialEq)]
+pub struct RecordTable {
+    pub schema: RecordSchema,
+    columns: Vec<Column>,
+    n_records: usize,
+}
+
+impl RecordTable {
+    /// Decode every complete record in `data`. Trailing bytes that
+    /// don't make up a whole record are ignored.
+    pub fn from_bytes(schema: RecordSchema, data: &[u8]) -> Result<RecordTable, SchemaError> {
+        let n_records = schema.record_count(data.len());
+        let mut columns = schema
+            .fields
+            .iter()
+            .map(|f| Column::with_capacity(f, n_records * f.count))
+            .collect::<Result<Vec<Column>, SchemaError>>()?;
+
+        let record_size = schema.record_size();
+        for record in data.chunks_exact(record_size.max(1)).take(n_records) {
+            let mut offset = 0;
+            for (field, column) in schema.fields.iter().zip(columns.iter_mut()) {
+                for element in record[offset..offset + field.byte_len()].chunks(field.size) {
+                    column.push_le(element);
+                }
+                offset += field.byte_len();
+            }
+        }
+        Ok(RecordTable { schema, columns, n_records })
+    }
+
+    /// Decode the binary data that follows a parsed header
+    pub fn from_metadata(metadata: &Metadata, data: &[u8]) -> Result<RecordTable, Error> {
+        if let Some(file_type) = header::lookup(metadata, "File type") {
+            if file_type != "Binary" {
+                return Err(Error::bad_value("File type", file_type, "Binary"));
+            }
+        }
+        let schema = RecordSchema::from_metadata(metadata)?;
+        Ok(RecordTable::from_bytes(schema, data)?)
+    }
+
+    /// Number of records
+    pub fn len(&self) -> usize {
+        self.n_records
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.n_records == 0
+    }
+
+    pub fn column_raw(&self, name: &str) -> Option<&Column> {
+        self.schema
+            .fields
+            .iter()
+            .position(|f| f.name == name)
+            .map(|i| &self.columns[i])
+    }
+
+    /// All values of field `name`, e.g. `column::<i16>("waveform")`.
+    /// A field with count `n` has `n` consecutive values per record.
+    pub fn column<T: ColumnType>(&self, name: &str) -> Result<&[T], TableError> {
+        let column = self.column_raw(name)
+            .ok_or_else(|| TableError::UnknownColumn { name: name.to_owned() })?;
+        T::from_column(column).ok_or_else(|| TableError::WrongType {
+            name: name.to_owned(),
+            requested: T::NAME,
+            found: column.type_name(),
+        })
+    }
+
+    /// The values of field `name` in record `i`
+    pub fn value<T: ColumnType>(&self, name: &str, i: usize) -> Result<&[T], TableError> {
+        let values = self.column::<T>(name)?;
+        if i >= self.n_records {
+            return Err(TableError::RecordOutOfRange { index: i, len: self.n_records });
+        }
+        let count = self.schema.field(name).map_or(0, |f| f.count);
+        Ok(&values[i * count..(i + 1) * count])
+    }
+}
+
+/// Parse a header and decode the records that follow it
+pub fn parse(file_contents: &[u8]) -> Result<(Metadata<'_>, RecordTable), Error> {
+    let (metadata, data) = header::parse(file_contents)?;
+    let table = RecordTable::from_metadata(&metadata, data)?;
+    Ok((metadata, table))
+}
+
+/// Read any MWL binary file (.tt, .p, .eeg, .pxyabw, cluster files ...)
+pub fn read_table(path: &Path) -> Result<(MetadataBuf, RecordTable), Error> {
+    let read = || -> Result<(MetadataBuf, RecordTable), Error> {
+        let mut buffer = Vec::new();
+        File::open(path)?.read_to_end(&mut buffer)?;
+        let (metadata, table) = parse(buffer.as_slice())?;
+        Ok((MetadataBuf::from(&metadata), table))
+    };
+    read().map_err(|e| e.at_path(path))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(t: u32, x: i16, ys: [f32; 2]) -> Vec<u8> {
+        let mut bytes = t.to_le_bytes().to_vec();
+        bytes.extend_from_slice(&x.to_le_bytes());
+        bytes.extend_from_slice(&ys[0].to_le_bytes());
+        bytes.extend_from_slice(&ys[1].to_le_bytes());
+        bytes
+    }
+
+    fn file() -> Vec<u8> {
+        let metadata = MetadataBuf::new()
+            .pair("File type", "Binary")
+            .pair("Fields", "timestamp,8,4,1\tx,2,2,1\ty,4,4,2");
+        let mut bytes = header::to_bytes(&metadata.as_metadata()).unwrap();
+        bytes.extend(record(10, -3, [0.5, 1.5]));
+        bytes.extend(record(20, 7, [2.5, 3.5]));
+        bytes.push(0xff);
+        bytes
+    }
+
+    #[test]
+    fn it_decodes_columns() {
+        let bytes = file();
+        let (_, table) = parse(&bytes).unwrap();
+        assert_eq!(table.len(), 2);
+        assert_eq!(table.column::<u32>("timestamp").unwrap(), &[10, 20]);
+        assert_eq!(table.column::<i16>("x").unwrap(), &[-3, 7]);
+        assert_eq!(table.column::<f32>("y").unwrap(), &[0.5, 1.5, 2.5, 3.5]);
+        assert_eq!(table.value::<f32>("y", 1).unwrap(), &[2.5, 3.5]);
+    }
+
+    #[test]
+    fn it_reports_bad_column_requests() {
+        let bytes = file();
+        let (_, table) = parse(&bytes).unwrap();
+        match table.column::<f64>("x") {
+            Err(TableError::WrongType { found: "i16", .. }) => (),
+            r => panic!("expected a type error, got {:?}", r),
+        }
+        match table.column::<i16>("z") {
+            Err(TableError::UnknownColumn { .. }) => (),
+            r => panic!("expected an unknown column error, got {:?}", r),
+        }
+        match table.value::<i16>("x", 2) {
+            Err(TableError::RecordOutOfRange { index: 2, len: 2 }) => (),
+            r => panic!("expected an out of range error, got {:?}", r),
+        }
+    }
+}
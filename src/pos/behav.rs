@@ -0,0 +1,117 @@
+use super::{Coordinates, DiodePos, ProcessedPos, SampleTime};
+use crate::Error;
+use super::smooth::{smooth, SmoothConfig};
+
+/// Process diode positions as `behav` does: the position is the
+/// midpoint of the diodes (or the one diode detected), the head
+/// direction points from the back diode to the front one, and the
+/// speed is the central difference of the midpoint over neighbouring
+/// samples (one-sided at the ends). Speed is only measured between
+/// samples with both diodes detected, since the position jumps by half
+/// the diode width when one drops out; elsewhere it is NaN.
+///
+/// Times may be in seconds or raw ticks; raw ticks are written to the
+/// output unchanged.
+pub fn process<T: SampleTime>(positions: &[DiodePos<f32, T>], coordinates: Coordinates)
+                              -> Vec<ProcessedPos> {
+    let mut processed: Vec<ProcessedPos> = positions
+        .iter()
+        .map(|p| process_one(p, coordinates))
+        .collect();
+    let both = |p: &DiodePos<f32, T>| {
+        coordinates.is_detected(p.diode_front) && coordinates.is_detected(p.diode_back)
+    };
+    for i in 0..processed.len() {
+        let before = i.saturating_sub(1);
+        let after = (i + 1).min(processed.len() - 1);
+        let (a, b) = (&processed[before], &processed[after]);
+        let dt = positions[after].time.seconds() - positions[before].time.seconds();
+        processed[i].behavior = if dt > 0.0 && both(&positions[before]) && both(&positions[after]) {
+            ((b.x - a.x).hypot(b.y - a.y) as f64 / dt) as f32
+        } else {
+            f32::NAN
+        };
+    }
+    processed
+}
+
+/// Process diode positions as `process` does, but taking position,
+/// head direction and speed from the Kalman smoother rather than from
+/// single samples and finite differences. The diode width is still
+/// measured sample by sample. Fails if `config` doesn't validate.
+pub fn process_smoothed<T: SampleTime>(positions: &[DiodePos<f32, T>], config: &SmoothConfig)
+                                       -> Result<Vec<ProcessedPos>, Error> {
+    Ok(positions
+        .iter()
+        .zip(smooth(positions, config)?)
+        .map(|(p, s)| ProcessedPos {
+            x: s.x,
+            y: s.y,
+            angle: s.heading,
+            behavior: s.speed(),
+            ..process_one(p, config.coordinates)
+        })
+        .collect())
+}
+
+fn process_one<T: SampleTime>(p: &DiodePos<f32, T>, coordinates: Coordinates) -> ProcessedPos {
+    let (front, back) = (p.diode_front, p.diode_back);
+    let nan = f32::NAN;
+    let ((x, y), angle, width) = match (coordinates.is_detected(front), coordinates.is_detected(back)) {
+        (true, true) => {
+            let (dx, dy) = (front.0 - back.0, front.1 - back.1);
+            (((front.0 + back.0) / 2.0, (front.1 + back.1) / 2.0), dy.atan2(dx), dx.hypot(dy))
+        },
+        (true, false) => (front, nan, nan),
+        (false, true) => (back, nan, nan),
+        (false, false) => ((nan, nan), nan, nan),
+    };
+    ProcessedPos { timestamp: p.time.ticks(), x, y, angle, behavior: nan, width }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pos::MISSING;
+
+    #[test]
+    fn it_processes_diode_positions() {
+        let ps = vec![
+            DiodePos { time: 1.0, diode_front: (12.0, 10.0), diode_back: (8.0, 10.0) },
+            DiodePos { time: 1.5, diode_front: (13.0, 14.0), diode_back: (13.0, 10.0) },
+            DiodePos { time: 2.0, diode_front: (MISSING, MISSING), diode_back: (13.0, 16.0) },
+        ];
+        let processed = process(&ps, Coordinates::Pixels);
+        assert_eq!(processed[0].timestamp, 10_000);
+        assert_eq!((processed[0].x, processed[0].y), (10.0, 10.0));
+        assert_eq!(processed[0].angle, 0.0);
+        assert_eq!(processed[0].width, 4.0);
+        assert_eq!(processed[1].angle, std::f32::consts::FRAC_PI_2);
+        // From (10, 10) to (13, 12) in 0.5s
+        assert_eq!(processed[0].behavior, 13f32.sqrt() / 0.5);
+        // Not across the sample with only the back diode
+        assert!(processed[1].behavior.is_nan() && processed[2].behavior.is_nan());
+        assert_eq!((processed[2].x, processed[2].y), (13.0, 16.0));
+        assert!(processed[2].angle.is_nan() && processed[2].width.is_nan());
+    }
+
+    #[test]
+    fn it_keeps_raw_timestamps() {
+        // Past 2^24 ticks, f32 seconds can't hold every tick
+        let ps: Vec<DiodePos<f32, u32>> = (0..3)
+            .map(|i| DiodePos {
+                time: 16_777_216 + 333 * i + 1,
+                diode_front: (12.0 + i as f32, 10.0),
+                diode_back: (8.0 + i as f32, 10.0),
+            })
+            .collect();
+        let processed = process(&ps, Coordinates::Pixels);
+        let timestamps: Vec<u32> = processed.iter().map(|p| p.timestamp).collect();
+        assert_eq!(timestamps, vec![16_777_217, 16_777_550, 16_777_883]);
+        // 1 pixel per 333 ticks
+        assert!((processed[1].behavior - 10_000.0 / 333.0).abs() < 1e-3);
+        let smoothed = process_smoothed(&ps, &SmoothConfig::default()).unwrap();
+        assert_eq!(smoothed[2].timestamp, 16_777_883);
+    }
+}
@@ -0,0 +1,388 @@
+use std::fs;
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+
+use crate::config;
+use crate::Error;
+use super::{Coordinates, DiodePos};
+
+/// How camera pixels map to arena coordinates (in centimeters), as
+/// stored in a calibration file. The file is TOML (or JSON, see
+/// `config`), with a `model` key picking one of the cases, for example
+///
+/// ```toml
+/// model = "similarity"
+/// scale = 0.25              # cm per pixel
+/// rotation = 0.0            # radians, counterclockwise
+/// translation = [-40.0, -30.0]
+/// ```
+///
+/// or, with the arena coordinates of points marked in a camera frame,
+///
+/// ```toml
+/// model = "reference_points"
+///
+/// [[points]]
+/// pixel = [102.0, 87.0]
+/// arena = [0.0, 0.0]
+/// ```
+///
+/// and at least three more `[[points]]`.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "model", rename_all = "snake_case")]
+pub enum Calibration {
+    /// Scale about the pixel origin, then rotate, then translate
+    Similarity {
+        scale: f64,
+        rotation: f64,
+        translation: (f64, f64),
+    },
+    /// The homography matrix, taking `(x, y, 1)` in pixels to arena
+    /// coordinates in homogeneous form
+    Homography { matrix: [[f64; 3]; 3] },
+    /// The homography that best fits reference points, in the least
+    /// squares sense. Needs at least four points, no three of them on
+    /// a line; with more, errors in marking them average out.
+    ReferencePoints { points: Vec<ReferencePoint> },
+}
+
+/// A point marked in a camera frame, and where it is in the arena
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ReferencePoint {
+    pub pixel: (f64, f64),
+    pub arena: (f64, f64),
+}
+
+impl Calibration {
+    pub fn load(path: &Path) -> Result<Calibration, Error> {
+        config::load(path)
+    }
+
+    pub fn from_toml(s: &str) -> Result<Calibration, Error> {
+        config::from_toml(s)
+    }
+
+    pub fn to_toml(&self) -> String {
+        toml::to_string(self).expect("a calibration is always representable as TOML")
+    }
+
+    pub fn save(&self, path: &Path) -> Result<(), Error> {
+        fs::write(path, self.to_toml()).map_err(|e| Error::from(e).at_path(path))
+    }
+
+    /// The transform from pixels to arena coordinates
+    pub fn homography(&self) -> Result<Homography, Error> {
+        match self {
+            Calibration::Similarity { scale, rotation, translation } => {
+                if !(scale.is_finite() && *scale > 0.0) {
+                    return Err(Error::bad_value("scale", scale.to_string(), "a positive number"));
+                }
+                if !rotation.is_finite() {
+                    return Err(Error::bad_value("rotation", rotation.to_string(),
+                                                "an angle in radians"));
+                }
+                // Only the translation is left to be other than finite
+                Homography::similarity(*scale, *rotation, *translation).ok_or_else(|| {
+                    Error::bad_value("translation", format!("{:?}", translation), "finite coordinates")
+                })
+            },
+            Calibration::Homography { matrix } => Homography::new(*matrix)
+                .ok_or_else(|| Error::bad_value("matrix", format!("{:?}", matrix), "an invertible matrix")),
+            Calibration::ReferencePoints { points } => Homography::from_points(points).ok_or_else(|| {
+                Error::bad_value("points", format!("{} points", points.len()),
+                                 "at least 4 points, no 3 of them on a line")
+            }),
+        }
+    }
+}
+
+
+/// A projective transform of the plane, which covers scaling,
+/// rotation and translation as well as the perspective of a camera
+/// that doesn't look straight down on the arena
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Homography {
+    matrix: [[f64; 3]; 3],
+}
+
+impl Homography {
+    /// `None` if the matrix is singular or not finite
+    pub fn new(matrix: [[f64; 3]; 3]) -> Option<Homography> {
+        if !matrix.iter().flatten().all(|v| v.is_finite()) {
+            return None;
+        }
+        let m = matrix;
+        let det = m[0][0] * (m[1][1] * m[2][2] - m[1][2] * m[2][1])
+            - m[0][1] * (m[1][0] * m[2][2] - m[1][2] * m[2][0])
+            + m[0][2] * (m[1][0] * m[2][1] - m[1][1] * m[2][0]);
+        if det.is_finite() && det != 0.0 {
+            Some(Homography { matrix })
+        } else {
+            None
+        }
+    }
+
+    pub fn identity() -> Homography {
+        Homography { matrix: [[1.0, 0.0, 0.0], [0.0, 1.0, 0.0], [0.0, 0.0, 1.0]] }
+    }
+
+    /// Scaling by `scale`, then rotating counterclockwise by `rotation`
+    /// radians, then translating. `None` for a scale of 0 or values
+    /// that aren't finite.
+    pub fn similarity(scale: f64, rotation: f64, (tx, ty): (f64, f64)) -> Option<Homography> {
+        let (sin, cos) = rotation.sin_cos();
+        Homography::new([[scale * cos, -scale * sin, tx], [scale * sin, scale * cos, ty], [0.0, 0.0, 1.0]])
+    }
+
+    /// The homography that best maps each point's `pixel` to its
+    /// `arena`, or `None` if the points don't pin one down
+    pub fn from_points(points: &[ReferencePoint]) -> Option<Homography> {
+        if points.len() < 4 {
+            return None;
+        }
+        // Fit between normalized coordinates, which keeps the normal
+        // equations well conditioned whatever the units
+        let pixel_norm = Normalization::of(points.iter().map(|p| p.pixel))?;
+        let arena_norm = Normalization::of(points.iter().map(|p| p.arena))?;
+
+        // With h22 = 1, each point gives two equations linear in the
+        // other eight entries
+        let mut ata = [[0.0; 8]; 8];
+        let mut atb = [0.0; 8];
+        for p in points {
+            let (x, y) = pixel_norm.apply(p.pixel);
+            let (u, v) = arena_norm.apply(p.arena);
+            let rows = [([x, y, 1.0, 0.0, 0.0, 0.0, -u * x, -u * y], u),
+                        ([0.0, 0.0, 0.0, x, y, 1.0, -v * x, -v * y], v)];
+            for (row, rhs) in rows.iter() {
+                for i in 0..8 {
+                    for j in 0..8 {
+                        ata[i][j] += row[i] * row[j];
+                    }
+                    atb[i] += row[i] * rhs;
+                }
+            }
+        }
+        let h = solve(ata, atb)?;
+        let normalized = [[h[0], h[1], h[2]], [h[3], h[4], h[5]], [h[6], h[7], 1.0]];
+
+        // Undo the normalizations: arena_norm⁻¹ · normalized · pixel_norm
+        let m = multiply(&arena_norm.inverse(), &multiply(&normalized, &pixel_norm.matrix()));
+        let m22 = m[2][2];
+        if m22 == 0.0 {
+            return None;
+        }
+        let mut matrix = m;
+        matrix.iter_mut().flat_map(|r| r.iter_mut()).for_each(|v| *v /= m22);
+        Homography::new(matrix)
+    }
+
+    pub fn matrix(&self) -> [[f64; 3]; 3] {
+        self.matrix
+    }
+
+    /// Transform one point, in pixels, to arena coordinates
+    pub fn apply(&self, (x, y): (f32, f32)) -> (f32, f32) {
+        let (x, y) = (x as f64, y as f64);
+        let m = &self.matrix;
+        let w = m[2][0] * x + m[2][1] * y + m[2][2];
+        let u = (m[0][0] * x + m[0][1] * y + m[0][2]) / w;
+        let v = (m[1][0] * x + m[1][1] * y + m[1][2]) / w;
+        (u as f32, v as f32)
+    }
+}
+
+/// Transform a whole track from pixels to arena coordinates. Diodes
+/// that weren't detected are marked as `Coordinates::Arena` marks
+/// them, so the result should be processed as such.
+pub fn calibrate<T: Copy>(track: &[DiodePos<f32, T>], homography: &Homography) -> Vec<DiodePos<f32, T>> {
+    let transform = |d: (f32, f32)| if Coordinates::Pixels.is_detected(d) {
+        homography.apply(d)
+    } else {
+        Coordinates::Arena.missing()
+    };
+    track
+        .iter()
+        .map(|p| DiodePos {
+            diode_front: transform(p.diode_front),
+            diode_back: transform(p.diode_back),
+            time: p.time,
+        })
+        .collect()
+}
+
+// Translation of the centroid of a set of points to the origin, then
+// scaling to a mean distance from it of √2
+struct Normalization {
+    centroid: (f64, f64),
+    scale: f64,
+}
+
+impl Normalization {
+    fn of<I: Iterator<Item = (f64, f64)> + Clone>(points: I) -> Option<Normalization> {
+        let n = points.clone().count() as f64;
+        let (sx, sy) = points.clone().fold((0.0, 0.0), |(sx, sy), (x, y)| (sx + x, sy + y));
+        let centroid = (sx / n, sy / n);
+        let mean_distance = points.map(|(x, y)| (x - centroid.0).hypot(y - centroid.1)).sum::<f64>() / n;
+        if mean_distance.is_finite() && mean_distance > 0.0 {
+            Some(Normalization { centroid, scale: 2f64.sqrt() / mean_distance })
+        } else {
+            None
+        }
+    }
+
+    fn apply(&self, (x, y): (f64, f64)) -> (f64, f64) {
+        ((x - self.centroid.0) * self.scale, (y - self.centroid.1) * self.scale)
+    }
+
+    fn matrix(&self) -> [[f64; 3]; 3] {
+        let (s, (cx, cy)) = (self.scale, self.centroid);
+        [[s, 0.0, -s * cx], [0.0, s, -s * cy], [0.0, 0.0, 1.0]]
+    }
+
+    fn inverse(&self) -> [[f64; 3]; 3] {
+        let (s, (cx, cy)) = (1.0 / self.scale, self.centroid);
+        [[s, 0.0, cx], [0.0, s, cy], [0.0, 0.0, 1.0]]
+    }
+}
+
+fn multiply(a: &[[f64; 3]; 3], b: &[[f64; 3]; 3]) -> [[f64; 3]; 3] {
+    let mut m = [[0.0; 3]; 3];
+    for (i, row) in m.iter_mut().enumerate() {
+        for (j, v) in row.iter_mut().enumerate() {
+            *v = (0..3).map(|k| a[i][k] * b[k][j]).sum();
+        }
+    }
+    m
+}
+
+// Gaussian elimination with partial pivoting, or `None` if the system
+// is (nearly) singular
+fn solve(mut a: [[f64; 8]; 8], mut b: [f64; 8]) -> Option<[f64; 8]> {
+    const EPSILON: f64 = 1e-9;
+    for col in 0..8 {
+        let pivot = (col..8).max_by(|&i, &j| a[i][col].abs().partial_cmp(&a[j][col].abs()).unwrap())?;
+        if a[pivot][col].is_nan() || a[pivot][col].abs() < EPSILON {
+            return None;
+        }
+        a.swap(col, pivot);
+        b.swap(col, pivot);
+        let pivot_row = a[col];
+        for row in col + 1..8 {
+            let f = a[row][col] / pivot_row[col];
+            for (v, p) in a[row][col..].iter_mut().zip(&pivot_row[col..]) {
+                *v -= f * p;
+            }
+            b[row] -= f * b[col];
+        }
+    }
+    let mut x = [0.0; 8];
+    for row in (0..8).rev() {
+        let sum: f64 = (row + 1..8).map(|k| a[row][k] * x[k]).sum();
+        x[row] = (b[row] - sum) / a[row][row];
+    }
+    Some(x)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pos::MISSING;
+    use crate::pos::behav;
+    use crate::pos::clean::{clean, CleanConfig};
+
+    fn close(a: (f32, f32), b: (f32, f32)) -> bool {
+        (a.0 - b.0).abs() < 1e-3 && (a.1 - b.1).abs() < 1e-3
+    }
+
+    #[test]
+    fn it_applies_a_similarity() {
+        let h = Calibration::Similarity {
+            scale: 0.5,
+            rotation: std::f64::consts::FRAC_PI_2,
+            translation: (10.0, 0.0),
+        }.homography().unwrap();
+        assert!(close(h.apply((2.0, 0.0)), (10.0, 1.0)));
+        assert!(close(h.apply((0.0, 4.0)), (8.0, 0.0)));
+
+        let bad = Calibration::Similarity { scale: 0.0, rotation: 0.0, translation: (0.0, 0.0) };
+        assert!(bad.homography().is_err());
+        for bad in &[Calibration::Similarity { scale: 1.0, rotation: f64::NAN, translation: (0.0, 0.0) },
+                     Calibration::Similarity { scale: 1.0, rotation: 0.0, translation: (f64::NAN, 0.0) }] {
+            assert!(bad.homography().is_err());
+        }
+    }
+
+    #[test]
+    fn it_fits_reference_points() {
+        // A camera looking at a 100x80cm arena at an angle
+        let truth = Homography::new([[0.4, 0.05, -20.0], [-0.02, 0.5, -15.0], [0.0004, 0.0002, 1.0]]).unwrap();
+        let pixels = [(60.0, 40.0), (600.0, 50.0), (620.0, 450.0), (40.0, 470.0), (320.0, 240.0)];
+        let points: Vec<ReferencePoint> = pixels
+            .iter()
+            .map(|&(x, y)| {
+                let (u, v) = truth.apply((x as f32, y as f32));
+                ReferencePoint { pixel: (x, y), arena: (u as f64, v as f64) }
+            })
+            .collect();
+        let h = Calibration::ReferencePoints { points: points.clone() }.homography().unwrap();
+        for &(x, y) in [(100.0, 100.0), (500.0, 300.0)].iter() {
+            assert!(close(h.apply((x, y)), truth.apply((x, y))));
+        }
+
+        // Too few points, or points on a line, don't pin it down
+        assert!(Homography::from_points(&points[..3]).is_none());
+        let line: Vec<ReferencePoint> = (0..5)
+            .map(|i| ReferencePoint { pixel: (i as f64, 2.0 * i as f64), arena: (i as f64, 0.0) })
+            .collect();
+        assert!(Homography::from_points(&line).is_none());
+    }
+
+    #[test]
+    fn it_reads_and_writes_calibration_files() {
+        let c = Calibration::from_toml("model = \"similarity\"\n\
+                                        scale = 0.25\n\
+                                        rotation = 0.0\n\
+                                        translation = [-40.0, -30.0]\n").unwrap();
+        assert_eq!(c, Calibration::Similarity { scale: 0.25, rotation: 0.0, translation: (-40.0, -30.0) });
+
+        let c = Calibration::ReferencePoints {
+            points: vec![ReferencePoint { pixel: (1.0, 2.0), arena: (0.0, 0.0) },
+                         ReferencePoint { pixel: (3.0, 4.0), arena: (10.0, 0.0) }],
+        };
+        assert_eq!(Calibration::from_toml(&c.to_toml()).unwrap(), c);
+
+        match Calibration::from_toml("model = \"fisheye\"") {
+            Err(Error::Config { .. }) => (),
+            r => panic!("expected a config error, got {:?}", r),
+        }
+    }
+
+    #[test]
+    fn it_calibrates_tracks() {
+        let h = Homography::similarity(2.0, 0.0, (-1.0, -1.0)).unwrap();
+        let track = vec![DiodePos { diode_front: (1.0, 1.0), diode_back: (MISSING, MISSING), time: 0.5 }];
+        let calibrated = calibrate(&track, &h);
+        assert_eq!(calibrated[0].diode_front, (1.0, 1.0));
+        assert!(!Coordinates::Arena.is_detected(calibrated[0].diode_back));
+        assert_eq!(calibrated[0].time, 0.5);
+    }
+
+    #[test]
+    fn diodes_at_the_arena_origin_are_detected() {
+        let h = Homography::similarity(0.25, 0.0, (-40.0, -30.0)).unwrap();
+        let track = vec![DiodePos { diode_front: (160.0, 120.0), diode_back: (200.0, 120.0), time: 0.5 }];
+        let calibrated = calibrate(&track, &h);
+        assert_eq!(calibrated[0].diode_front, (0.0, 0.0));
+        assert!(Coordinates::Arena.is_detected(calibrated[0].diode_front));
+
+        let processed = behav::process(&calibrated, Coordinates::Arena);
+        assert_eq!((processed[0].x, processed[0].y), (5.0, 0.0));
+        assert_eq!(processed[0].width, 10.0);
+        let config = CleanConfig { coordinates: Coordinates::Arena, ..CleanConfig::default() };
+        let (_, quality) = clean(&calibrated, &config);
+        assert!(quality[0].is_usable());
+    }
+}
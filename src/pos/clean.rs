@@ -0,0 +1,217 @@
+use super::{Coordinates, DiodePos, SampleTime};
+
+/// The region the animal can be in, in pixels
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Arena {
+    pub x_min: f32,
+    pub y_min: f32,
+    pub x_max: f32,
+    pub y_max: f32,
+}
+
+impl Arena {
+    pub fn contains(&self, (x, y): (f32, f32)) -> bool {
+        x >= self.x_min && x <= self.x_max && y >= self.y_min && y <= self.y_max
+    }
+}
+
+/// How a track is cleaned
+#[derive(Clone, Debug, PartialEq)]
+pub struct CleanConfig {
+    /// Diodes outside the arena are missing. With `None`, any
+    /// detected position is accepted.
+    pub arena: Option<Arena>,
+    /// Diodes that move faster than this (in pixels per second) from
+    /// their last good position are reflections, and are rejected
+    pub max_speed: f32,
+    /// Runs of up to this many bad samples between good ones are
+    /// filled by linear interpolation
+    pub max_gap: usize,
+    pub coordinates: Coordinates,
+}
+
+impl Default for CleanConfig {
+    fn default() -> CleanConfig {
+        CleanConfig { arena: None, max_speed: 1000.0, max_gap: 5, coordinates: Coordinates::Pixels }
+    }
+}
+
+/// What cleaning made of one diode in one sample
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Quality {
+    /// Tracked, and kept as it was
+    Good,
+    /// Bad, but filled in from the good samples either side
+    Interpolated,
+    /// Not tracked, as marked by the track's `Coordinates`
+    Missing,
+    OutOfArena,
+    /// Rejected for moving faster than `max_speed`
+    Jump,
+}
+
+impl Quality {
+    /// Whether the cleaned position can be used
+    pub fn is_usable(self) -> bool {
+        self == Quality::Good || self == Quality::Interpolated
+    }
+}
+
+/// The quality of both diodes of a sample
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SampleQuality {
+    pub front: Quality,
+    pub back: Quality,
+}
+
+impl SampleQuality {
+    /// Whether both cleaned diode positions can be used
+    pub fn is_usable(&self) -> bool {
+        self.front.is_usable() && self.back.is_usable()
+    }
+}
+
+/// Clean each diode of `track` independently: mark missing and
+/// out-of-arena samples, reject jumps, and interpolate short gaps.
+/// Samples that stay bad are set to `Coordinates::missing`. Returns the cleaned
+/// track and the quality of each of its samples.
+pub fn clean<T: SampleTime>(track: &[DiodePos<f32, T>], config: &CleanConfig)
+                            -> (Vec<DiodePos<f32, T>>, Vec<SampleQuality>) {
+    let times: Vec<f64> = track.iter().map(|p| p.time.seconds()).collect();
+    let fronts: Vec<(f32, f32)> = track.iter().map(|p| p.diode_front).collect();
+    let backs: Vec<(f32, f32)> = track.iter().map(|p| p.diode_back).collect();
+    let (fronts, front_quality) = clean_diode(&times, fronts, config);
+    let (backs, back_quality) = clean_diode(&times, backs, config);
+    let cleaned = (0..track.len())
+        .map(|i| DiodePos { diode_front: fronts[i], diode_back: backs[i], time: track[i].time })
+        .collect();
+    let quality = front_quality
+        .into_iter()
+        .zip(back_quality)
+        .map(|(front, back)| SampleQuality { front, back })
+        .collect();
+    (cleaned, quality)
+}
+
+fn clean_diode(times: &[f64], mut diode: Vec<(f32, f32)>, config: &CleanConfig)
+               -> (Vec<(f32, f32)>, Vec<Quality>) {
+    let mut quality: Vec<Quality> = diode
+        .iter()
+        .map(|d| match config.arena {
+            _ if !config.coordinates.is_detected(*d) => Quality::Missing,
+            Some(arena) if !arena.contains(*d) => Quality::OutOfArena,
+            _ => Quality::Good,
+        })
+        .collect();
+
+    // Compare each sample with the last good one. After a run of
+    // rejections too long to fill, take the next sample as good, so
+    // that a bad first sample can't reject the rest of the track.
+    let mut last_good: Option<usize> = None;
+    let mut rejected = 0;
+    for i in 0..diode.len() {
+        if quality[i] != Quality::Good {
+            continue;
+        }
+        if let Some(j) = last_good {
+            let dt = (times[i] - times[j]) as f32;
+            let distance = (diode[i].0 - diode[j].0).hypot(diode[i].1 - diode[j].1);
+            if rejected <= config.max_gap && (dt <= 0.0 || distance / dt > config.max_speed) {
+                quality[i] = Quality::Jump;
+                rejected += 1;
+                continue;
+            }
+        }
+        last_good = Some(i);
+        rejected = 0;
+    }
+
+    let mut i = 0;
+    while i < diode.len() {
+        if quality[i] == Quality::Good {
+            i += 1;
+            continue;
+        }
+        let start = i;
+        while i < diode.len() && quality[i] != Quality::Good {
+            i += 1;
+        }
+        let fillable = start > 0 && i < diode.len() && i - start <= config.max_gap;
+        for k in start..i {
+            if fillable {
+                let (a, b) = (start - 1, i);
+                let f = ((times[k] - times[a]) / (times[b] - times[a])) as f32;
+                diode[k] = (diode[a].0 + f * (diode[b].0 - diode[a].0),
+                            diode[a].1 + f * (diode[b].1 - diode[a].1));
+                quality[k] = Quality::Interpolated;
+            } else {
+                diode[k] = config.coordinates.missing();
+            }
+        }
+    }
+    (diode, quality)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pos::MISSING;
+
+    fn track(fronts: &[(f32, f32)]) -> Vec<DiodePos<f32, f32>> {
+        fronts
+            .iter()
+            .enumerate()
+            .map(|(i, f)| DiodePos { diode_front: *f, diode_back: (50.0, 50.0), time: i as f32 * 0.1 })
+            .collect()
+    }
+
+    #[test]
+    fn it_fills_short_gaps() {
+        let config = CleanConfig {
+            arena: Some(Arena { x_min: 1.0, y_min: 1.0, x_max: 100.0, y_max: 100.0 }),
+            max_speed: 100.0,
+            max_gap: 2,
+            ..CleanConfig::default()
+        };
+        let t = track(&[(10.0, 10.0), (0.0, 0.0), (90.0, 90.0), (13.0, 13.0),
+                        (14.0, 14.0), (200.0, 14.0), (16.0, 16.0)]);
+        let (cleaned, quality) = clean(&t, &config);
+        let fronts: Vec<Quality> = quality.iter().map(|q| q.front).collect();
+        assert_eq!(fronts, vec![Quality::Good, Quality::Interpolated, Quality::Interpolated,
+                                Quality::Good, Quality::Good, Quality::Interpolated, Quality::Good]);
+        assert!(quality.iter().all(|q| q.back == Quality::Good));
+        assert_eq!(cleaned[1].diode_front, (11.0, 11.0));
+        assert_eq!(cleaned[5].diode_front, (15.0, 15.0));
+        assert_eq!(cleaned[6], t[6]);
+    }
+
+    #[test]
+    fn it_leaves_long_gaps_missing() {
+        let config = CleanConfig { max_gap: 1, ..CleanConfig::default() };
+        let t = track(&[(0.0, 0.0), (10.0, 10.0), (0.0, 0.0), (0.0, 0.0), (12.0, 12.0)]);
+        let (cleaned, quality) = clean(&t, &config);
+        let fronts: Vec<Quality> = quality.iter().map(|q| q.front).collect();
+        assert_eq!(fronts, vec![Quality::Missing, Quality::Good, Quality::Missing,
+                                Quality::Missing, Quality::Good]);
+        assert!(!quality[2].is_usable());
+        assert_eq!(cleaned[3].diode_front, (MISSING, MISSING));
+    }
+
+    #[test]
+    fn it_keeps_raw_timestamps() {
+        let config = CleanConfig { max_speed: 100.0, ..CleanConfig::default() };
+        // Past 2^24 ticks, an f32 time can't hold every tick
+        let t: Vec<DiodePos<f32, u32>> = track(&[(10.0, 10.0), (0.0, 0.0), (12.0, 12.0)])
+            .into_iter()
+            .enumerate()
+            .map(|(i, p)| DiodePos { time: 16_777_217 + 1000 * i as u32, diode_front: p.diode_front,
+                                     diode_back: p.diode_back })
+            .collect();
+        let (cleaned, quality) = clean(&t, &config);
+        assert_eq!(quality[1].front, Quality::Interpolated);
+        assert_eq!(cleaned[1].diode_front, (11.0, 11.0));
+        let times: Vec<u32> = cleaned.iter().map(|p| p.time).collect();
+        assert_eq!(times, vec![16_777_217, 16_778_217, 16_779_217]);
+    }
+}
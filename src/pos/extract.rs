@@ -0,0 +1,162 @@
+use crate::Error;
+use super::{DiodeId, DiodeImage, DiodePos, MISSING};
+
+/// How diode images are reduced to positions
+#[derive(Clone, Debug, PartialEq)]
+pub struct CentroidConfig {
+    /// Frames with fewer pixels than this are rejected
+    pub min_pixels: usize,
+    /// Pixels further than this from the median pixel (in pixels)
+    /// are outliers
+    pub cluster_radius: f32,
+    /// Frames with a larger fraction of outliers than this are
+    /// rejected, since the diode can't be told from a reflection
+    pub max_outliers: f32,
+}
+
+impl Default for CentroidConfig {
+    fn default() -> CentroidConfig {
+        CentroidConfig { min_pixels: 1, cluster_radius: 10.0, max_outliers: 0.25 }
+    }
+}
+
+/// The centroid of the pixels of a diode image, leaving out outliers,
+/// or `None` if the frame is rejected
+pub fn diode_centroid(image: &DiodeImage<u16, u32>, config: &CentroidConfig) -> Option<(f32, f32)> {
+    if image.coords.is_empty() || image.coords.len() < config.min_pixels {
+        return None;
+    }
+    let xs: Vec<f32> = image.coords.iter().map(|(x, _)| *x as f32).collect();
+    let ys: Vec<f32> = image.coords.iter().map(|(_, y)| *y as f32).collect();
+    let (mx, my) = (median(&xs), median(&ys));
+    let inliers: Vec<(f32, f32)> = xs
+        .into_iter()
+        .zip(ys)
+        .filter(|(x, y)| (x - mx).hypot(y - my) <= config.cluster_radius)
+        .collect();
+    let outliers = image.coords.len() - inliers.len();
+    if inliers.is_empty() || outliers as f32 > config.max_outliers * image.coords.len() as f32 {
+        return None;
+    }
+    let n = inliers.len() as f32;
+    let (sx, sy) = inliers.iter().fold((0.0, 0.0), |(sx, sy), (x, y)| (sx + x, sy + y));
+    Some((sx / n, sy / n))
+}
+
+fn median(vs: &[f32]) -> f32 {
+    let mut sorted = vs.to_vec();
+    sorted.sort_by(|a, b| a.partial_cmp(b).unwrap());
+    let mid = sorted.len() / 2;
+    if sorted.len().is_multiple_of(2) {
+        (sorted[mid - 1] + sorted[mid]) / 2.0
+    } else {
+        sorted[mid]
+    }
+}
+
+/// Pairs each front diode frame with the back diode frame that
+/// follows it, yielding one position per pair at the time of the
+/// front frame, in ticks of 100us. A diode whose frame is missing or
+/// rejected is placed at `MISSING`.
+pub struct Positions<I> {
+    frames: I,
+    config: CentroidConfig,
+    pending: Option<DiodeImage<u16, u32>>,
+}
+
+impl<I> Positions<I>
+where
+    I: Iterator<Item = Result<DiodeImage<u16, u32>, Error>>,
+{
+    pub fn new(frames: I, config: CentroidConfig) -> Positions<I> {
+        Positions { frames, config, pending: None }
+    }
+
+    fn pair(&self, front: Option<&DiodeImage<u16, u32>>,
+            back: Option<&DiodeImage<u16, u32>>) -> DiodePos<f32, u32> {
+        let centroid = |image: Option<&DiodeImage<u16, u32>>| image
+            .and_then(|i| diode_centroid(i, &self.config))
+            .unwrap_or((MISSING, MISSING));
+        let time = front.or(back).map_or(0, |i| i.time);
+        DiodePos {
+            diode_front: centroid(front),
+            diode_back: centroid(back),
+            time,
+        }
+    }
+}
+
+impl<I> Iterator for Positions<I>
+where
+    I: Iterator<Item = Result<DiodeImage<u16, u32>, Error>>,
+{
+    type Item = Result<DiodePos<f32, u32>, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let frame = match self.frames.next() {
+                Some(Ok(frame)) => frame,
+                Some(Err(e)) => return Some(Err(e)),
+                None => return self.pending.take().map(|front| Ok(self.pair(Some(&front), None))),
+            };
+            match (frame.diode_id, self.pending.take()) {
+                (DiodeId::Front, None) => self.pending = Some(frame),
+                (DiodeId::Front, Some(front)) => {
+                    self.pending = Some(frame);
+                    return Some(Ok(self.pair(Some(&front), None)));
+                },
+                (DiodeId::Back, front) => return Some(Ok(self.pair(front.as_ref(), Some(&frame)))),
+            }
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn image(diode_id: DiodeId, time: u32, coords: Vec<(u16, u16)>) -> Result<DiodeImage<u16, u32>, Error> {
+        Ok(DiodeImage { diode_id, coords, time })
+    }
+
+    #[test]
+    fn it_rejects_outlier_pixels() {
+        let config = CentroidConfig::default();
+        let mut coords = vec![(100, 50), (102, 50), (100, 52), (102, 52)];
+        let i = DiodeImage { diode_id: DiodeId::Front, coords: coords.clone(), time: 0 };
+        assert_eq!(diode_centroid(&i, &config), Some((101.0, 51.0)));
+
+        // A stray pixel is left out of the centroid
+        coords.push((300, 50));
+        let i = DiodeImage { coords: coords.clone(), ..i };
+        assert_eq!(diode_centroid(&i, &config), Some((101.0, 51.0)));
+
+        // Two clusters of similar size can't be told apart
+        coords.extend(vec![(300, 52), (302, 50)]);
+        let i = DiodeImage { coords, ..i };
+        assert_eq!(diode_centroid(&i, &config), None);
+
+        let empty = DiodeImage { coords: vec![], ..i };
+        assert_eq!(diode_centroid(&empty, &config), None);
+    }
+
+    #[test]
+    fn it_pairs_front_and_back_frames() {
+        let frames = vec![
+            image(DiodeId::Front, 100, vec![(10, 10)]),
+            image(DiodeId::Back, 110, vec![(20, 20)]),
+            image(DiodeId::Front, 200, vec![(11, 11)]),
+            image(DiodeId::Front, 300, vec![]),
+            image(DiodeId::Back, 310, vec![(21, 21)]),
+        ];
+        let ps: Vec<DiodePos<f32, u32>> = Positions::new(frames.into_iter(), CentroidConfig::default())
+            .map(Result::unwrap)
+            .collect();
+        assert_eq!(ps, vec![
+            DiodePos { diode_front: (10.0, 10.0), diode_back: (20.0, 20.0), time: 100 },
+            DiodePos { diode_front: (11.0, 11.0), diode_back: (MISSING, MISSING), time: 200 },
+            DiodePos { diode_front: (MISSING, MISSING), diode_back: (21.0, 21.0), time: 300 },
+        ]);
+    }
+}
@@ -0,0 +1,243 @@
+use std::ops::Range;
+
+use crate::Error;
+use crate::interval::IntervalSet;
+use crate::mwl_ad::TICKS_PER_SECOND;
+use super::linearize::{Direction, LinearPos, TrackGraph};
+
+/// How laps are found
+#[derive(Clone, Debug, PartialEq)]
+pub struct LapConfig {
+    /// The nodes of the track graph that laps run between. With
+    /// `None`, the ends of the track (`TrackGraph::ends`).
+    pub wells: Option<Vec<usize>>,
+    /// How far along the track from a well the animal can be and still
+    /// be at it
+    pub well_radius: f32,
+    /// The center well of a W-maze, where outbound trials start. With
+    /// `None`, laps aren't classified into trials.
+    pub home_well: Option<usize>,
+    /// Traversals that take longer than this, in seconds, are
+    /// rejected
+    pub max_duration: Option<f64>,
+    /// Losing track of the animal (off the track, or no samples) for
+    /// longer than this, in seconds, abandons the traversal under way
+    pub max_gap: f64,
+}
+
+impl Default for LapConfig {
+    fn default() -> LapConfig {
+        LapConfig { wells: None, well_radius: 10.0, home_well: None, max_duration: None, max_gap: 1.0 }
+    }
+}
+
+/// The kind of a W-maze trial
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Trial {
+    /// From the home well out to one of the others
+    Outbound,
+    /// From an outer well, which is correct if it ends at the home
+    /// well
+    Inbound,
+}
+
+/// A traversal from one well to another
+#[derive(Clone, Debug, PartialEq)]
+pub struct Lap {
+    /// The last sample at the well the lap starts from, in ticks of
+    /// 100us
+    pub start: u32,
+    /// The first sample at the well the lap ends at
+    pub end: u32,
+    pub from_well: usize,
+    pub to_well: usize,
+    /// `Increasing` if the end well is further along the linear track
+    /// than the start well, `Decreasing` if it is nearer, and `Unknown`
+    /// if they are at the same linear position
+    pub direction: Direction,
+    pub trial: Option<Trial>,
+}
+
+impl Lap {
+    pub fn interval(&self) -> Range<u32> {
+        self.start..self.end
+    }
+
+    /// In seconds, or 0 if the lap ends before it starts
+    pub fn duration(&self) -> f64 {
+        self.end.saturating_sub(self.start) as f64 / TICKS_PER_SECOND
+    }
+}
+
+/// Find the laps in a linearized track: each time the animal leaves a
+/// well and next reaches a different one. Traversals that return to the
+/// well they started from, that lose track of the animal for more than
+/// `max_gap`, or that take longer than `max_duration`, are rejected.
+/// The wells and home well must be nodes of `graph`, which must be the
+/// graph `linear` was projected onto.
+pub fn detect_laps(linear: &[LinearPos], graph: &TrackGraph, config: &LapConfig)
+                   -> Result<Vec<Lap>, Error> {
+    let wells = config.wells.clone().unwrap_or_else(|| graph.ends());
+    let home = config.home_well.iter().map(|w| ("home_well", w));
+    for (key, &node) in wells.iter().map(|w| ("wells", w)).chain(home) {
+        if node >= graph.nodes.len() {
+            return Err(Error::bad_value(key, node.to_string(),
+                                        format!("a node index below {}", graph.nodes.len())));
+        }
+    }
+    let offsets = graph.edge_offsets();
+    let well_at = |p: &LinearPos| -> Option<usize> {
+        let e = p.edge?;
+        let (&(a, b), offset) = (graph.edges.get(e)?, offsets.get(e)?);
+        let along = p.position - offset;
+        wells.iter().copied().find(|&w| {
+            (w == a && along <= config.well_radius)
+                || (w == b && graph.edge_length(e) - along <= config.well_radius)
+        })
+    };
+    let max_gap = (config.max_gap * TICKS_PER_SECOND) as u32;
+
+    let mut laps = Vec::new();
+    // The well last visited, and the time of the last sample there
+    let mut departure: Option<(usize, u32)> = None;
+    let mut last_seen: Option<u32> = None;
+    for p in linear.iter().filter(|p| p.edge.is_some()) {
+        if last_seen.is_some_and(|t| p.timestamp.saturating_sub(t) > max_gap) {
+            departure = None;
+        }
+        last_seen = Some(p.timestamp);
+        let well = match well_at(p) {
+            Some(w) => w,
+            None => continue,
+        };
+        if let Some((from, start)) = departure {
+            if from != well {
+                let lap = new_lap(graph, config, from, well, start, p.timestamp);
+                if config.max_duration.is_none_or(|max| lap.duration() <= max) {
+                    laps.push(lap);
+                }
+            }
+        }
+        departure = Some((well, p.timestamp));
+    }
+    Ok(laps)
+}
+
+/// The times of the laps, to filter spikes and positions by
+pub fn lap_intervals(laps: &[Lap]) -> IntervalSet {
+    laps.iter().map(Lap::interval).collect()
+}
+
+fn new_lap(graph: &TrackGraph, config: &LapConfig, from_well: usize, to_well: usize,
+           start: u32, end: u32) -> Lap {
+    let direction = match (graph.node_position(from_well), graph.node_position(to_well)) {
+        (Some(a), Some(b)) if b > a => Direction::Increasing,
+        (Some(a), Some(b)) if b < a => Direction::Decreasing,
+        _ => Direction::Unknown,
+    };
+    let trial = config.home_well.map(|home| if from_well == home {
+        Trial::Outbound
+    } else {
+        Trial::Inbound
+    });
+    Lap { start, end, from_well, to_well, direction, trial }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pos::linearize::{linearize, LinearizeConfig};
+    use crate::pos::ProcessedPos;
+
+    // A W-maze: the center arm from the home well (node 0) to the
+    // junction, then the left and right arms
+    const W_MAZE: &str = "nodes = [[50.0, 0.0], [50.0, 80.0], [0.0, 80.0], [0.0, 0.0], \
+                                   [100.0, 80.0], [100.0, 0.0]]\n\
+                          edges = [[0, 1], [1, 2], [2, 3], [1, 4], [4, 5]]\n";
+
+    // Visit each point in turn, 1s apart
+    fn visit(points: &[(f32, f32)]) -> Vec<ProcessedPos> {
+        points
+            .iter()
+            .enumerate()
+            .map(|(i, &(x, y))| ProcessedPos {
+                timestamp: i as u32 * 10_000,
+                x,
+                y,
+                angle: f32::NAN,
+                behavior: f32::NAN,
+                width: f32::NAN,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn it_finds_w_maze_trials() {
+        let graph = TrackGraph::from_toml(W_MAZE).unwrap();
+        assert_eq!(graph.ends(), vec![0, 3, 5]);
+        let positions = visit(&[
+            (50.0, 0.0), (50.0, 2.0), (50.0, 40.0), (50.0, 80.0), (0.0, 60.0), (0.0, 3.0),
+            // Back, but turning around halfway
+            (0.0, 40.0), (0.0, 5.0),
+            (0.0, 70.0), (50.0, 60.0), (50.0, 1.0),
+            (50.0, 50.0), (100.0, 70.0), (100.0, 0.0),
+        ]);
+        let linear = linearize(&positions, &graph, &LinearizeConfig::default()).unwrap();
+        let config = LapConfig { home_well: Some(0), ..LapConfig::default() };
+        let laps = detect_laps(&linear, &graph, &config).unwrap();
+
+        let summary: Vec<(usize, usize, Option<Trial>)> =
+            laps.iter().map(|l| (l.from_well, l.to_well, l.trial)).collect();
+        assert_eq!(summary, vec![(0, 3, Some(Trial::Outbound)), (3, 0, Some(Trial::Inbound)),
+                                 (0, 5, Some(Trial::Outbound))]);
+        assert_eq!(laps[0].interval(), 10_000..50_000);
+        assert_eq!(laps[0].duration(), 4.0);
+        assert_eq!(laps[1].start, 70_000);
+        assert_eq!(laps[0].direction, Direction::Increasing);
+        assert_eq!(laps[1].direction, Direction::Decreasing);
+
+        // The inbound and outbound laps meet at the home well
+        let intervals = lap_intervals(&laps);
+        assert_eq!(intervals.intervals(), &[10_000..50_000, 70_000..130_000]);
+        assert!(intervals.contains(30_000) && !intervals.contains(60_000));
+
+        let config = LapConfig { max_duration: Some(3.5), ..config };
+        assert_eq!(detect_laps(&linear, &graph, &config).unwrap().len(), 2);
+
+        let config = LapConfig { home_well: Some(6), ..LapConfig::default() };
+        match detect_laps(&linear, &graph, &config) {
+            Err(Error::BadValue { key, .. }) => assert_eq!(key, "home_well"),
+            r => panic!("expected a bad value, got {:?}", r),
+        }
+        let config = LapConfig { wells: Some(vec![0, 9]), ..LapConfig::default() };
+        assert!(detect_laps(&linear, &graph, &config).is_err());
+    }
+
+    #[test]
+    fn it_tolerates_inconsistent_input() {
+        let graph = TrackGraph::from_toml("nodes = [[0.0, 0.0], [100.0, 0.0]]\nedges = [[0, 1]]\n").unwrap();
+        // Projected onto a bigger graph than the one given
+        let stray = LinearPos { timestamp: 0, position: 5.0, edge: Some(3), distance: 0.0,
+                                direction: Direction::Unknown };
+        assert!(detect_laps(&[stray], &graph, &LapConfig::default()).unwrap().is_empty());
+
+        let lap = Lap { start: 20_000, end: 10_000, from_well: 0, to_well: 1,
+                        direction: Direction::Increasing, trial: None };
+        assert_eq!(lap.duration(), 0.0);
+        assert_eq!(new_lap(&graph, &LapConfig::default(), 0, 7, 0, 1).direction, Direction::Unknown);
+    }
+
+    #[test]
+    fn losing_the_animal_abandons_a_traversal() {
+        let graph = TrackGraph::from_toml("nodes = [[0.0, 0.0], [100.0, 0.0]]\nedges = [[0, 1]]\n").unwrap();
+        let mut positions = visit(&[(0.0, 0.0), (30.0, 0.0), (60.0, 0.0), (100.0, 0.0)]);
+        let linear = linearize(&positions, &graph, &LinearizeConfig::default()).unwrap();
+        assert_eq!(detect_laps(&linear, &graph, &LapConfig::default()).unwrap().len(), 1);
+
+        positions[1].x = f32::NAN;
+        positions[2].x = f32::NAN;
+        let linear = linearize(&positions, &graph, &LinearizeConfig::default()).unwrap();
+        assert!(detect_laps(&linear, &graph, &LapConfig::default()).unwrap().is_empty());
+    }
+}
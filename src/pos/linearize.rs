@@ -0,0 +1,303 @@
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+
+use crate::config;
+use crate::mwl_ad::TICKS_PER_SECOND;
+use crate::Error;
+use super::ProcessedPos;
+
+/// The paths an animal can take through a maze, as straight edges
+/// between nodes, in arena coordinates. Loaded from a config file
+/// such as
+///
+/// ```toml
+/// # A W-maze: center arm, then the left and right arms
+/// nodes = [[50.0, 0.0], [50.0, 80.0], [0.0, 80.0], [0.0, 0.0], [100.0, 80.0], [100.0, 0.0]]
+/// edges = [[0, 1], [1, 2], [2, 3], [1, 4], [4, 5]]
+/// edge_spacing = 15.0
+/// ```
+///
+/// Linear position runs along the edges in the order they are listed,
+/// each edge from its first node to its second.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct TrackGraph {
+    pub nodes: Vec<(f32, f32)>,
+    /// Pairs of indices into `nodes`
+    pub edges: Vec<(usize, usize)>,
+    /// Linear distance left between the end of one edge and the start
+    /// of the next, so that edges that don't meet in the maze aren't
+    /// contiguous in linear position
+    #[serde(default)]
+    pub edge_spacing: f32,
+}
+
+impl TrackGraph {
+    pub fn load(path: &Path) -> Result<TrackGraph, Error> {
+        let graph: TrackGraph = config::load(path)?;
+        graph.validate().map_err(|e| e.at_path(path))?;
+        Ok(graph)
+    }
+
+    pub fn from_toml(s: &str) -> Result<TrackGraph, Error> {
+        let graph: TrackGraph = config::from_toml(s)?;
+        graph.validate()?;
+        Ok(graph)
+    }
+
+    pub fn from_json(s: &str) -> Result<TrackGraph, Error> {
+        let graph: TrackGraph = config::from_json(s)?;
+        graph.validate()?;
+        Ok(graph)
+    }
+
+    /// Check that there is at least one edge, and that each edge joins
+    /// two distinct nodes that exist
+    pub fn validate(&self) -> Result<(), Error> {
+        if self.edges.is_empty() {
+            return Err(Error::bad_value("edges", "[]", "at least one edge"));
+        }
+        for &(a, b) in self.edges.iter() {
+            if a >= self.nodes.len() || b >= self.nodes.len() {
+                return Err(Error::bad_value("edges", format!("[{}, {}]", a, b),
+                                            format!("node indices below {}", self.nodes.len())));
+            }
+            if self.nodes[a] == self.nodes[b] {
+                return Err(Error::bad_value("edges", format!("[{}, {}]", a, b),
+                                            "an edge between nodes in different places"));
+            }
+        }
+        if self.edge_spacing.is_nan() || self.edge_spacing < 0.0 {
+            return Err(Error::bad_value("edge_spacing", self.edge_spacing.to_string(),
+                                        "a distance of 0 or more"));
+        }
+        Ok(())
+    }
+
+    pub fn edge_length(&self, edge: usize) -> f32 {
+        let (a, b) = self.edges[edge];
+        let ((xa, ya), (xb, yb)) = (self.nodes[a], self.nodes[b]);
+        (xb - xa).hypot(yb - ya)
+    }
+
+    /// The linear position of the start of each edge
+    pub fn edge_offsets(&self) -> Vec<f32> {
+        let mut offset = 0.0;
+        (0..self.edges.len())
+            .map(|e| {
+                let start = offset;
+                offset += self.edge_length(e) + self.edge_spacing;
+                start
+            })
+            .collect()
+    }
+
+    /// The nodes on only one edge: the ends of a linear track, or the
+    /// ends of the arms of a maze
+    pub fn ends(&self) -> Vec<usize> {
+        (0..self.nodes.len())
+            .filter(|&n| self.edges.iter().filter(|&&(a, b)| a == n || b == n).count() == 1)
+            .collect()
+    }
+
+    /// The linear position of a node, on the first edge it is on
+    pub fn node_position(&self, node: usize) -> Option<f32> {
+        let offsets = self.edge_offsets();
+        self.edges.iter().enumerate().find_map(|(e, &(a, b))| match node {
+            n if n == a => Some(offsets[e]),
+            n if n == b => Some(offsets[e] + self.edge_length(e)),
+            _ => None,
+        })
+    }
+
+    /// The nearest point of the graph to `(x, y)`, as the edge it is
+    /// on, the fraction of the way along that edge, and the distance
+    /// to it. Ties go to the edge listed first.
+    pub fn project(&self, (x, y): (f32, f32)) -> Option<(usize, f32, f32)> {
+        if x.is_nan() || y.is_nan() {
+            return None;
+        }
+        self.edges
+            .iter()
+            .enumerate()
+            .map(|(e, &(a, b))| {
+                let ((xa, ya), (xb, yb)) = (self.nodes[a], self.nodes[b]);
+                let (dx, dy) = (xb - xa, yb - ya);
+                let t = (((x - xa) * dx + (y - ya) * dy) / (dx * dx + dy * dy)).clamp(0.0, 1.0);
+                let distance = (xa + t * dx - x).hypot(ya + t * dy - y);
+                (e, t, distance)
+            })
+            .fold(None, |best: Option<(usize, f32, f32)>, p| match best {
+                Some(b) if b.2 <= p.2 => Some(b),
+                _ => Some(p),
+            })
+    }
+}
+
+/// How positions are linearized
+#[derive(Clone, Debug, PartialEq)]
+pub struct LinearizeConfig {
+    /// Positions further than this from every edge are left off the
+    /// track. With `None`, every position is projected.
+    pub max_distance: Option<f32>,
+    /// Below this speed along the track, in units per second, the
+    /// animal isn't going either way
+    pub min_speed: f32,
+}
+
+impl Default for LinearizeConfig {
+    fn default() -> LinearizeConfig {
+        LinearizeConfig { max_distance: None, min_speed: 2.0 }
+    }
+}
+
+/// Which way along the track an animal is going
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Direction {
+    /// Towards larger linear positions
+    Increasing,
+    Decreasing,
+    /// Slower than `min_speed`
+    Stationary,
+    /// Off the track, or with no neighbouring sample on the same edge
+    Unknown,
+}
+
+/// A position projected onto a track graph
+#[derive(Clone, Debug, PartialEq)]
+pub struct LinearPos {
+    /// In ticks of 100us
+    pub timestamp: u32,
+    /// Distance along the track, or NaN off the track
+    pub position: f32,
+    /// Index of the edge the position was projected onto
+    pub edge: Option<usize>,
+    /// Distance from the 2D position to the track
+    pub distance: f32,
+    pub direction: Direction,
+}
+
+impl LinearPos {
+    /// The timestamp in seconds
+    pub fn time(&self) -> f64 {
+        self.timestamp as f64 / TICKS_PER_SECOND
+    }
+}
+
+/// Project each position onto the nearest edge of `graph`, and work
+/// out the direction of travel along the track from the neighbouring
+/// samples on the same edge
+pub fn linearize(positions: &[ProcessedPos], graph: &TrackGraph, config: &LinearizeConfig)
+                 -> Result<Vec<LinearPos>, Error> {
+    graph.validate()?;
+    let offsets = graph.edge_offsets();
+    let mut linear: Vec<LinearPos> = positions
+        .iter()
+        .map(|p| {
+            let projection = graph
+                .project((p.x, p.y))
+                .filter(|&(_, _, d)| config.max_distance.is_none_or(|max| d <= max));
+            match projection {
+                Some((e, t, distance)) => LinearPos {
+                    timestamp: p.timestamp,
+                    position: offsets[e] + t * graph.edge_length(e),
+                    edge: Some(e),
+                    distance,
+                    direction: Direction::Unknown,
+                },
+                None => LinearPos {
+                    timestamp: p.timestamp,
+                    position: f32::NAN,
+                    edge: None,
+                    distance: f32::NAN,
+                    direction: Direction::Unknown,
+                },
+            }
+        })
+        .collect();
+
+    for i in 0..linear.len() {
+        let before = i.saturating_sub(1);
+        let after = (i + 1).min(linear.len() - 1);
+        // Central difference where both neighbours are on the same edge,
+        // otherwise one-sided
+        let velocity = [(before, after), (i, after), (before, i)]
+            .iter()
+            .filter(|&&(a, b)| a < b && linear[i].edge.is_some()
+                    && linear[a].edge == linear[i].edge && linear[b].edge == linear[i].edge)
+            .map(|&(a, b)| {
+                let dt = (linear[b].timestamp as f64 - linear[a].timestamp as f64)
+                    / TICKS_PER_SECOND;
+                (linear[b].position - linear[a].position) as f64 / dt
+            })
+            .find(|v| v.is_finite());
+        linear[i].direction = match velocity {
+            None => Direction::Unknown,
+            Some(v) if v.abs() < config.min_speed as f64 => Direction::Stationary,
+            Some(v) if v > 0.0 => Direction::Increasing,
+            Some(_) => Direction::Decreasing,
+        };
+    }
+    Ok(linear)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // An L: up 100 along y, then 50 along x, with a gap of 10 between
+    // the edges in linear position
+    const L_TRACK: &str = "nodes = [[0.0, 0.0], [0.0, 100.0], [50.0, 100.0]]\n\
+                           edges = [[0, 1], [1, 2]]\n\
+                           edge_spacing = 10.0\n";
+
+    fn pos(seconds: u32, x: f32, y: f32) -> ProcessedPos {
+        ProcessedPos {
+            timestamp: seconds * 10_000,
+            x,
+            y,
+            angle: f32::NAN,
+            behavior: f32::NAN,
+            width: f32::NAN,
+        }
+    }
+
+    #[test]
+    fn it_loads_graphs() {
+        let graph = TrackGraph::from_toml(L_TRACK).unwrap();
+        assert_eq!(graph.edge_offsets(), vec![0.0, 110.0]);
+        let json = "{\"nodes\": [[0, 0], [0, 100], [50, 100]], \"edges\": [[0, 1], [1, 2]]}";
+        let graph = TrackGraph::from_json(json).unwrap();
+        assert_eq!(graph.edge_spacing, 0.0);
+        assert_eq!(graph.edge_offsets(), vec![0.0, 100.0]);
+
+        match TrackGraph::from_toml("nodes = [[0.0, 0.0]]\nedges = [[0, 1]]\n") {
+            Err(Error::BadValue { key, .. }) => assert_eq!(key, "edges"),
+            r => panic!("expected a bad value, got {:?}", r),
+        }
+        assert!(TrackGraph::from_toml("nodes = 3").is_err());
+    }
+
+    #[test]
+    fn it_projects_onto_the_nearest_edge() {
+        let graph = TrackGraph::from_toml(L_TRACK).unwrap();
+        let positions = vec![pos(0, 3.0, 10.0), pos(1, -2.0, 30.0), pos(2, 20.0, 97.0),
+                             pos(3, 30.0, 101.0), pos(4, 30.0, 101.5), pos(5, f32::NAN, f32::NAN),
+                             pos(6, 200.0, 200.0)];
+        let config = LinearizeConfig { max_distance: Some(20.0), ..LinearizeConfig::default() };
+        let linear = linearize(&positions, &graph, &config).unwrap();
+
+        let edges: Vec<Option<usize>> = linear.iter().map(|l| l.edge).collect();
+        assert_eq!(edges, vec![Some(0), Some(0), Some(1), Some(1), Some(1), None, None]);
+        assert_eq!(linear[0].position, 10.0);
+        assert_eq!(linear[0].distance, 3.0);
+        assert_eq!(linear[2].position, 130.0);
+        assert!(linear[6].position.is_nan());
+
+        let directions: Vec<Direction> = linear.iter().map(|l| l.direction).collect();
+        assert_eq!(directions, vec![Direction::Increasing, Direction::Increasing,
+                                    Direction::Increasing, Direction::Increasing,
+                                    Direction::Stationary, Direction::Unknown, Direction::Unknown]);
+    }
+}
@@ -1,6 +1,80 @@
+pub mod behav;
+pub mod calibrate;
+pub mod clean;
+pub mod extract;
+pub mod laps;
+pub mod linearize;
 pub mod mwl_ad;
+pub mod smooth;
+pub mod swap;
 
-#[derive (Debug, PartialEq)]
+use crate::mwl_ad::TICKS_PER_SECOND;
+
+/// The coordinate of a diode that wasn't detected, as in MWL files
+pub const MISSING: f32 = 0.0;
+
+/// Whether a diode's coordinates are a detection, rather than
+/// `MISSING` or NaN
+pub fn is_detected(diode: (f32, f32)) -> bool {
+    Coordinates::Pixels.is_detected(diode)
+}
+
+/// What the coordinates of a track are, which decides how a diode that
+/// wasn't detected is marked
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Coordinates {
+    /// Camera pixels, as in MWL files: `MISSING` or NaN
+    Pixels,
+    /// Arena coordinates from `calibrate::calibrate`: NaN only, since
+    /// `MISSING` is a real place once the origin can be anywhere
+    Arena,
+}
+
+impl Coordinates {
+    pub fn is_detected(self, (x, y): (f32, f32)) -> bool {
+        let missing = self == Coordinates::Pixels && (x, y) == (MISSING, MISSING);
+        !missing && !x.is_nan() && !y.is_nan()
+    }
+
+    /// The coordinates of a diode that wasn't detected
+    pub fn missing(self) -> (f32, f32) {
+        match self {
+            Coordinates::Pixels => (MISSING, MISSING),
+            Coordinates::Arena => (f32::NAN, f32::NAN),
+        }
+    }
+}
+
+/// The time of a position sample: seconds as an f32, as the readers
+/// return it, or a raw u32 timestamp in ticks of 100us, which stays
+/// exact past the first 2^24 ticks
+pub trait SampleTime: Copy {
+    fn seconds(self) -> f64;
+    /// Rounded to the nearest tick, and clamped to the range of u32
+    fn ticks(self) -> u32;
+}
+
+impl SampleTime for f32 {
+    fn seconds(self) -> f64 {
+        self as f64
+    }
+
+    fn ticks(self) -> u32 {
+        (self as f64 * TICKS_PER_SECOND).round().clamp(0.0, u32::MAX as f64) as u32
+    }
+}
+
+impl SampleTime for u32 {
+    fn seconds(self) -> f64 {
+        self as f64 / TICKS_PER_SECOND
+    }
+
+    fn ticks(self) -> u32 {
+        self
+    }
+}
+
+#[derive (Clone, Debug, PartialEq)]
 pub struct DiodePos<P,T>
 {
     pub diode_front: (P,P),
@@ -8,17 +82,44 @@ pub struct DiodePos<P,T>
     pub time: T
 }
 
-#[derive (Debug, PartialEq)]
+#[derive (Clone, Copy, Debug, PartialEq)]
 pub enum DiodeId
 {
     Front,
     Back,
 }
 
-#[derive (Debug, PartialEq)]
+#[derive (Clone, Debug, PartialEq)]
 pub struct DiodeImage<P,T>
 {
     pub diode_id: DiodeId,
     pub coords: Vec<(P,P)>,
     pub time: T,
 }
+
+/// A position sample after processing by `behav`, as stored in
+/// pxyabw files. Values that can't be worked out for a sample, such
+/// as the head direction when a diode is missing, are NaN.
+#[derive (Clone, Debug, PartialEq)]
+pub struct ProcessedPos
+{
+    /// In ticks of 100us
+    pub timestamp: u32,
+    /// Midpoint between the diodes
+    pub x: f32,
+    pub y: f32,
+    /// Head direction, in radians counterclockwise from the x axis,
+    /// taken from the back diode to the front one
+    pub angle: f32,
+    /// Instantaneous speed of the midpoint, in units per second
+    pub behavior: f32,
+    /// Distance between the diodes
+    pub width: f32,
+}
+
+impl ProcessedPos {
+    /// The timestamp in seconds
+    pub fn time(&self) -> f64 {
+        self.timestamp as f64 / TICKS_PER_SECOND
+    }
+}
@@ -1,35 +1,651 @@
-use std::path::{Path};
+use std::path::{Path, PathBuf};
 use std::fs::{File};
-use std::io::Read;
+use std::io;
+use std::iter;
+use std::io::{BufReader, BufWriter, Read, Seek, Write};
 use nom::combinator as nomc;
 use nom::sequence as noms;
 use nom::multi as nomm;
 use nom::number::complete as nomnum;
 use nom::{IResult};
 
-use crate::mwl_ad::header;
-use super::{DiodePos};
+use crate::Error;
+use crate::interval::IntervalSet;
+use crate::mwl_ad::{FormatType, TICKS_PER_SECOND};
+use crate::mwl_ad::header::{self, Metadata, MetadataBuf};
+use crate::mwl_ad::schema::{Field, RecordSchema};
+use crate::mwl_ad::stream::{self, RecordStream, Recovery, Warning};
+use super::{DiodeId, DiodeImage, DiodePos, ProcessedPos};
 
 
-pub fn read_p(path: &Path) -> Vec<DiodePos<f32, f32>> {
-    let mut buffer = Vec::new();
-    File::open(path).unwrap().read_to_end(&mut buffer).unwrap();
-    let (_, file_binary) = header::parse(buffer.as_slice()).unwrap();
-    parse_p( file_binary ).unwrap().1
+/// The record layout `parse_p` decodes: a u32 timestamp followed by
+/// the front and back diode pixel coordinates
+pub fn p_schema() -> RecordSchema {
+    RecordSchema::new(vec![
+        Field::new("timestamp", FormatType::ULongT, 4, 1),
+        Field::new("xfront", FormatType::ShortT, 2, 1),
+        Field::new("yfront", FormatType::ShortT, 2, 1),
+        Field::new("xback", FormatType::ShortT, 2, 1),
+        Field::new("yback", FormatType::ShortT, 2, 1),
+    ])
+}
+
+pub fn read_p(path: &Path) -> Result<Vec<DiodePos<f32, f32>>, Error> {
+    PosReader::open(path)?.collect()
+}
+
+/// Reads the samples of a position (.p) file one at a time
+pub struct PosReader<R> {
+    header: MetadataBuf,
+    records: RecordStream<BufReader<R>>,
+}
+
+impl PosReader<File> {
+    pub fn open(path: &Path) -> Result<PosReader<File>, Error> {
+        File::open(path)
+            .map_err(Error::from)
+            .and_then(PosReader::new)
+            .map(|mut r| {
+                r.records = r.records.with_path(path);
+                r
+            })
+            .map_err(|e| e.at_path(path))
+    }
+}
+
+impl<R: Read> PosReader<R> {
+    /// Parse the header of `inner`; samples are read on iteration
+    pub fn new(inner: R) -> Result<PosReader<R>, Error> {
+        let (header, records) = stream::open(inner, &p_schema())?;
+        Ok(PosReader { header, records })
+    }
+
+    pub fn header(&self) -> &MetadataBuf {
+        &self.header
+    }
+
+    /// Choose how to handle damaged files, as [`Recovery`] describes
+    /// (`Recovery::Strict` by default)
+    pub fn recovery(mut self, recovery: Recovery) -> PosReader<R> {
+        self.records.set_recovery(recovery);
+        self
+    }
+
+    /// Problems worked around so far in `Recovery::Salvage` mode
+    pub fn warnings(&self) -> &[Warning] {
+        self.records.warnings()
+    }
+
+    /// Read the samples with their raw timestamps, in ticks of 100us,
+    /// rather than in seconds, so that times late in a long recording
+    /// stay exact
+    pub fn ticks(mut self) -> impl Iterator<Item = Result<DiodePos<f32, u32>, Error>> {
+        iter::from_fn(move || {
+            self.records.next_record().map(|record| {
+                // A record has exactly the size parse_p_record_ticks consumes
+                Ok(parse_p_record_ticks(record?).unwrap().1)
+            })
+        })
+    }
+}
+
+impl<R: Read + Seek> PosReader<R> {
+    /// Restrict the reader to samples with timestamps (in ticks of
+    /// 100us) in `[after, before)`, as [`RecordStream`] describes
+    pub fn time_range(mut self, after: Option<u32>, before: Option<u32>)
+                      -> Result<PosReader<R>, Error> {
+        self.records.seek_time_range(after, before)?;
+        Ok(self)
+    }
+
+    /// Restrict the reader to samples with timestamps in `intervals`,
+    /// within any `time_range`, as [`RecordStream`] describes
+    pub fn during(mut self, intervals: IntervalSet) -> Result<PosReader<R>, Error> {
+        self.records.seek_intervals(intervals)?;
+        Ok(self)
+    }
+}
+
+impl<R: Read> Iterator for PosReader<R> {
+    type Item = Result<DiodePos<f32, f32>, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.records.next_record().map(|record| {
+            // A record has exactly the size parse_p_record consumes
+            Ok(parse_p_record(record?).unwrap().1)
+        })
+    }
+}
+
+/// Header for a .p file holding positions taken from `source`: a raw
+/// position file, or a .p file that has been cleaned or otherwise
+/// processed. The source's header is kept as a provenance section.
+pub fn p_header<S: AsRef<str>>(args: &[S], source_name: &str, source: &Metadata) -> MetadataBuf {
+    let mut h = MetadataBuf::for_program(args);
+    h.push_pair("File type", "Binary");
+    h.push_pair("Extraction type", "diode positions");
+    h.push_pair("Fields", p_schema());
+    h.push_comment("");
+    h.push_section(source_name, source);
+    h
+}
+
+/// Writes positions to a .p file, rounding coordinates to whole pixels
+pub struct PosWriter<W: Write> {
+    inner: W,
+    record: Vec<u8>,
+}
+
+impl PosWriter<BufWriter<File>> {
+    pub fn create(path: &Path, header: &MetadataBuf) -> Result<PosWriter<BufWriter<File>>, Error> {
+        File::create(path)
+            .map_err(Error::from)
+            .and_then(|f| PosWriter::new(BufWriter::new(f), header))
+            .map_err(|e| e.at_path(path))
+    }
+}
+
+impl<W: Write> PosWriter<W> {
+    /// Write `header`, which must declare the .p record layout
+    /// (see `p_header`)
+    pub fn new(mut inner: W, header: &MetadataBuf) -> Result<PosWriter<W>, Error> {
+        let schema = p_schema();
+        stream::write_header(&mut inner, &header.as_metadata(), &schema)?;
+        Ok(PosWriter { inner, record: Vec::with_capacity(schema.record_size()) })
+    }
+
+    /// Write a position whose time is in seconds, as produced by
+    /// `PosReader`
+    pub fn write_pos(&mut self, pos: &DiodePos<f32, f32>) -> Result<(), Error> {
+        let timestamp = (pos.time as f64 * TICKS_PER_SECOND).round();
+        if !(0.0..=u32::MAX as f64).contains(&timestamp) {
+            return Err(Error::bad_value("timestamp", pos.time.to_string(),
+                                        "a time representable in u32 ticks"));
+        }
+        self.write_record(&DiodePos {
+            diode_front: pos.diode_front,
+            diode_back: pos.diode_back,
+            time: timestamp as u32,
+        })
+    }
+
+    /// Write a position whose time is a raw timestamp
+    pub fn write_record(&mut self, pos: &DiodePos<f32, u32>) -> Result<(), Error> {
+        let (xfront, yfront) = pos.diode_front;
+        let (xback, yback) = pos.diode_back;
+        self.record.clear();
+        self.record.extend_from_slice(&pos.time.to_le_bytes());
+        for (name, v) in &[("xfront", xfront), ("yfront", yfront),
+                           ("xback", xback), ("yback", yback)] {
+            let pixel = v.round();
+            if pixel.is_nan() || pixel < i16::MIN as f32 || pixel > i16::MAX as f32 {
+                return Err(Error::bad_value(name, v.to_string(), "a pixel coordinate within i16"));
+            }
+            self.record.extend_from_slice(&(pixel as i16).to_le_bytes());
+        }
+        self.inner.write_all(&self.record)?;
+        Ok(())
+    }
+
+    /// Flush and return the underlying writer
+    pub fn into_inner(mut self) -> Result<W, Error> {
+        self.inner.flush()?;
+        Ok(self.inner)
+    }
+}
+
+
+/// The record layout of pxyabw files, as written by `behav`: a u32
+/// timestamp followed by x, y, head angle, behavior and diode width,
+/// as floats (see `ProcessedPos`)
+pub fn pxyabw_schema() -> RecordSchema {
+    RecordSchema::new(vec![
+        Field::new("timestamp", FormatType::ULongT, 4, 1),
+        Field::new("x", FormatType::FloatT, 4, 1),
+        Field::new("y", FormatType::FloatT, 4, 1),
+        Field::new("a", FormatType::FloatT, 4, 1),
+        Field::new("b", FormatType::FloatT, 4, 1),
+        Field::new("w", FormatType::FloatT, 4, 1),
+    ])
+}
+
+pub fn read_pxyabw(path: &Path) -> Result<Vec<ProcessedPos>, Error> {
+    ProcessedPosReader::open(path)?.collect()
+}
+
+/// Header for a pxyabw file holding positions processed from
+/// `source`, whose header is kept as a provenance section
+pub fn pxyabw_header<S: AsRef<str>>(args: &[S], source_name: &str, source: &Metadata) -> MetadataBuf {
+    let mut h = MetadataBuf::for_program(args);
+    h.push_pair("File type", "Binary");
+    h.push_pair("Extraction type", "processed positions");
+    h.push_pair("Fields", pxyabw_schema());
+    h.push_comment("");
+    h.push_section(source_name, source);
+    h
+}
+
+/// Reads the samples of a pxyabw file one at a time
+pub struct ProcessedPosReader<R> {
+    header: MetadataBuf,
+    records: RecordStream<BufReader<R>>,
+}
+
+impl ProcessedPosReader<File> {
+    pub fn open(path: &Path) -> Result<ProcessedPosReader<File>, Error> {
+        File::open(path)
+            .map_err(Error::from)
+            .and_then(ProcessedPosReader::new)
+            .map(|mut r| {
+                r.records = r.records.with_path(path);
+                r
+            })
+            .map_err(|e| e.at_path(path))
+    }
+}
+
+impl<R: Read> ProcessedPosReader<R> {
+    /// Parse the header of `inner`; samples are read on iteration
+    pub fn new(inner: R) -> Result<ProcessedPosReader<R>, Error> {
+        let (header, records) = stream::open(inner, &pxyabw_schema())?;
+        Ok(ProcessedPosReader { header, records })
+    }
+
+    pub fn header(&self) -> &MetadataBuf {
+        &self.header
+    }
+
+    /// Choose how to handle damaged files, as [`Recovery`] describes
+    /// (`Recovery::Strict` by default)
+    pub fn recovery(mut self, recovery: Recovery) -> ProcessedPosReader<R> {
+        self.records.set_recovery(recovery);
+        self
+    }
+
+    /// Problems worked around so far in `Recovery::Salvage` mode
+    pub fn warnings(&self) -> &[Warning] {
+        self.records.warnings()
+    }
+}
+
+impl<R: Read + Seek> ProcessedPosReader<R> {
+    /// Restrict the reader to samples with timestamps (in ticks of
+    /// 100us) in `[after, before)`, as [`RecordStream`] describes
+    pub fn time_range(mut self, after: Option<u32>, before: Option<u32>)
+                      -> Result<ProcessedPosReader<R>, Error> {
+        self.records.seek_time_range(after, before)?;
+        Ok(self)
+    }
+
+    /// Restrict the reader to samples with timestamps in `intervals`,
+    /// within any `time_range`, as [`RecordStream`] describes
+    pub fn during(mut self, intervals: IntervalSet) -> Result<ProcessedPosReader<R>, Error> {
+        self.records.seek_intervals(intervals)?;
+        Ok(self)
+    }
+}
+
+impl<R: Read> Iterator for ProcessedPosReader<R> {
+    type Item = Result<ProcessedPos, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.records.next_record().map(|record| {
+            // A record has exactly the size parse_pxyabw_record consumes
+            Ok(parse_pxyabw_record(record?).unwrap().1)
+        })
+    }
+}
+
+/// Writes processed positions to a pxyabw file
+pub struct ProcessedPosWriter<W: Write> {
+    inner: W,
+    record: Vec<u8>,
+}
+
+impl ProcessedPosWriter<BufWriter<File>> {
+    pub fn create(path: &Path, header: &MetadataBuf)
+                  -> Result<ProcessedPosWriter<BufWriter<File>>, Error> {
+        File::create(path)
+            .map_err(Error::from)
+            .and_then(|f| ProcessedPosWriter::new(BufWriter::new(f), header))
+            .map_err(|e| e.at_path(path))
+    }
+}
+
+impl<W: Write> ProcessedPosWriter<W> {
+    /// Write `header`, which must declare the pxyabw record layout
+    /// (see `pxyabw_header`)
+    pub fn new(mut inner: W, header: &MetadataBuf) -> Result<ProcessedPosWriter<W>, Error> {
+        let schema = pxyabw_schema();
+        stream::write_header(&mut inner, &header.as_metadata(), &schema)?;
+        Ok(ProcessedPosWriter { inner, record: Vec::with_capacity(schema.record_size()) })
+    }
+
+    pub fn write_pos(&mut self, pos: &ProcessedPos) -> Result<(), Error> {
+        self.record.clear();
+        self.record.extend_from_slice(&pos.timestamp.to_le_bytes());
+        for v in &[pos.x, pos.y, pos.angle, pos.behavior, pos.width] {
+            self.record.extend_from_slice(&v.to_le_bytes());
+        }
+        self.inner.write_all(&self.record)?;
+        Ok(())
+    }
+
+    /// Flush and return the underlying writer
+    pub fn into_inner(mut self) -> Result<W, Error> {
+        self.inner.flush()?;
+        Ok(self.inner)
+    }
+}
+
+pub fn parse_pxyabw_record(str: &[u8]) -> IResult<&[u8], ProcessedPos> {
+    nomc::map(
+        noms::pair(
+            nomnum::le_u32, // unsigned long (timestamp)
+            nomm::count(nomnum::le_f32, 5)
+        ),
+        |(timestamp, vs)| ProcessedPos {
+            timestamp,
+            x: vs[0],
+            y: vs[1],
+            angle: vs[2],
+            behavior: vs[3],
+            width: vs[4],
+        }
+    )(str)
+}
+
+/// Reads the frames of a raw position tracker file. After the header,
+/// each frame is a u32 timestamp, a u8 video field (0 for the field
+/// lighting the front diode, 1 for the back), a u16 pixel count, and
+/// that many pixels above the tracker's threshold, as pairs of u16
+/// x and y coordinates. All values are little-endian.
+pub struct RawPosReader<R> {
+    header: MetadataBuf,
+    inner: BufReader<R>,
+    path: Option<PathBuf>,
+    offset: u64,
+    recovery: Recovery,
+    warnings: Vec<Warning>,
+}
+
+impl RawPosReader<File> {
+    pub fn open(path: &Path) -> Result<RawPosReader<File>, Error> {
+        File::open(path)
+            .map_err(Error::from)
+            .and_then(RawPosReader::new)
+            .map(|mut r| {
+                r.path = Some(path.to_path_buf());
+                r
+            })
+            .map_err(|e| e.at_path(path))
+    }
+}
+
+impl<R: Read> RawPosReader<R> {
+    /// Parse the header of `inner`; frames are read on iteration
+    pub fn new(inner: R) -> Result<RawPosReader<R>, Error> {
+        let mut inner = BufReader::new(inner);
+        let bytes = header::read_bytes(&mut inner)?;
+        let (metadata, _) = header::parse(&bytes)?;
+        Ok(RawPosReader {
+            header: MetadataBuf::from(&metadata),
+            inner,
+            path: None,
+            offset: bytes.len() as u64,
+            recovery: Recovery::Strict,
+            warnings: Vec::new(),
+        })
+    }
+
+    pub fn header(&self) -> &MetadataBuf {
+        &self.header
+    }
+
+    /// Choose how to handle a partial trailing frame
+    /// (`Recovery::Strict` by default)
+    pub fn recovery(mut self, recovery: Recovery) -> RawPosReader<R> {
+        self.recovery = recovery;
+        self
+    }
+
+    /// Problems worked around so far in `Recovery::Salvage` mode
+    pub fn warnings(&self) -> &[Warning] {
+        &self.warnings
+    }
+
+    // Read the next frame, or as much of it as the stream holds,
+    // returning the frame size and the bytes read
+    fn read_frame(&mut self) -> io::Result<(usize, Vec<u8>)> {
+        let mut frame = vec![0; 7];
+        let filled = fill(&mut self.inner, &mut frame)?;
+        if filled < frame.len() {
+            frame.truncate(filled);
+            return Ok((7, frame));
+        }
+        let n_pixels = u16::from_le_bytes([frame[5], frame[6]]) as usize;
+        frame.resize(7 + 4 * n_pixels, 0);
+        let filled = 7 + fill(&mut self.inner, &mut frame[7..])?;
+        let size = frame.len();
+        frame.truncate(filled);
+        Ok((size, frame))
+    }
+}
+
+impl<R: Read> Iterator for RawPosReader<R> {
+    type Item = Result<DiodeImage<u16, u32>, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let offset = self.offset;
+        let (size, frame) = match self.read_frame() {
+            Ok(f) => f,
+            Err(err) => return Some(Err(Error::Io { path: self.path.clone(), offset: Some(offset), err })),
+        };
+        if frame.is_empty() {
+            return None;
+        }
+        self.offset += frame.len() as u64;
+        if frame.len() < size {
+            return match self.recovery {
+                Recovery::Strict => Some(Err(Error::TruncatedRecord {
+                    path: self.path.clone(),
+                    offset,
+                    expected: size,
+                    found: frame.len(),
+                })),
+                Recovery::Salvage => {
+                    self.warnings.push(Warning::TrailingBytes { offset, count: frame.len() });
+                    None
+                },
+            };
+        }
+        let diode_id = match frame[4] {
+            0 => DiodeId::Front,
+            1 => DiodeId::Back,
+            field => return Some(Err(Error::BadValue {
+                path: self.path.clone(),
+                offset: Some(offset),
+                key: "field".to_owned(),
+                value: field.to_string(),
+                expected: "0 (front diode) or 1 (back diode)".to_owned(),
+            })),
+        };
+        let coords = frame[7..]
+            .chunks_exact(4)
+            .map(|p| (u16::from_le_bytes([p[0], p[1]]), u16::from_le_bytes([p[2], p[3]])))
+            .collect();
+        Some(Ok(DiodeImage { diode_id, coords, time: stream::record_timestamp(&frame) }))
+    }
+}
+
+// Read as much of `buf` as the stream holds, returning the number
+// of bytes read
+fn fill<R: Read>(inner: &mut R, buf: &mut [u8]) -> io::Result<usize> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match inner.read(&mut buf[filled..]) {
+            Ok(0) => break,
+            Ok(n) => filled += n,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => (),
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(filled)
 }
 
 pub fn parse_p(str: &[u8]) -> IResult< &[u8], Vec<DiodePos<f32, f32>> > {
-    nomm::many0(
-        nomc::map(
+    nomm::many0(parse_p_record)(str)
+}
+
+pub fn parse_p_record(str: &[u8]) -> IResult< &[u8], DiodePos<f32, f32> > {
+    nomc::map(
+        parse_p_record_ticks,
+        |p| DiodePos {
+            time:        (p.time as f64 / TICKS_PER_SECOND) as f32,
+            diode_front: p.diode_front,
+            diode_back:  p.diode_back,
+        }
+    )(str)
+}
+
+/// Like `parse_p_record`, keeping the raw timestamp
+pub fn parse_p_record_ticks(str: &[u8]) -> IResult< &[u8], DiodePos<f32, u32> > {
+    nomc::map(
         noms::pair(
             nomnum::le_u32, //unsigned long (timestamp)
             nomm::count( nomnum::le_i16, 4)
         ),
         |(t,pos_coords)| DiodePos {
-            time:        t as f32 / 10_000.0,
+            time:        t,
             diode_front: (pos_coords[0] as f32, pos_coords[1] as f32),
             diode_back:  (pos_coords[2] as f32, pos_coords[3] as f32),
         }
-    ))(str)
+    )(str)
 }
 
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    /// A raw position file holding one frame per `(field, pixels)`,
+    /// with timestamps 100, 200, ...
+    pub(crate) fn raw_pos_file(frames: &[(u8, Vec<(u16, u16)>)]) -> Vec<u8> {
+        let metadata = MetadataBuf::new().pair("File type", "Binary");
+        let mut bytes = header::to_bytes(&metadata.as_metadata()).unwrap();
+        for (i, (field, pixels)) in frames.iter().enumerate() {
+            bytes.extend_from_slice(&(100 * (i as u32 + 1)).to_le_bytes());
+            bytes.push(*field);
+            bytes.extend_from_slice(&(pixels.len() as u16).to_le_bytes());
+            for (x, y) in pixels {
+                bytes.extend_from_slice(&x.to_le_bytes());
+                bytes.extend_from_slice(&y.to_le_bytes());
+            }
+        }
+        bytes
+    }
+
+    #[test]
+    fn written_positions_read_back() {
+        let source = MetadataBuf::new().pair("File type", "Binary");
+        let header = p_header(&["xcrust-test"], "x.pos", &source.as_metadata());
+        let mut writer = PosWriter::new(Vec::new(), &header).unwrap();
+        writer.write_pos(&DiodePos { time: 1.5, diode_front: (10.4, 20.6), diode_back: (-3.0, 0.0) })
+            .unwrap();
+        writer.write_record(&DiodePos { time: 15_001, diode_front: (1.0, 2.0), diode_back: (3.0, 4.0) })
+            .unwrap();
+        let bytes = writer.into_inner().unwrap();
+
+        let reader = PosReader::new(bytes.as_slice()).unwrap();
+        let t = header::tree(&reader.header().as_metadata());
+        assert_eq!(t.lookup("Program"), Some("xcrust-test"));
+        assert_eq!(t.child("x.pos").and_then(|s| s.lookup("File type")), Some("Binary"));
+        let ps: Vec<DiodePos<f32, f32>> = reader.map(Result::unwrap).collect();
+        assert_eq!(ps, vec![
+            DiodePos { time: 1.5, diode_front: (10.0, 21.0), diode_back: (-3.0, 0.0) },
+            DiodePos { time: 1.5001, diode_front: (1.0, 2.0), diode_back: (3.0, 4.0) },
+        ]);
+    }
+
+    #[test]
+    fn processed_positions_read_back() {
+        let header = pxyabw_header(&["xcrust-test"], "x.p", &MetadataBuf::new().as_metadata());
+        let mut writer = ProcessedPosWriter::new(Vec::new(), &header).unwrap();
+        let ps = vec![
+            ProcessedPos { timestamp: 100, x: 1.0, y: 2.0, angle: 0.5, behavior: 3.0, width: 4.0 },
+            ProcessedPos { timestamp: 200, x: 1.5, y: 2.5, angle: -0.5, behavior: 1.0, width: 4.5 },
+        ];
+        for p in ps.iter() {
+            writer.write_pos(p).unwrap();
+        }
+        let bytes = writer.into_inner().unwrap();
+        assert_eq!(bytes.len(), header::to_bytes(&header.as_metadata()).unwrap().len() + 2 * 24);
+
+        let reader = ProcessedPosReader::new(bytes.as_slice()).unwrap();
+        let read: Vec<ProcessedPos> = reader.map(Result::unwrap).collect();
+        assert_eq!(read, ps);
+    }
+
+    #[test]
+    fn writer_rejects_overflowing_coordinates() {
+        let header = p_header(&["xcrust-test"], "x.pos", &MetadataBuf::new().as_metadata());
+        let mut writer = PosWriter::new(Vec::new(), &header).unwrap();
+        let p = DiodePos { time: 0.0, diode_front: (1.0, 40_000.0), diode_back: (0.0, 0.0) };
+        match writer.write_pos(&p) {
+            Err(Error::BadValue { key, value, .. }) => {
+                assert_eq!(key, "yfront");
+                assert_eq!(value, "40000");
+            },
+            r => panic!("expected a bad value, got {:?}", r),
+        }
+    }
+
+    #[test]
+    fn it_reads_raw_frames() {
+        let mut bytes = raw_pos_file(&[(0, vec![(300, 20), (301, 21)]), (1, vec![])]);
+        bytes.extend_from_slice(&[1, 0, 0, 0, 0, 1, 0]);
+        let frames: Vec<Result<DiodeImage<u16, u32>, Error>> =
+            RawPosReader::new(bytes.as_slice()).unwrap().collect();
+        assert_eq!(frames.len(), 3);
+        assert_eq!(frames[0].as_ref().ok(), Some(&DiodeImage {
+            diode_id: DiodeId::Front,
+            coords: vec![(300, 20), (301, 21)],
+            time: 100,
+        }));
+        assert_eq!(frames[1].as_ref().map(|f| &f.diode_id).ok(), Some(&DiodeId::Back));
+        match frames[2] {
+            Err(Error::TruncatedRecord { expected: 11, found: 7, .. }) => (),
+            ref r => panic!("expected a truncated frame, got {:?}", r),
+        }
+
+        let mut salvaged = RawPosReader::new(bytes.as_slice()).unwrap().recovery(Recovery::Salvage);
+        assert_eq!(salvaged.by_ref().count(), 2);
+        assert_eq!(salvaged.warnings().len(), 1);
+    }
+
+    #[test]
+    fn it_streams_records() {
+        let metadata = MetadataBuf::new().pair("Fields", p_schema());
+        let mut bytes = header::to_bytes(&metadata.as_metadata()).unwrap();
+        for (t, x) in [(10u32, 1i16), (20, 2)].iter() {
+            bytes.extend_from_slice(&t.to_le_bytes());
+            for _ in 0..4 {
+                bytes.extend_from_slice(&x.to_le_bytes());
+            }
+        }
+        bytes.extend_from_slice(&[0, 0, 0]);
+
+        let reader = PosReader::new(bytes.as_slice()).unwrap();
+        assert_eq!(reader.header().as_metadata(), metadata.as_metadata());
+        let ps: Vec<Result<DiodePos<f32, f32>, Error>> = reader.collect();
+        assert_eq!(ps.len(), 3);
+        assert_eq!(ps[0].as_ref().ok(),
+                   Some(&DiodePos { time: 0.001, diode_front: (1.0, 1.0), diode_back: (1.0, 1.0) }));
+        assert_eq!(ps[1].as_ref().ok(),
+                   Some(&DiodePos { time: 0.002, diode_front: (2.0, 2.0), diode_back: (2.0, 2.0) }));
+        match ps[2] {
+            Err(Error::TruncatedRecord { found: 3, .. }) => (),
+            ref r => panic!("expected a truncated record, got {:?}", r),
+        }
+    }
+}
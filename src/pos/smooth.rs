@@ -0,0 +1,342 @@
+use std::f64::consts::PI;
+
+use crate::Error;
+use super::{Coordinates, DiodePos, SampleTime};
+
+/// The noise model of the smoother. Units are those of the track:
+/// pixels, or centimeters once calibrated.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SmoothConfig {
+    /// Standard deviation of the error in a measured midpoint, on
+    /// each axis
+    pub position_noise: f32,
+    /// How freely the velocity changes: the spectral density of the
+    /// random acceleration, in units² per s³ on each axis
+    pub acceleration_noise: f32,
+    /// Standard deviation of the error in a measured heading, in
+    /// radians
+    pub heading_noise: f32,
+    /// How freely the angular velocity changes, in radians² per s³
+    pub angular_acceleration_noise: f32,
+    pub coordinates: Coordinates,
+}
+
+impl Default for SmoothConfig {
+    fn default() -> SmoothConfig {
+        SmoothConfig {
+            position_noise: 1.0,
+            acceleration_noise: 1000.0,
+            heading_noise: 0.1,
+            angular_acceleration_noise: 100.0,
+            coordinates: Coordinates::Pixels,
+        }
+    }
+}
+
+impl SmoothConfig {
+    /// Check that every noise level is positive, since the filter
+    /// gives NaN or nonsense otherwise
+    pub fn validate(&self) -> Result<(), Error> {
+        let noises = [("position_noise", self.position_noise),
+                      ("acceleration_noise", self.acceleration_noise),
+                      ("heading_noise", self.heading_noise),
+                      ("angular_acceleration_noise", self.angular_acceleration_noise)];
+        for &(key, noise) in noises.iter() {
+            if !(noise.is_finite() && noise > 0.0) {
+                return Err(Error::bad_value(key, noise.to_string(), "a positive number"));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A sample of a smoothed track, with the standard deviation of each
+/// estimate. Estimates are NaN where the track gives nothing to go
+/// on, which is when no sample of it has both diodes detected.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SmoothedPos<T = f32> {
+    /// The time of the track sample
+    pub time: T,
+    /// Midpoint between the diodes
+    pub x: f32,
+    pub y: f32,
+    /// Velocity of the midpoint, in units per second
+    pub vx: f32,
+    pub vy: f32,
+    /// Head direction, in radians counterclockwise from the x axis,
+    /// in `(-PI, PI]`
+    pub heading: f32,
+    /// In radians per second, counterclockwise
+    pub angular_velocity: f32,
+    pub x_sd: f32,
+    pub y_sd: f32,
+    pub vx_sd: f32,
+    pub vy_sd: f32,
+    pub heading_sd: f32,
+}
+
+impl<T> SmoothedPos<T> {
+    pub fn speed(&self) -> f32 {
+        self.vx.hypot(self.vy)
+    }
+
+    /// Standard deviation of `speed`, to first order
+    pub fn speed_sd(&self) -> f32 {
+        let speed = self.speed();
+        if speed > 0.0 {
+            (self.vx * self.vx_sd).hypot(self.vy * self.vy_sd) / speed
+        } else {
+            self.vx_sd.max(self.vy_sd)
+        }
+    }
+}
+
+/// Smooth the midpoint and heading of a track with a constant-velocity
+/// Kalman filter, followed by a Rauch-Tung-Striebel smoother, so that
+/// each estimate draws on the whole track. The midpoint and heading are
+/// measured only at samples with both diodes detected; the others are
+/// filled in from the motion model, with the uncertainty growing
+/// across the gap. Fails if `config` doesn't validate.
+pub fn smooth<T: SampleTime>(track: &[DiodePos<f32, T>], config: &SmoothConfig)
+                             -> Result<Vec<SmoothedPos<T>>, Error> {
+    config.validate()?;
+    let times: Vec<f64> = track.iter().map(|p| p.time.seconds()).collect();
+    let detected = |d| config.coordinates.is_detected(d);
+    let both = |p: &DiodePos<f32, T>| detected(p.diode_front) && detected(p.diode_back);
+    let measure = |f: &dyn Fn(&DiodePos<f32, T>) -> f64| -> Vec<Option<f64>> {
+        track.iter().map(|p| if both(p) { Some(f(p)) } else { None }).collect()
+    };
+    let xs = measure(&|p| (p.diode_front.0 as f64 + p.diode_back.0 as f64) / 2.0);
+    let ys = measure(&|p| (p.diode_front.1 as f64 + p.diode_back.1 as f64) / 2.0);
+    let headings = measure(&|p| {
+        ((p.diode_front.1 - p.diode_back.1) as f64).atan2((p.diode_front.0 - p.diode_back.0) as f64)
+    });
+
+    let position = ConstantVelocity {
+        measurement_variance: (config.position_noise as f64).powi(2),
+        process_noise: config.acceleration_noise as f64,
+        angular: false,
+    };
+    let heading = ConstantVelocity {
+        measurement_variance: (config.heading_noise as f64).powi(2),
+        process_noise: config.angular_acceleration_noise as f64,
+        angular: true,
+    };
+    let x = position.smooth(&times, &xs);
+    let y = position.smooth(&times, &ys);
+    let h = heading.smooth(&times, &headings);
+
+    let sd = |p: &Mat2, i: usize| p[i][i].sqrt() as f32;
+    Ok((0..track.len())
+        .map(|i| SmoothedPos {
+            time: track[i].time,
+            x: x[i].0[0] as f32,
+            y: y[i].0[0] as f32,
+            vx: x[i].0[1] as f32,
+            vy: y[i].0[1] as f32,
+            heading: wrap(h[i].0[0]) as f32,
+            angular_velocity: h[i].0[1] as f32,
+            x_sd: sd(&x[i].1, 0),
+            y_sd: sd(&y[i].1, 0),
+            vx_sd: sd(&x[i].1, 1),
+            vy_sd: sd(&y[i].1, 1),
+            heading_sd: sd(&h[i].1, 0),
+        })
+        .collect())
+}
+
+type Vec2 = [f64; 2];
+type Mat2 = [[f64; 2]; 2];
+
+// Variance of the initial state: vague enough that the first
+// measurements decide it
+const DIFFUSE: f64 = 1e10;
+
+// One coordinate and its rate of change, measured directly. An angular
+// coordinate is tracked unwrapped, with innovations taken the short way
+// round the circle.
+struct ConstantVelocity {
+    measurement_variance: f64,
+    process_noise: f64,
+    angular: bool,
+}
+
+impl ConstantVelocity {
+    fn smooth(&self, times: &[f64], measurements: &[Option<f64>]) -> Vec<(Vec2, Mat2)> {
+        let n = times.len();
+        if measurements.iter().all(Option::is_none) {
+            return vec![([f64::NAN; 2], [[f64::NAN; 2]; 2]); n];
+        }
+
+        // Forward pass, keeping the predictions for the backward one
+        let mut predicted: Vec<(Vec2, Mat2)> = Vec::with_capacity(n);
+        let mut filtered: Vec<(Vec2, Mat2)> = Vec::with_capacity(n);
+        let mut state = ([0.0, 0.0], [[DIFFUSE, 0.0], [0.0, DIFFUSE]]);
+        for i in 0..n {
+            if i > 0 {
+                state = self.predict(&state, times[i] - times[i - 1]);
+            }
+            predicted.push(state);
+            if let Some(z) = measurements[i] {
+                state = self.update(&state, z);
+            }
+            filtered.push(state);
+        }
+
+        // Backward pass
+        let mut smoothed = filtered.clone();
+        for i in (0..n.saturating_sub(1)).rev() {
+            let f = transition(times[i + 1] - times[i]);
+            let (xf, pf) = &filtered[i];
+            let (xp, pp) = &predicted[i + 1];
+            let (xs, ps) = &smoothed[i + 1];
+            let gain = mul(&mul(pf, &transpose(&f)), &inverse(pp));
+            let dx = [xs[0] - xp[0], xs[1] - xp[1]];
+            let x = add_vec(xf, &apply(&gain, &dx));
+            let dp = sub(ps, pp);
+            let p = add(pf, &mul(&mul(&gain, &dp), &transpose(&gain)));
+            smoothed[i] = (x, p);
+        }
+        smoothed
+    }
+
+    fn predict(&self, (x, p): &(Vec2, Mat2), dt: f64) -> (Vec2, Mat2) {
+        let dt = dt.max(0.0);
+        let f = transition(dt);
+        let q = self.process_noise;
+        let noise = [[q * dt.powi(3) / 3.0, q * dt.powi(2) / 2.0], [q * dt.powi(2) / 2.0, q * dt]];
+        (apply(&f, x), add(&mul(&mul(&f, p), &transpose(&f)), &noise))
+    }
+
+    fn update(&self, (x, p): &(Vec2, Mat2), z: f64) -> (Vec2, Mat2) {
+        let innovation = if self.angular { wrap(z - x[0]) } else { z - x[0] };
+        let s = p[0][0] + self.measurement_variance;
+        let k = [p[0][0] / s, p[1][0] / s];
+        let x = [x[0] + k[0] * innovation, x[1] + k[1] * innovation];
+        // (I - KH)P, with H = [1, 0]
+        let p = [[p[0][0] - k[0] * p[0][0], p[0][1] - k[0] * p[0][1]],
+                 [p[1][0] - k[1] * p[0][0], p[1][1] - k[1] * p[0][1]]];
+        (x, p)
+    }
+}
+
+/// An angle in `(-PI, PI]`
+fn wrap(angle: f64) -> f64 {
+    let a = (angle + PI).rem_euclid(2.0 * PI) - PI;
+    if a == -PI { PI } else { a }
+}
+
+fn transition(dt: f64) -> Mat2 {
+    [[1.0, dt.max(0.0)], [0.0, 1.0]]
+}
+
+fn apply(m: &Mat2, v: &Vec2) -> Vec2 {
+    [m[0][0] * v[0] + m[0][1] * v[1], m[1][0] * v[0] + m[1][1] * v[1]]
+}
+
+fn mul(a: &Mat2, b: &Mat2) -> Mat2 {
+    [[a[0][0] * b[0][0] + a[0][1] * b[1][0], a[0][0] * b[0][1] + a[0][1] * b[1][1]],
+     [a[1][0] * b[0][0] + a[1][1] * b[1][0], a[1][0] * b[0][1] + a[1][1] * b[1][1]]]
+}
+
+fn transpose(m: &Mat2) -> Mat2 {
+    [[m[0][0], m[1][0]], [m[0][1], m[1][1]]]
+}
+
+fn inverse(m: &Mat2) -> Mat2 {
+    let det = m[0][0] * m[1][1] - m[0][1] * m[1][0];
+    [[m[1][1] / det, -m[0][1] / det], [-m[1][0] / det, m[0][0] / det]]
+}
+
+fn add(a: &Mat2, b: &Mat2) -> Mat2 {
+    [[a[0][0] + b[0][0], a[0][1] + b[0][1]], [a[1][0] + b[1][0], a[1][1] + b[1][1]]]
+}
+
+fn sub(a: &Mat2, b: &Mat2) -> Mat2 {
+    [[a[0][0] - b[0][0], a[0][1] - b[0][1]], [a[1][0] - b[1][0], a[1][1] - b[1][1]]]
+}
+
+fn add_vec(a: &Vec2, b: &Vec2) -> Vec2 {
+    [a[0] + b[0], a[1] + b[1]]
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pos::MISSING;
+
+    // Running along x at 20 units per second, 30Hz, facing forward,
+    // with the measured midpoint off by up to a unit
+    fn noisy_run(n: usize) -> Vec<DiodePos<f32, f32>> {
+        (0..n)
+            .map(|i| {
+                let t = i as f32 / 30.0;
+                let noise = if i % 2 == 0 { 1.0 } else { -1.0 };
+                let x = 20.0 + 20.0 * t;
+                let y = 50.0 + noise;
+                DiodePos { diode_front: (x + 2.0, y), diode_back: (x - 2.0, y), time: t }
+            })
+            .collect()
+    }
+
+    #[test]
+    fn it_smooths_a_noisy_run() {
+        let smoothed = smooth(&noisy_run(90), &SmoothConfig::default()).unwrap();
+        for p in smoothed[10..80].iter() {
+            assert!((p.speed() - 20.0).abs() < 2.0, "speed {} at {}", p.speed(), p.time);
+            assert!((p.y - 50.0).abs() < 0.5);
+            assert!(p.heading.abs() < 0.05);
+            assert!(p.x_sd < 1.0 && p.speed_sd() < 8.0);
+        }
+    }
+
+    #[test]
+    fn it_fills_gaps_with_growing_uncertainty() {
+        let mut track = noisy_run(90);
+        for p in track[40..50].iter_mut() {
+            p.diode_back = (MISSING, MISSING);
+        }
+        let smoothed = smooth(&track, &SmoothConfig::default()).unwrap();
+        let true_x = 20.0 + 20.0 * track[45].time;
+        assert!((smoothed[45].x - true_x).abs() < 1.0);
+        assert!(smoothed[45].x_sd > smoothed[40].x_sd);
+        assert!(smoothed[40].x_sd > smoothed[30].x_sd);
+    }
+
+    #[test]
+    fn heading_is_continuous_across_pi() {
+        // Facing -x, with the measured heading flicking either side of PI
+        let track: Vec<DiodePos<f32, f32>> = (0..30)
+            .map(|i| {
+                let dy = if i % 2 == 0 { 0.1 } else { -0.1 };
+                DiodePos { diode_front: (48.0, 50.0 + dy), diode_back: (52.0, 50.0), time: i as f32 / 30.0 }
+            })
+            .collect();
+        for p in smooth(&track, &SmoothConfig::default()).unwrap().iter() {
+            assert!(p.heading.abs() > 3.1, "heading {}", p.heading);
+            assert!(p.angular_velocity.abs() < 0.5);
+        }
+    }
+
+    #[test]
+    fn it_gives_nan_without_measurements() {
+        let track = vec![DiodePos { diode_front: (10.0, 10.0), diode_back: (MISSING, MISSING), time: 0.0 }];
+        let smoothed = smooth(&track, &SmoothConfig::default()).unwrap();
+        assert!(smoothed[0].x.is_nan() && smoothed[0].heading_sd.is_nan());
+        assert!(smooth::<f32>(&[], &SmoothConfig::default()).unwrap().is_empty());
+    }
+
+    #[test]
+    fn it_rejects_noise_levels_that_arent_positive() {
+        for &noise in &[0.0, -1.0, f32::NAN, f32::INFINITY] {
+            let config = SmoothConfig { position_noise: noise, ..SmoothConfig::default() };
+            match smooth(&noisy_run(3), &config) {
+                Err(Error::BadValue { key, .. }) => assert_eq!(key, "position_noise"),
+                r => panic!("expected a bad value for noise {}, got {:?}", noise, r),
+            }
+        }
+        let config = SmoothConfig { acceleration_noise: 0.0, ..SmoothConfig::default() };
+        assert!(config.validate().is_err());
+    }
+}
@@ -0,0 +1,230 @@
+use std::f32::consts::PI;
+use std::ops::Range;
+
+use super::{Coordinates, DiodePos, SampleTime};
+
+/// How swapped diodes are detected
+#[derive(Clone, Debug, PartialEq)]
+pub struct SwapConfig {
+    /// Movement direction only counts when the midpoint between the
+    /// diodes moves faster than this, in pixels per second
+    pub min_speed: f32,
+    /// How much a heading pointing away from the movement direction
+    /// counts, against a heading that turns around between samples
+    pub movement_weight: f32,
+    pub coordinates: Coordinates,
+}
+
+impl Default for SwapConfig {
+    fn default() -> SwapConfig {
+        SwapConfig { min_speed: 10.0, movement_weight: 1.0, coordinates: Coordinates::Pixels }
+    }
+}
+
+/// The ranges of samples of `track` whose front and back diodes look
+/// swapped.
+///
+/// Over each run of samples where both diodes are detected, this picks
+/// the labelling of the diodes that best keeps the heading (from the
+/// back diode to the front one) from turning around between samples,
+/// and pointing along the direction of movement while the animal runs.
+/// Samples with a diode missing break the runs, and are never swapped.
+pub fn detect_swaps<T: SampleTime>(track: &[DiodePos<f32, T>], config: &SwapConfig) -> Vec<Range<usize>> {
+    let movement = movement_directions(track, config);
+    let mut swapped = vec![false; track.len()];
+    let mut i = 0;
+    while i < track.len() {
+        if !both_detected(&track[i], config.coordinates) {
+            i += 1;
+            continue;
+        }
+        let start = i;
+        while i < track.len() && both_detected(&track[i], config.coordinates) {
+            i += 1;
+        }
+        let run = best_labelling(&track[start..i], &movement[start..i], config);
+        swapped[start..i].copy_from_slice(&run);
+    }
+    ranges(&swapped)
+}
+
+/// Swap the diodes back in the samples `detect_swaps` finds, and
+/// return the ranges of samples that were swapped
+pub fn fix_swaps<T: SampleTime>(track: &mut [DiodePos<f32, T>], config: &SwapConfig) -> Vec<Range<usize>> {
+    let swaps = detect_swaps(track, config);
+    for r in swaps.iter() {
+        for p in track[r.clone()].iter_mut() {
+            std::mem::swap(&mut p.diode_front, &mut p.diode_back);
+        }
+    }
+    swaps
+}
+
+fn both_detected<T>(p: &DiodePos<f32, T>, coordinates: Coordinates) -> bool {
+    coordinates.is_detected(p.diode_front) && coordinates.is_detected(p.diode_back)
+}
+
+fn heading<T>(p: &DiodePos<f32, T>, swapped: bool) -> f32 {
+    let (front, back) = if swapped {
+        (p.diode_back, p.diode_front)
+    } else {
+        (p.diode_front, p.diode_back)
+    };
+    (front.1 - back.1).atan2(front.0 - back.0)
+}
+
+/// The absolute difference between two angles, in `[0, PI]`
+fn angle_between(a: f32, b: f32) -> f32 {
+    let d = (a - b).rem_euclid(2.0 * PI);
+    d.min(2.0 * PI - d)
+}
+
+// Direction of movement of the diodes' midpoint at each sample, where
+// the midpoint is known either side and moves fast enough
+fn movement_directions<T: SampleTime>(track: &[DiodePos<f32, T>], config: &SwapConfig) -> Vec<Option<f32>> {
+    let midpoint = |p: &DiodePos<f32, T>| if both_detected(p, config.coordinates) {
+        Some(((p.diode_front.0 + p.diode_back.0) / 2.0, (p.diode_front.1 + p.diode_back.1) / 2.0))
+    } else {
+        None
+    };
+    (0..track.len())
+        .map(|i| {
+            let (a, b) = (i.saturating_sub(1), (i + 1).min(track.len() - 1));
+            let dt = (track[b].time.seconds() - track[a].time.seconds()) as f32;
+            let ((xa, ya), (xb, yb)) = (midpoint(&track[a])?, midpoint(&track[b])?);
+            let (dx, dy) = (xb - xa, yb - ya);
+            if dt > 0.0 && dx.hypot(dy) / dt > config.min_speed {
+                Some(dy.atan2(dx))
+            } else {
+                None
+            }
+        })
+        .collect()
+}
+
+// Viterbi over two states per sample, kept or swapped, for a run of
+// samples with both diodes detected
+fn best_labelling<T>(run: &[DiodePos<f32, T>], movement: &[Option<f32>],
+                     config: &SwapConfig) -> Vec<bool> {
+    let states = [false, true];
+    let state_cost = |i: usize, s: bool| movement[i]
+        .map_or(0.0, |m| config.movement_weight * angle_between(heading(&run[i], s), m) / PI);
+
+    let mut cost = [state_cost(0, false), state_cost(0, true)];
+    let mut from: Vec<[usize; 2]> = Vec::with_capacity(run.len());
+    from.push([0, 1]);
+    for i in 1..run.len() {
+        let mut next = [0.0; 2];
+        let mut back = [0; 2];
+        for (s, &swapped) in states.iter().enumerate() {
+            let h = heading(&run[i], swapped);
+            let (best, c) = states
+                .iter()
+                .enumerate()
+                .map(|(p, &prev)| (p, cost[p] + angle_between(h, heading(&run[i - 1], prev)) / PI))
+                .fold((0, f32::INFINITY), |a, b| if b.1 < a.1 { b } else { a });
+            next[s] = c + state_cost(i, swapped);
+            back[s] = best;
+        }
+        cost = next;
+        from.push(back);
+    }
+
+    let mut s = if cost[1] < cost[0] { 1 } else { 0 };
+    let mut labelling = vec![false; run.len()];
+    for i in (0..run.len()).rev() {
+        labelling[i] = states[s];
+        s = from[i][s];
+    }
+    labelling
+}
+
+fn ranges(flags: &[bool]) -> Vec<Range<usize>> {
+    let mut ranges = Vec::new();
+    let mut start = None;
+    for (i, f) in flags.iter().chain(std::iter::once(&false)).enumerate() {
+        match (start, *f) {
+            (None, true) => start = Some(i),
+            (Some(s), false) => {
+                ranges.push(s..i);
+                start = None;
+            },
+            _ => (),
+        }
+    }
+    ranges
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Running along x at 10 pixels per 0.1s, facing forward
+    fn run(n: usize) -> Vec<DiodePos<f32, f32>> {
+        (0..n)
+            .map(|i| {
+                let x = 10.0 * i as f32 + 20.0;
+                DiodePos { diode_front: (x + 2.0, 50.0), diode_back: (x - 2.0, 50.0), time: 0.1 * i as f32 }
+            })
+            .collect()
+    }
+
+    fn swap(p: &mut DiodePos<f32, f32>) {
+        std::mem::swap(&mut p.diode_front, &mut p.diode_back);
+    }
+
+    #[test]
+    fn it_fixes_swapped_stretches() {
+        let original = run(10);
+        let mut track = original.clone();
+        for p in track[3..6].iter_mut() {
+            swap(p);
+        }
+        let swaps = fix_swaps(&mut track, &SwapConfig::default());
+        assert_eq!(swaps, vec![3..6]);
+        assert_eq!(track, original);
+    }
+
+    #[test]
+    fn it_uses_movement_to_orient_a_run() {
+        // Swapped from the start: continuity alone can't tell
+        let mut track = run(6);
+        for p in track.iter_mut() {
+            swap(p);
+        }
+        assert_eq!(detect_swaps(&track, &SwapConfig::default()), vec![0..6]);
+
+        // Standing still, there is nothing to go on
+        let still: Vec<DiodePos<f32, f32>> = track
+            .iter()
+            .map(|p| DiodePos { time: p.time, ..track[0].clone() })
+            .collect();
+        assert!(detect_swaps(&still, &SwapConfig::default()).is_empty());
+    }
+
+    #[test]
+    fn missing_diodes_break_runs() {
+        let mut track = run(6);
+        track[2].diode_back = (0.0, 0.0);
+        swap(&mut track[4]);
+        assert_eq!(detect_swaps(&track, &SwapConfig::default()), vec![4..5]);
+    }
+
+    #[test]
+    fn it_fixes_tracks_timed_in_ticks() {
+        // Past 2^24 ticks, an f32 time can't hold every tick
+        let original: Vec<DiodePos<f32, u32>> = run(6)
+            .into_iter()
+            .enumerate()
+            .map(|(i, p)| DiodePos { time: 16_777_217 + 1000 * i as u32, diode_front: p.diode_front,
+                                     diode_back: p.diode_back })
+            .collect();
+        let mut track = original.clone();
+        for p in track.iter_mut() {
+            std::mem::swap(&mut p.diode_front, &mut p.diode_back);
+        }
+        assert_eq!(fix_swaps(&mut track, &SwapConfig::default()), vec![0..6]);
+        assert_eq!(track, original);
+    }
+}
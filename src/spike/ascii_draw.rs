@@ -14,7 +14,7 @@ pub const DEFAULT_PLOT : PlotSpec = PlotSpec {
 };
 
 
-pub fn draw_spike(plot: PlotSpec, waveforms: &Vec<Vec<f32>>) {
+pub fn draw_spike(plot: PlotSpec, waveforms: &[Vec<f32>]) {
 
     let n_channels = waveforms.len() as u16;
     let n_samps    = waveforms[0].len() as u16;
@@ -35,7 +35,7 @@ pub fn draw_spike(plot: PlotSpec, waveforms: &Vec<Vec<f32>>) {
     // of rows proportional to (v - min)/(max - min) and the
     // window height
     let v_row =
-        |v : f32| { plot.height as u16 -
+        |v : f32| { plot.height -
                     ( (v - plot.v_low) *
                        v_range_inverse *
                        plot.height as f32) as u16 + 1};
@@ -43,24 +43,21 @@ pub fn draw_spike(plot: PlotSpec, waveforms: &Vec<Vec<f32>>) {
     // The column (within that channel's window) for a voltage is
     // the index of its sample, times the ratio of window length
     // to waveform length
-    let t_col = |t : u16| ( (t as f32 * plot.width as f32 /
-                             n_samps as f32) as u16);
+    let t_col = |t : u16| (t as f32 * plot.width as f32 /
+                           n_samps as f32) as u16;
 
     // Initiate an empty character matrix
-    let pixel_count = row_col_ind((plot.height+2, window_width)) as usize;
-    let mut pixel = Vec::with_capacity( pixel_count );
-    for _ in 0..pixel_count {
-        pixel.push(' ');
-    }; 
+    let pixel_count = row_col_ind((plot.height+2, window_width));
+    let mut pixel = vec![' '; pixel_count];
 
     // Draw the outer border
     for r in 1..window_height-1 {
-        pixel[ row_col_ind((r,                0)) as usize ] = '║';
-        pixel[ row_col_ind((r, window_width - 1)) as usize ] = '║';
+        pixel[ row_col_ind((r,                0)) ] = '║';
+        pixel[ row_col_ind((r, window_width - 1)) ] = '║';
     };
     for c in 1..window_width-1 {
-        pixel[ row_col_ind((0,                 c)) as usize ] = '═';
-        pixel[ row_col_ind((window_height - 1, c)) as usize ] = '═';
+        pixel[ row_col_ind((0,                 c)) ] = '═';
+        pixel[ row_col_ind((window_height - 1, c)) ] = '═';
     };
 
     // Draw the outer border corners
@@ -100,7 +97,7 @@ pub fn draw_spike(plot: PlotSpec, waveforms: &Vec<Vec<f32>>) {
         let i0 = row_col_ind((r,           0));
         let i1 = row_col_ind((r,window_width));
         let bytes : &[char] = &pixel[i0..i1];
-        let s : String = bytes.into_iter().collect();
+        let s : String = bytes.iter().collect();
         println!("{}", s);
     };
     
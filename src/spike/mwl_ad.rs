@@ -1,6 +1,6 @@
 use std::fs::{File};
-use std::io::Read;
-use std::str;
+use std::io::{BufReader, BufWriter, Read, Seek, Write};
+use std::path::Path;
 
 use nom::{IResult};
 use nom::combinator as nomc;
@@ -8,71 +8,589 @@ use nom::sequence as noms;
 use nom::multi as nomm;
 use nom::number::complete as nomnum;
 
-// use nom::combinator as nom;
-
 use super::{Spike};
-// use super::mwl_ad;
-use crate::mwl_ad::header;
+use crate::Error;
+use crate::interval::IntervalSet;
+use crate::mwl_ad::{to_volts, FormatType, TICKS_PER_SECOND};
+use crate::mwl_ad::acquisition::{self, AdAcquisitionHeader};
+use crate::mwl_ad::header::{self, Metadata, MetadataBuf};
+use crate::mwl_ad::mapped::{MappedFile, Record};
+use crate::mwl_ad::schema::{Field, RecordSchema};
+use crate::mwl_ad::stream::{self, RecordStream, Recovery, Warning};
+
+
+
+/// Samples per channel in a spike as recorded by the AD system
+pub const SPIKE_LEN: usize = 32;
+
+/// Channels per probe of a tetrode
+pub const TETRODE_CHANNELS: usize = 4;
+
+/// The usual .tt record layout: a u32 timestamp followed by 32
+/// samples from each of 4 channels, interleaved
+pub fn tt_schema() -> RecordSchema {
+    tt_schema_with_len(TETRODE_CHANNELS, SPIKE_LEN)
+}
+
+/// The .tt record layout for spikes of `spike_len` samples from each
+/// of `n_channels` channels, as written by adextract with `-eslen`
+pub fn tt_schema_with_len(n_channels: usize, spike_len: usize) -> RecordSchema {
+    RecordSchema::new(vec![
+        Field::new("timestamp", FormatType::ULongT, 4, 1),
+        Field::new("waveform", FormatType::ShortT, 2, n_channels * spike_len),
+    ])
+}
+
+pub fn read_spikes(file_path: &Path) -> Result<Vec<Spike<f32,f32>>, Error> {
+    SpikeReader::open(file_path)?.collect()
+}
+
+/// Reads the spikes of a tetrode (.tt) file one at a time, so memory
+/// use doesn't grow with the size of the file
+pub struct SpikeReader<R> {
+    header: MetadataBuf,
+    layout: TtLayout,
+    records: RecordStream<BufReader<R>>,
+}
+
+impl SpikeReader<File> {
+    pub fn open(path: &Path) -> Result<SpikeReader<File>, Error> {
+        File::open(path)
+            .map_err(Error::from)
+            .and_then(SpikeReader::new)
+            .map(|mut r| {
+                r.records = r.records.with_path(path);
+                r
+            })
+            .map_err(|e| e.at_path(path))
+    }
+}
+
+impl<R: Read> SpikeReader<R> {
+    /// Parse the header of `inner`; spikes are read on iteration
+    pub fn new(inner: R) -> Result<SpikeReader<R>, Error> {
+        let mut layout = None;
+        let (header, records) = stream::open_with(inner, |metadata| {
+            let l = TtLayout::from_metadata(metadata)?;
+            let schema = l.schema();
+            layout = Some(l);
+            Ok(schema)
+        })?;
+        let layout = layout.unwrap();
+        Ok(SpikeReader { header, layout, records })
+    }
+
+    pub fn header(&self) -> &MetadataBuf {
+        &self.header
+    }
+
+    /// Choose how to handle damaged files, as [`Recovery`] describes
+    /// (`Recovery::Strict` by default)
+    pub fn recovery(mut self, recovery: Recovery) -> SpikeReader<R> {
+        self.records.set_recovery(recovery);
+        self
+    }
+
+    /// Problems worked around so far in `Recovery::Salvage` mode
+    pub fn warnings(&self) -> &[Warning] {
+        self.records.warnings()
+    }
+}
+
+impl<R: Read + Seek> SpikeReader<R> {
+    /// Restrict the reader to spikes with timestamps (in ticks of
+    /// 100us) in `[after, before)`, as [`RecordStream`] describes
+    pub fn time_range(mut self, after: Option<u32>, before: Option<u32>)
+                      -> Result<SpikeReader<R>, Error> {
+        self.records.seek_time_range(after, before)?;
+        Ok(self)
+    }
+
+    /// Restrict the reader to spikes with timestamps in `intervals`,
+    /// within any `time_range`, as [`RecordStream`] describes
+    pub fn during(mut self, intervals: IntervalSet) -> Result<SpikeReader<R>, Error> {
+        self.records.seek_intervals(intervals)?;
+        Ok(self)
+    }
+}
+
+impl<R: Read> Iterator for SpikeReader<R> {
+    type Item = Result<Spike<f32,f32>, Error>;
 
+    fn next(&mut self) -> Option<Self::Item> {
+        let layout = &self.layout;
+        self.records.next_record().map(|record| {
+            let record = record?;
+            // A record has exactly the size parse_spike consumes
+            Ok(parse_spike(layout, record).unwrap().1)
+        })
+    }
+}
+
+/// Random access to the spikes of a memory-mapped tetrode file.
+/// Waveforms are only decoded when asked for.
+pub struct MappedSpikes {
+    file: MappedFile,
+    layout: TtLayout,
+}
+
+impl MappedSpikes {
+    pub fn open(path: &Path) -> Result<MappedSpikes, Error> {
+        let file = MappedFile::open(path)?;
+        let layout = TtLayout::from_metadata(&file.header().as_metadata())
+            .and_then(|l| {
+                // Spikes are decoded by field name
+                file.schema().check_fields(&l.schema())?;
+                Ok(l)
+            })
+            .map_err(|e| e.at_path(path))?;
+        Ok(MappedSpikes { file, layout })
+    }
+
+    pub fn file(&self) -> &MappedFile {
+        &self.file
+    }
+
+    pub fn len(&self) -> usize {
+        self.file.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.file.is_empty()
+    }
+
+    pub fn spike(&self, i: usize) -> Option<MappedSpike<'_>> {
+        self.file.record(i).map(|record| MappedSpike { record, layout: &self.layout })
+    }
+
+    pub fn spikes(&self) -> impl Iterator<Item = MappedSpike<'_>> {
+        (0..self.len()).filter_map(move |i| self.spike(i))
+    }
+}
+
+/// A spike borrowed from a `MappedSpikes`
+#[derive(Clone, Copy, Debug)]
+pub struct MappedSpike<'a> {
+    record: Record<'a>,
+    layout: &'a TtLayout,
+}
 
+impl<'a> MappedSpike<'a> {
+    /// The raw timestamp, in ticks of 100us
+    pub fn timestamp(&self) -> u32 {
+        stream::record_timestamp(self.record.bytes())
+    }
 
-#[derive (Debug)]
-struct Metadata {
+    /// The timestamp in seconds
+    pub fn time(&self) -> f32 {
+        (self.timestamp() as f64 / TICKS_PER_SECOND) as f32
+    }
+
+    pub fn n_channels(&self) -> usize {
+        self.layout.gains.len()
+    }
+
+    /// Samples per channel
+    pub fn spike_len(&self) -> usize {
+        self.layout.spike_len
+    }
+
+    /// Sample `i` of `channel`, in volts, or `None` past the last
+    /// channel or sample
+    pub fn sample(&self, channel: usize, i: usize) -> Option<f32> {
+        let gain = *self.layout.gains.get(channel)?;
+        if i >= self.spike_len() {
+            return None;
+        }
+        let v = self.record.get::<i16>("waveform", i * self.n_channels() + channel)?;
+        Some(to_volts(v, gain))
+    }
+
+    /// The full waveform of `channel`, in volts, or `None` past the
+    /// last channel
+    pub fn waveform(&self, channel: usize) -> Option<Vec<f32>> {
+        (0..self.spike_len()).map(|i| self.sample(channel, i)).collect()
+    }
+
+    pub fn to_spike(&self) -> Spike<f32,f32> {
+        Spike {
+            time: self.time(),
+            waveforms: (0..self.n_channels()).filter_map(|c| self.waveform(c)).collect(),
+        }
+    }
+}
+
+/// Header for a .tt file holding spikes taken from `source`, for
+/// example a filtered or time-sliced subset. The probe and record
+/// layout are declared up front as adextract does, and the source's
+/// own header follows as a provenance section, so the acquisition
+/// settings stay available to `read_spikes` and to mwsoft tools.
+pub fn derived_tt_header<S: AsRef<str>>(
+    args: &[S],
+    source_name: &str,
+    source: &Metadata,
+) -> Result<MetadataBuf, Error> {
+    let probe = acquisition::value(&header::tree(source), "Probe")?;
+    let layout = TtLayout::from_metadata(source)?;
+    Ok(tt_header(args, probe, layout.spike_len, source_name, source))
 }
 
-pub fn read_spikes(file_path: &str) -> Vec<Spike<f32,f32>> {
-    let mut buffer = Vec::new();
-    File::open(file_path).unwrap().read_to_end(&mut buffer).unwrap();
-    let (header, file_binary) = header::parse(buffer.as_slice()).unwrap();
-    let probe_ind_0 = match header::require( &header, "Probe" ) {
-        Ok("0") => 0,
-        Ok("1") => 4,
-        Ok(s)   => panic!("Unknown probe: {}. It should be \"0\" or \"1\"", s ),
-        Err(s)  => panic!("error reading probe index: {:?}", s),
-    };
+/// Header for a .tt file holding spikes of `probe`, `spike_len`
+/// samples per channel, extracted from `source`: either a raw AD
+/// file or a .tt file, whose header is kept as a provenance section
+pub fn tt_header<S: AsRef<str>>(
+    args: &[S],
+    probe: usize,
+    spike_len: usize,
+    source_name: &str,
+    source: &Metadata,
+) -> MetadataBuf {
+    let n_channels = AdAcquisitionHeader::from_metadata(source)
+        .map(|a| a.nelect_chan)
+        .unwrap_or(TETRODE_CHANNELS);
+    let mut h = MetadataBuf::for_program(args);
+    h.push_pair("File type", "Binary");
+    h.push_pair("Extraction type", "tetrode waveforms");
+    h.push_pair("Probe", probe);
+    h.push_pair("Fields", tt_schema_with_len(n_channels, spike_len));
+    h.push_comment("");
+    h.push_section(source_name, source);
+    h
+}
 
-    let gain = |n: i8| {
-        let key = format!("channel {} ampgain", n);
-        let gain_str : &str = header::require(&header, key.as_str()).unwrap();
-        gain_str.parse::<f32>().unwrap()
-    };
-    let gains = vec![gain(probe_ind_0),
-                     gain(probe_ind_0 + 1),
-                     gain(probe_ind_0 + 2),
-                     gain(probe_ind_0 + 3)];
+/// Writes spikes to a tetrode (.tt) file, converting waveforms back
+/// to raw AD values with the gains of the probe named in the header
+pub struct SpikeWriter<W: Write> {
+    inner: W,
+    layout: TtLayout,
+    record: Vec<u8>,
+}
 
-    let spikes = parse_spikes( gains, file_binary );
-    spikes.unwrap().1
+impl SpikeWriter<BufWriter<File>> {
+    pub fn create(path: &Path, header: &MetadataBuf) -> Result<SpikeWriter<BufWriter<File>>, Error> {
+        File::create(path)
+            .map_err(Error::from)
+            .and_then(|f| SpikeWriter::new(BufWriter::new(f), header))
+            .map_err(|e| e.at_path(path))
+    }
 }
 
+impl<W: Write> SpikeWriter<W> {
+    /// Write `header`, which must declare a .tt record layout and
+    /// carry the acquisition settings of its probe
+    /// (see `tt_header` and `derived_tt_header`)
+    pub fn new(mut inner: W, header: &MetadataBuf) -> Result<SpikeWriter<W>, Error> {
+        let metadata = header.as_metadata();
+        let layout = TtLayout::from_metadata(&metadata)?;
+        let schema = layout.schema();
+        stream::write_header(&mut inner, &metadata, &schema)?;
+        Ok(SpikeWriter { inner, layout, record: Vec::with_capacity(schema.record_size()) })
+    }
 
+    /// Write a spike whose waveforms are in volts and time in seconds,
+    /// as produced by `SpikeReader`. An f32 time only resolves single
+    /// ticks for the first 2^24 of them (about 28 minutes); to copy
+    /// spikes from later in a recording, use `write_spike_at` with the
+    /// raw timestamp.
+    pub fn write_spike(&mut self, spike: &Spike<f32,f32>) -> Result<(), Error> {
+        let timestamp = (spike.time as f64 * TICKS_PER_SECOND).round();
+        if !(0.0..=u32::MAX as f64).contains(&timestamp) {
+            return Err(Error::bad_value("timestamp", spike.time.to_string(),
+                                        "a time representable in u32 ticks"));
+        }
+        self.write_spike_at(timestamp as u32, spike)
+    }
 
-fn parse_spikes(gains : Vec<f32>, input: &[u8]) -> IResult<&[u8], Vec<Spike<f32,f32>>> {
-    nomm::many0(
+    /// Write a spike whose waveforms are in volts at the raw
+    /// `timestamp`, in ticks of 100us, ignoring `spike.time`
+    pub fn write_spike_at<T>(&mut self, timestamp: u32, spike: &Spike<f32,T>) -> Result<(), Error> {
+        let n_channels = self.layout.gains.len();
+        let n_samples = self.layout.spike_len;
+        if spike.waveforms.len() != n_channels
+            || spike.waveforms.iter().any(|w| w.len() != n_samples) {
+            return Err(Error::bad_value("waveform",
+                                        format!("{} channels", spike.waveforms.len()),
+                                        format!("{} channels of {} samples", n_channels, n_samples)));
+        }
+        let mut waveform = Vec::with_capacity(n_channels * n_samples);
+        for i in 0..n_samples {
+            for (c, gain) in self.layout.gains.iter().enumerate() {
+                waveform.push(from_volts(spike.waveforms[c][i], *gain)?);
+            }
+        }
+        self.write_record(timestamp, &waveform)
+    }
+
+    /// Write a spike from its raw timestamp and interleaved AD values
+    pub fn write_record(&mut self, timestamp: u32, waveform: &[i16]) -> Result<(), Error> {
+        let n_values = self.layout.n_values();
+        if waveform.len() != n_values {
+            return Err(Error::bad_value("waveform", format!("{} samples", waveform.len()),
+                                        format!("{} samples", n_values)));
+        }
+        self.record.clear();
+        self.record.extend_from_slice(&timestamp.to_le_bytes());
+        for v in waveform {
+            self.record.extend_from_slice(&v.to_le_bytes());
+        }
+        self.inner.write_all(&self.record)?;
+        Ok(())
+    }
+
+    /// Flush and return the underlying writer
+    pub fn into_inner(mut self) -> Result<W, Error> {
+        self.inner.flush()?;
+        Ok(self.inner)
+    }
+}
+
+
+/// The channel gains and spike length of a .tt file, which together
+/// fix its record layout
+#[derive(Clone, Debug)]
+struct TtLayout {
+    gains: Vec<f32>,
+    spike_len: usize,
+}
+
+impl TtLayout {
+    /// Take the gains from the acquisition settings of the file's
+    /// probe, and the spike length from its `Fields` line
+    fn from_metadata(metadata: &Metadata) -> Result<TtLayout, Error> {
+        let gains = probe_gains(metadata)?;
+        let schema = RecordSchema::from_metadata(metadata)?;
+        let count = schema.field("waveform").map_or(0, |f| f.count);
+        if gains.is_empty() || count == 0 || count % gains.len() != 0 {
+            return Err(Error::bad_value("Fields", schema.to_string(),
+                                        format!("a waveform of {} interleaved channels", gains.len())));
+        }
+        Ok(TtLayout { spike_len: count / gains.len(), gains })
+    }
+
+    fn n_values(&self) -> usize {
+        self.gains.len() * self.spike_len
+    }
+
+    fn schema(&self) -> RecordSchema {
+        tt_schema_with_len(self.gains.len(), self.spike_len)
+    }
+}
+
+/// Amplifier gains of the channels of the file's probe, taken from
+/// the embedded acquisition header
+fn probe_gains(metadata: &Metadata) -> Result<Vec<f32>, Error> {
+    let probe = acquisition::value::<usize>(&header::tree(metadata), "Probe")?;
+    let acquisition = AdAcquisitionHeader::from_metadata(metadata)?;
+    Ok(acquisition
+        .probe_channels(probe)?
+        .iter()
+        .map(|c| c.ampgain)
+        .collect())
+}
+
+
+fn parse_spike<'a>(layout: &TtLayout, input: &'a [u8]) -> IResult<&'a [u8], Spike<f32,f32>> {
+    let gains = &layout.gains;
     nomc::map(
         noms::pair(
             nomnum::le_u32, // unsigned long (timestamp)
-            nomm::count(nomnum::le_i16, 128)
+            nomm::count(nomnum::le_i16, layout.n_values())
         ),
-        |(t,vs)| {
-            let time = t as f32 / 10_000.0;
+        move |(t,vs)| {
+            let time = (t as f64 / TICKS_PER_SECOND) as f32;
 
             let voltages : Vec<f32> = vs
                 .into_iter()
                 .zip( gains.iter().cycle() )
-                .map(|(v,g)| (v as f32) / 32_768.0 * 5.0 / g )
+                .map(|(v,g)| to_volts(v, *g))
                 .collect();
 
             let mut waveforms : Vec<Vec<f32>> = Vec::new();
-            for _ in 0..4 {
+            for _ in 0..gains.len() {
                 waveforms.push (Vec::new());
             }
-            for i in 0..voltages.len() {
-                let chan = i % 4;
-                waveforms[chan].push(voltages[i]);
+            for (i, v) in voltages.into_iter().enumerate() {
+                let chan = i % gains.len();
+                waveforms[chan].push(v);
             };
 
             Spike {time, waveforms}
         }
-    ))(input)
+    )(input)
+}
+
+/// The inverse of `to_volts`, failing if `v` is beyond the AD range
+fn from_volts(v: f32, gain: f32) -> Result<i16, Error> {
+    let raw = (v as f64 * gain as f64 / 5.0 * 32_768.0).round();
+    if raw < i16::MIN as f64 || raw > i16::MAX as f64 || raw.is_nan() {
+        return Err(Error::bad_value("waveform", v.to_string(),
+                                    format!("a voltage within the AD range at gain {}", gain)));
+    }
+    Ok(raw as i16)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+    use std::path::PathBuf;
+    use std::process;
+    use crate::mwl_ad::header::tests::HEADER_FIXTURE;
+    use crate::mwl_ad::raw::RawSpikeReader;
+
+    /// A path in the temp directory that no other test or test run
+    /// uses at the same time
+    fn temp_path(name: &str) -> PathBuf {
+        env::temp_dir().join(format!("xcrust-{}-{}", process::id(), name))
+    }
+
+    /// A .tt file with the fixture header and one spike per
+    /// timestamp, each with sample values `t, t+1, ...`
+    fn tt_bytes(timestamps: &[u32]) -> Vec<u8> {
+        let mut bytes = HEADER_FIXTURE.as_bytes().to_vec();
+        for t in timestamps {
+            bytes.extend_from_slice(&t.to_le_bytes());
+            for i in 0..128 {
+                bytes.extend_from_slice(&((*t as i16).wrapping_add(i)).to_le_bytes());
+            }
+        }
+        bytes
+    }
+
+    /// Write `tt_bytes(timestamps)` to a temp file
+    fn tt_file(name: &str, timestamps: &[u32]) -> PathBuf {
+        let path = temp_path(name);
+        fs::write(&path, tt_bytes(timestamps)).unwrap();
+        path
+    }
+
+    #[test]
+    fn mapped_spikes_match_streamed_spikes() {
+        let path = tt_file("mapped-spikes.tt", &[100, 200, 300]);
+        let streamed: Vec<Spike<f32,f32>> = SpikeReader::open(&path)
+            .unwrap()
+            .map(Result::unwrap)
+            .collect();
+        let mapped = MappedSpikes::open(&path).unwrap();
+        assert_eq!(mapped.len(), 3);
+        assert_eq!(streamed.len(), 3);
+        for (i, s) in streamed.iter().enumerate() {
+            let m = mapped.spike(i).unwrap();
+            assert_eq!(m.time(), s.time);
+            assert_eq!(m.waveform(2).as_ref(), Some(&s.waveforms[2]));
+            assert_eq!(m.to_spike().waveforms, s.waveforms);
+        }
+        assert_eq!(mapped.spike(1).unwrap().sample(1, 0), Some(to_volts(201, 24994.0)));
+        assert_eq!(mapped.spike(1).unwrap().sample(4, 0), None);
+        assert_eq!(mapped.spike(1).unwrap().sample(0, 32), None);
+        assert!(mapped.spike(1).unwrap().waveform(4).is_none());
+        assert!(mapped.spike(3).is_none());
+        assert_eq!(mapped.file().time_range(Some(150), None).unwrap(), 1..3);
+        assert_eq!(mapped.file().time_range(Some(100), Some(300)).unwrap(), 0..2);
+
+        let ranged: Vec<f32> = SpikeReader::open(&path)
+            .and_then(|r| r.time_range(Some(200), Some(301)))
+            .unwrap()
+            .map(|s| s.unwrap().time)
+            .collect();
+        assert_eq!(ranged, vec![0.02, 0.03]);
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn mapped_spikes_need_named_fields() {
+        let path = temp_path("mapped-renamed.tt");
+        let mut bytes = HEADER_FIXTURE
+            .replace("timestamp,8,4,1\twaveform,2,2,128", "time,8,4,1\twaveform,2,2,128")
+            .into_bytes();
+        bytes.extend_from_slice(&[0; 260]);
+        fs::write(&path, bytes).unwrap();
+        match MappedSpikes::open(&path) {
+            Err(Error::Schema { path: Some(p), .. }) => assert_eq!(p, path),
+            r => panic!("expected a schema error, got {:?}", r.map(|m| m.len())),
+        }
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn written_spikes_read_back() {
+        let source_bytes = tt_bytes(&[100, 200, 300]);
+        let reader = SpikeReader::new(source_bytes.as_slice()).unwrap();
+        let header = derived_tt_header(&["xcrust-test", "-o", "out.tt"],
+                                       "writer-source.tt",
+                                       &reader.header().as_metadata()).unwrap();
+        let spikes: Vec<Spike<f32,f32>> = reader.map(Result::unwrap).collect();
+
+        let mut writer = SpikeWriter::new(Vec::new(), &header).unwrap();
+        for s in spikes.iter().skip(1) {
+            writer.write_spike(s).unwrap();
+        }
+        let bytes = writer.into_inner().unwrap();
+
+        let header_len = HEADER_FIXTURE.len();
+        assert_eq!(&bytes[bytes.len() - 520..], &source_bytes[header_len + 260..]);
+
+        let reread = SpikeReader::new(bytes.as_slice()).unwrap();
+        let t = header::tree(&reread.header().as_metadata());
+        assert_eq!(t.lookup("Program"), Some("xcrust-test"));
+        let inner = t.child("writer-source.tt").unwrap();
+        assert_eq!(inner.lookup("Program"), Some("./adextract"));
+        let times: Vec<f32> = reread.map(|s| s.unwrap().time).collect();
+        assert_eq!(times, vec![0.02, 0.03]);
+    }
+
+    #[test]
+    fn extracted_short_spikes_read_back() {
+        let raw = crate::mwl_ad::raw::tests::raw_file(&[(0, 100), (1, 150), (0, 200)]);
+        let reader = RawSpikeReader::new(raw.as_slice()).unwrap();
+        let header = tt_header(&["xcrust-extract"], 1, 8, "x.spk.raw",
+                               &reader.header().as_metadata());
+        let mut writer = SpikeWriter::new(Vec::new(), &header).unwrap();
+        for s in reader.map(Result::unwrap).filter(|s| s.electrode == 1) {
+            writer.write_record(s.timestamp, s.truncated(4, 8)).unwrap();
+        }
+        let bytes = writer.into_inner().unwrap();
+
+        let spikes: Vec<Spike<f32,f32>> = SpikeReader::new(bytes.as_slice())
+            .unwrap()
+            .map(Result::unwrap)
+            .collect();
+        assert_eq!(spikes.len(), 1);
+        assert_eq!(spikes[0].time, 0.015);
+        assert_eq!(spikes[0].waveforms.len(), 4);
+        assert_eq!(spikes[0].waveforms[1].len(), 8);
+        // Probe 1 has channels 4..8 of the acquisition header
+        assert_eq!(spikes[0].waveforms[1][2], to_volts(150 + 9, 24994.0));
+    }
+
+    #[test]
+    fn late_spikes_keep_their_timestamps() {
+        let (m, _) = header::parse(HEADER_FIXTURE.as_bytes()).unwrap();
+        let header = derived_tt_header(&["xcrust-test"], "x.tt", &m).unwrap();
+        // Past 2^24 ticks, an f32 time can't hold every tick
+        let timestamp = 16_777_216 + 3;
+        let spike = Spike { time: (), waveforms: vec![vec![0.0001; 32]; 4] };
+        let mut writer = SpikeWriter::new(Vec::new(), &header).unwrap();
+        writer.write_spike_at(timestamp, &spike).unwrap();
+        let bytes = writer.into_inner().unwrap();
+
+        let (_, mut records) = stream::open(bytes.as_slice(), &tt_schema()).unwrap();
+        let record = records.next_record().unwrap().unwrap();
+        assert_eq!(stream::record_timestamp(record), timestamp);
+        assert_ne!(timestamp as f32 as u32, timestamp);
+    }
+
+    #[test]
+    fn writer_rejects_out_of_range_voltages() {
+        let (m, _) = header::parse(HEADER_FIXTURE.as_bytes()).unwrap();
+        let header = derived_tt_header(&["xcrust-test"], "x.tt", &m).unwrap();
+        let mut writer = SpikeWriter::new(Vec::new(), &header).unwrap();
+        let spike = Spike { time: 1.0, waveforms: vec![vec![1.0; 32]; 4] };
+        match writer.write_spike(&spike) {
+            Err(Error::BadValue { key, .. }) => assert_eq!(key, "waveform"),
+            r => panic!("expected a bad value, got {:?}", r),
+        }
+    }
 }